@@ -0,0 +1,248 @@
+//! Anti-entropy set reconciliation over a fixed-shape Merkle tree.
+//!
+//! Elements are placed in one of `FANOUT^DEPTH` leaves by their hash. Every
+//! tree node stores the XOR of the hashes below it, so it can be updated on
+//! insert without rehashing. Two peers reconcile by walking down the ranges
+//! whose hashes differ; at the leaves each side sends its contents and the
+//! other answers with only the elements its peer is missing.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet},
+    hash::{Hash, Hasher},
+};
+
+pub const FANOUT: usize = 16;
+pub const DEPTH: usize = 3;
+
+/// Hash of the range `index` at `level`; level 0 is the root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Digest {
+    pub level: usize,
+    pub index: usize,
+    pub hash: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Leaf<T> {
+    pub index: usize,
+    pub elements: Vec<T>,
+}
+
+/// One round of the reconciliation protocol.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncMsg<T> {
+    /// Ranges the receiver should compare against its own.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub digests: Vec<Digest>,
+    /// Full contents of differing leaves; the receiver answers with `missing`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub leaves: Vec<Leaf<T>>,
+    /// Elements the receiver does not have. Needs no answer.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub missing: Vec<T>,
+}
+
+impl<T> SyncMsg<T> {
+    pub fn is_empty(&self) -> bool {
+        self.digests.is_empty() && self.leaves.is_empty() && self.missing.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MerkleSet<T> {
+    levels: Vec<Vec<u64>>,
+    leaves: BTreeMap<usize, BTreeSet<T>>,
+}
+
+impl<T> Default for MerkleSet<T> {
+    fn default() -> Self {
+        Self {
+            levels: (0..=DEPTH).map(|l| vec![0; FANOUT.pow(l as u32)]).collect(),
+            leaves: BTreeMap::new(),
+        }
+    }
+}
+
+fn hash_of<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn leaf_of(hash: u64) -> usize {
+    (hash >> (64 - 4 * DEPTH as u32)) as usize
+}
+
+impl<T> MerkleSet<T>
+where
+    T: Hash + Ord + Clone + Serialize + DeserializeOwned,
+{
+    /// Inserts `value`, returning whether it was new.
+    pub fn insert(&mut self, value: T) -> bool {
+        let hash = hash_of(&value);
+        let leaf = leaf_of(hash);
+        if !self.leaves.entry(leaf).or_default().insert(value) {
+            return false;
+        }
+        for level in 0..=DEPTH {
+            let index = leaf / FANOUT.pow((DEPTH - level) as u32);
+            self.levels[level][index] ^= hash;
+        }
+        true
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.leaves
+            .get(&leaf_of(hash_of(value)))
+            .is_some_and(|l| l.contains(value))
+    }
+
+    /// Opening message of a reconciliation round.
+    pub fn start(&self) -> SyncMsg<T> {
+        SyncMsg {
+            digests: vec![self.digest(0, 0)],
+            leaves: Vec::new(),
+            missing: Vec::new(),
+        }
+    }
+
+    /// Handles a round from a peer. Returns the elements learned from it and
+    /// the answer to send back, which is empty once both sides agree.
+    pub fn handle(&mut self, msg: SyncMsg<T>) -> (Vec<T>, SyncMsg<T>) {
+        let mut learned = Vec::new();
+        let mut answer = SyncMsg {
+            digests: Vec::new(),
+            leaves: Vec::new(),
+            missing: Vec::new(),
+        };
+
+        for leaf in msg.leaves {
+            let theirs: BTreeSet<T> = leaf.elements.into_iter().collect();
+            if let Some(ours) = self.leaves.get(&leaf.index) {
+                answer.missing.extend(ours.difference(&theirs).cloned());
+            }
+            learned.extend(theirs);
+        }
+        learned.extend(msg.missing);
+
+        for d in msg.digests {
+            // digests come straight off the wire; ignore any that do not
+            // name a range of our tree
+            match self.levels.get(d.level).and_then(|l| l.get(d.index)) {
+                Some(hash) if *hash != d.hash => {}
+                _ => continue,
+            }
+            if d.level == DEPTH {
+                answer.leaves.push(Leaf {
+                    index: d.index,
+                    elements: self
+                        .leaves
+                        .get(&d.index)
+                        .map(|l| l.iter().cloned().collect())
+                        .unwrap_or_default(),
+                });
+            } else {
+                let first = d.index * FANOUT;
+                answer
+                    .digests
+                    .extend((first..first + FANOUT).map(|i| self.digest(d.level + 1, i)));
+            }
+        }
+
+        learned.retain(|v| self.insert(v.clone()));
+        (learned, answer)
+    }
+
+    fn digest(&self, level: usize, index: usize) -> Digest {
+        Digest {
+            level,
+            index,
+            hash: self.levels[level][index],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(values: impl IntoIterator<Item = usize>) -> MerkleSet<usize> {
+        let mut s = MerkleSet::default();
+        for v in values {
+            s.insert(v);
+        }
+        s
+    }
+
+    /// Runs rounds between `a` and `b` until neither has anything to say.
+    fn reconcile(a: &mut MerkleSet<usize>, b: &mut MerkleSet<usize>) -> usize {
+        let mut msg = a.start();
+        let mut rounds = 0;
+        let mut turn_b = true;
+        while !msg.is_empty() {
+            let (_, answer) = if turn_b { b.handle(msg) } else { a.handle(msg) };
+            msg = answer;
+            turn_b = !turn_b;
+            rounds += 1;
+        }
+        rounds
+    }
+
+    #[test]
+    fn equal_sets_stop_after_root() {
+        let mut a = set(0..100);
+        let mut b = set(0..100);
+        assert_eq!(reconcile(&mut a, &mut b), 1);
+    }
+
+    #[test]
+    fn converges_in_both_directions() {
+        let mut a = set((0..500).filter(|v| v % 7 != 0));
+        let mut b = set((0..500).filter(|v| v % 5 != 0));
+        reconcile(&mut a, &mut b);
+        for v in (0..500).filter(|v| v % 35 != 0) {
+            assert!(a.contains(&v) && b.contains(&v), "missing {v}");
+        }
+        assert_eq!(a.levels, b.levels);
+    }
+
+    #[test]
+    fn transfers_only_missing_elements() {
+        let mut a = set(0..1000);
+        let mut b = set(0..999);
+        let (_, answer) = b.handle(a.start());
+        let (_, answer) = a.handle(answer);
+        let (_, answer) = b.handle(answer);
+        let (_, answer) = a.handle(answer);
+        let (learned, _) = b.handle(answer);
+        assert_eq!(learned, vec![999]);
+    }
+
+    #[test]
+    fn ignores_digests_outside_the_tree() {
+        let mut a = set(0..10);
+        let msg = SyncMsg {
+            digests: vec![
+                Digest {
+                    level: DEPTH + 1,
+                    index: 0,
+                    hash: 1,
+                },
+                Digest {
+                    level: 1,
+                    index: FANOUT,
+                    hash: 1,
+                },
+            ],
+            leaves: vec![Leaf {
+                index: usize::MAX,
+                elements: vec![42],
+            }],
+            missing: Vec::new(),
+        };
+        let (learned, answer) = a.handle(msg);
+        assert_eq!(learned, vec![42]);
+        assert!(answer.is_empty());
+    }
+}
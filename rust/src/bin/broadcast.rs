@@ -1,21 +1,36 @@
 use anyhow::{Context, Ok};
-use nazgul::*;
+use nazgul::{
+    anti_entropy::{MerkleSet, SyncMsg},
+    crdt::{Crdt, GSet},
+    failure_detector::{FailureDetector, DEFAULT_THRESHOLD},
+    gossip::DeltaGossip,
+    *,
+};
 
 use std::{
     collections::HashMap,
-    sync::{atomic::AtomicUsize, mpsc::Sender, Mutex},
-    thread,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::Sender,
+        Mutex,
+    },
     time::Duration,
 };
 
 use serde::{Deserialize, Serialize};
 
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(300);
+const MAX_BUFFERED_DELTAS: usize = 64;
+const ANTI_ENTROPY_INTERVAL: Duration = Duration::from_millis(2000);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(1000);
+
 struct BroadcastNode {
     id: AtomicUsize,
     node: String,
-    messages: Vec<usize>,
-    neighbors: Vec<String>,
-    waiting_for_ack: HashMap<usize, Message<Payload>>,
+    messages: Mutex<DeltaGossip<GSet<usize>>>,
+    digest: Mutex<MerkleSet<usize>>,
+    sync_round: AtomicUsize,
+    detector: Mutex<FailureDetector>,
     output: Mutex<std::io::Stdout>,
 }
 
@@ -27,7 +42,20 @@ enum Payload {
         message: usize,
     },
     BroadcastOk,
-    BroadcastUnAcked,
+    Gossip {
+        seq: u64,
+        messages: GSet<usize>,
+    },
+    GossipOk {
+        seq: u64,
+    },
+    GossipTick,
+    Sync {
+        sync: SyncMsg<usize>,
+    },
+    AntiEntropyTick,
+    Heartbeat,
+    HeartbeatTick,
     Read,
     ReadOk {
         messages: Vec<usize>,
@@ -43,101 +71,185 @@ impl Node<(), Payload> for BroadcastNode {
     where
         Self: Sized,
     {
-        let node_id = init.node_id.clone();
-        thread::spawn(move || loop {
-            thread::sleep(Duration::from_millis(6000));
-            let msg = Message::new(
-                node_id.as_str().to_string(),
-                node_id.as_str().to_string(),
-                Body {
-                    id: None,
-                    in_reply_to: None,
-                    payload: Payload::BroadcastUnAcked,
-                },
-            );
-            if tx.send(msg).context("send input").is_err() {
-                break;
-            }
-        });
+        spawn_ticker(
+            init.node_id.clone(),
+            GOSSIP_INTERVAL,
+            Payload::GossipTick,
+            tx.clone(),
+        );
+        spawn_ticker(
+            init.node_id.clone(),
+            ANTI_ENTROPY_INTERVAL,
+            Payload::AntiEntropyTick,
+            tx.clone(),
+        );
+        spawn_ticker(
+            init.node_id.clone(),
+            HEARTBEAT_INTERVAL,
+            Payload::HeartbeatTick,
+            tx,
+        );
         Ok(BroadcastNode {
             id: AtomicUsize::new(1),
             node: init.node_id,
-            messages: Vec::new(),
-            neighbors: Vec::new(),
-            waiting_for_ack: HashMap::new(),
+            messages: Mutex::new(DeltaGossip::new(MAX_BUFFERED_DELTAS)),
+            digest: Mutex::new(MerkleSet::default()),
+            sync_round: AtomicUsize::new(0),
+            detector: Mutex::new(FailureDetector::new(HEARTBEAT_INTERVAL, DEFAULT_THRESHOLD)),
             output: Mutex::new(std::io::stdout()),
         })
     }
 
     fn step(&self, input: Message<Payload>) -> anyhow::Result<()> {
-        let src = input.src.clone();
+        // every message from a peer doubles as a heartbeat
+        if let Some(event) = self.detector.lock().unwrap().heartbeat(&input.src) {
+            eprintln!("MEMBERSHIP {:?}", event);
+        }
         let mut reply = input.into_reply(Some(&self.id));
         match reply.body.payload {
             Payload::Broadcast { message } => {
+                self.messages
+                    .lock()
+                    .unwrap()
+                    .update(|messages| messages.insert(message));
+                self.digest.lock().unwrap().insert(message);
                 reply.body.payload = Payload::BroadcastOk;
                 reply.send(&self.output).context("failed to send message")?;
-
-                if !self.messages.contains(&message) {
-                    self.messages.push(message);
-                    for neighbor in &self.neighbors {
-                        if neighbor.as_str() == self.node.as_str() || neighbor.as_str() == src {
-                            continue;
-                        }
-                        let broad_msg = Message {
-                            src: self.node.clone(),
-                            dst: neighbor.to_string(),
-                            body: Body {
-                                id: Some(self.id.load(std::sync::atomic::Ordering::Relaxed)),
-                                in_reply_to: None,
-                                payload: Payload::Broadcast { message },
-                            },
-                        };
-                        self.id.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                        broad_msg
-                            .send(&self.output)
-                            .context(format!("failed to send message to node: {neighbor}"))?;
-
-                        // std::mem::swap(&mut broad_msg.src, &mut broad_msg.dst); // into_reply will swap them
-                        // TODO: needs refactor to use mutable ref
-                        // switched to immutable self because of kafka-log
-                        // consider smart pointers to get mutable access
-                        self.waiting_for_ack
-                            .insert(broad_msg.body.id.unwrap(), broad_msg);
+            }
+            Payload::Gossip { seq, messages } => {
+                self.messages.lock().unwrap().receive(&messages);
+                let mut digest = self.digest.lock().unwrap();
+                for m in messages.iter() {
+                    digest.insert(*m);
+                }
+                drop(digest);
+                reply.body.payload = Payload::GossipOk { seq };
+                reply.send(&self.output).context("failed to send message")?;
+            }
+            Payload::GossipOk { seq } => {
+                // reply.dst is the node that acked
+                self.messages.lock().unwrap().ack(&reply.dst, seq);
+            }
+            Payload::GossipTick => {
+                let outgoing = self.messages.lock().unwrap().flush();
+                for o in outgoing {
+                    let mut detector = self.detector.lock().unwrap();
+                    if !detector.is_alive(&o.peer) {
+                        continue;
                     }
+                    detector.sent(&o.peer);
+                    drop(detector);
+                    let msg = Message::new(
+                        self.node.clone(),
+                        o.peer.clone(),
+                        Body {
+                            id: Some(self.id.fetch_add(1, Ordering::SeqCst)),
+                            in_reply_to: None,
+                            payload: Payload::Gossip {
+                                seq: o.seq,
+                                messages: o.delta,
+                            },
+                        },
+                    );
+                    msg.send(&self.output)
+                        .context(format!("failed to send message to node: {}", o.peer))?;
                 }
             }
-            Payload::BroadcastOk => {
-                let Some(msg_id) = reply.body.in_reply_to else {
+            Payload::AntiEntropyTick => {
+                let detector = self.detector.lock().unwrap();
+                let mut peers: Vec<_> = detector
+                    .peers()
+                    .filter(|p| detector.is_alive(p))
+                    .cloned()
+                    .collect();
+                drop(detector);
+                if peers.is_empty() {
                     return Ok(());
-                };
-                if self.waiting_for_ack.contains_key(&msg_id) {
-                    // TODO: needs refactor to use mutable ref
-                    // switched to immutable self because of kafka-log
-                    // consider smart pointers to get mutable access
-                    self.waiting_for_ack
-                        .remove(&msg_id)
-                        .context("removing message from waiting to ack map")?;
+                }
+                peers.sort();
+                let round = self.sync_round.fetch_add(1, Ordering::SeqCst);
+                let peer = &peers[round % peers.len()];
+                let msg = Message::new(
+                    self.node.clone(),
+                    peer.clone(),
+                    Body {
+                        id: Some(self.id.fetch_add(1, Ordering::SeqCst)),
+                        in_reply_to: None,
+                        payload: Payload::Sync {
+                            sync: self.digest.lock().unwrap().start(),
+                        },
+                    },
+                );
+                msg.send(&self.output)
+                    .context(format!("failed to send message to node: {peer}"))?;
+            }
+            Payload::Sync { sync } => {
+                let (learned, answer) = self.digest.lock().unwrap().handle(sync);
+                if !learned.is_empty() {
+                    let mut delta = GSet::default();
+                    for m in learned {
+                        delta.insert(m);
+                    }
+                    self.messages.lock().unwrap().receive(&delta);
+                }
+                if !answer.is_empty() {
+                    reply.body.payload = Payload::Sync { sync: answer };
+                    reply.send(&self.output).context("failed to send message")?;
+                }
+            }
+            Payload::HeartbeatTick => {
+                let mut detector = self.detector.lock().unwrap();
+                for event in detector.check() {
+                    eprintln!("MEMBERSHIP {:?}", event);
+                }
+                let peers = detector.due_pings();
+                for peer in &peers {
+                    detector.sent(peer);
+                }
+                drop(detector);
+                for peer in peers {
+                    let msg = Message::new(
+                        self.node.clone(),
+                        peer,
+                        Body {
+                            id: None,
+                            in_reply_to: None,
+                            payload: Payload::Heartbeat,
+                        },
+                    );
+                    msg.send(&self.output).context("failed to send heartbeat")?;
                 }
             }
             Payload::Read => {
                 reply.body.payload = Payload::ReadOk {
-                    messages: self.messages.clone(),
+                    messages: self
+                        .messages
+                        .lock()
+                        .unwrap()
+                        .state()
+                        .value()
+                        .into_iter()
+                        .collect(),
                 };
                 reply.send(&self.output).context("failed to send message")?;
             }
             Payload::Topology { mut topology } => {
-                self.neighbors = topology.remove(&self.node).unwrap_or_else(|| {
+                let neighbors = topology.remove(&self.node).unwrap_or_else(|| {
                     panic!("could not retrieve topology for node: {}", &self.node)
                 });
+                let neighbors: Vec<_> = neighbors.into_iter().filter(|n| *n != self.node).collect();
+                self.messages
+                    .lock()
+                    .unwrap()
+                    .set_peers(neighbors.iter().cloned());
+                self.detector.lock().unwrap().set_peers(neighbors);
                 reply.body.payload = Payload::TopologyOk;
                 reply.send(&self.output).context("failed to send message")?;
             }
-            Payload::BroadcastUnAcked => {
-                for m in self.waiting_for_ack.values() {
-                    m.send(&self.output).context("failed to send message")?;
-                }
-            }
-            Payload::TopologyOk | Payload::ReadOk { .. } => {}
+            Payload::Heartbeat
+            | Payload::BroadcastOk
+            | Payload::TopologyOk
+            | Payload::ReadOk { .. } => {}
         }
         Ok(())
     }
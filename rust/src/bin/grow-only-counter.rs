@@ -1,20 +1,51 @@
 use std::{
-    collections::HashMap,
-    sync::{atomic::AtomicUsize, Mutex},
-    thread,
-    time::Duration,
+    env,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
-use nazgul::{main_loop, Body, Message, Node};
+use nazgul::{
+    crdt::{Crdt, GCounter},
+    failure_detector::{FailureDetector, DEFAULT_THRESHOLD},
+    gossip::DeltaGossip,
+    main_loop,
+    membership::{Envelope, Swim, SwimConfig, SwimMsg},
+    spawn_ticker, Body, Message, Node,
+};
 use serde::{Deserialize, Serialize};
 
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(1000);
+const MAX_BUFFERED_DELTAS: usize = 32;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(1000);
+const SWIM_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Nodes to join through, from `NAZGUL_SEEDS` (comma-separated). Without it
+/// every node in `Init::node_ids` is a seed.
+struct Config {
+    seeds: Option<Vec<String>>,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        Self {
+            seeds: env::var("NAZGUL_SEEDS")
+                .ok()
+                .map(|s| s.split(',').map(|n| n.trim().to_string()).collect()),
+        }
+    }
+}
+
 struct GrowOnlyCounter {
     id: AtomicUsize,
     node: String,
-    count: usize,
-    node_ids: Vec<String>,
-    node_values: HashMap<String, usize>,
+    counter: Mutex<DeltaGossip<GCounter>>,
+    detector: Mutex<FailureDetector>,
+    swim: Mutex<Swim>,
+    seeds: Vec<String>,
     output: Mutex<std::io::Stdout>,
 }
 
@@ -26,95 +57,189 @@ enum Payload {
     AddOk,
     Read,
     ReadOk { value: usize },
-    ServerRead,
-    ServerReadOk { value: usize },
+    Gossip { seq: u64, counter: GCounter },
+    GossipOk { seq: u64 },
+    GossipTick,
+    Heartbeat,
+    HeartbeatTick,
+    Swim { swim: SwimMsg },
+    SwimTick,
 }
 
-impl Node<(), Payload> for GrowOnlyCounter {
+impl Node<Config, Payload> for GrowOnlyCounter {
     fn from_init(
-        _state: (),
+        config: Config,
         init: nazgul::Init,
         tx: std::sync::mpsc::Sender<nazgul::Message<Payload>>,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        let node = Self {
+        // peers are whoever SWIM finds alive, starting from nobody
+        let seeds = config.seeds.unwrap_or(init.node_ids);
+        spawn_ticker(
+            init.node_id.clone(),
+            SWIM_INTERVAL,
+            Payload::SwimTick,
+            tx.clone(),
+        );
+        spawn_ticker(
+            init.node_id.clone(),
+            GOSSIP_INTERVAL,
+            Payload::GossipTick,
+            tx.clone(),
+        );
+        spawn_ticker(
+            init.node_id.clone(),
+            HEARTBEAT_INTERVAL,
+            Payload::HeartbeatTick,
+            tx,
+        );
+        Ok(Self {
             id: AtomicUsize::new(1),
-            node: init.node_id.clone(),
-            count: 0,
-            node_ids: init
-                .node_ids
-                .into_iter()
-                .filter(|x| *x != init.node_id)
-                .collect(),
-            node_values: HashMap::new(),
+            swim: Mutex::new(Swim::new(&init.node_id, SwimConfig::default())),
+            node: init.node_id,
+            counter: Mutex::new(DeltaGossip::new(MAX_BUFFERED_DELTAS)),
+            detector: Mutex::new(FailureDetector::new(HEARTBEAT_INTERVAL, DEFAULT_THRESHOLD)),
+            seeds,
             output: Mutex::new(std::io::stdout()),
-        };
-        let nid = node.node.clone();
-        let nids = node.node_ids.clone();
-        thread::spawn(move || loop {
-            thread::sleep(Duration::from_millis(5000));
-            for n in nids.clone() {
-                let msg = Message {
-                    src: n,
-                    dst: nid.to_string(),
-                    body: Body {
-                        id: None,
-                        in_reply_to: None,
-                        payload: Payload::ServerRead,
-                    },
-                };
-                if tx.send(msg).is_err() {
-                    break;
-                }
-            }
-        });
-        Ok(node)
+        })
     }
 
     fn step(&self, input: nazgul::Message<Payload>) -> anyhow::Result<()> {
+        // every message from a peer doubles as a heartbeat
+        if let Some(event) = self.detector.lock().unwrap().heartbeat(&input.src) {
+            eprintln!("MEMBERSHIP {:?}", event);
+        }
         let mut reply = input.into_reply(Some(&self.id));
         match reply.body.payload {
             Payload::Add { delta } => {
-                // TODO: needs refactor to use mutable ref
-                // switched to immutable self because of kafka-log
-                // consider smart pointers to get mutable access
-                self.count += delta;
+                self.counter
+                    .lock()
+                    .unwrap()
+                    .update(|c| c.increment(&self.node, delta));
                 reply.body.payload = Payload::AddOk;
                 reply.send(&self.output).context("sending AddOk")?;
             }
             Payload::Read => {
-                let mut count = self.count;
-                for nv in self.node_values.values() {
-                    count += nv;
-                }
-                reply.body.payload = Payload::ReadOk { value: count };
+                let value = self.counter.lock().unwrap().state().value();
+                reply.body.payload = Payload::ReadOk { value };
                 reply.send(&self.output).context("sending ReadOk")?;
             }
-            Payload::ServerRead => {
-                eprintln!(
-                    "SENDING ServerRead from {}, going to {}, with value {}",
-                    reply.src, reply.dst, self.count
-                );
-                reply.body.payload = Payload::ServerReadOk { value: self.count };
-                reply.send(&self.output).context("sending ServerReadOk")?;
+            Payload::Gossip { seq, counter } => {
+                self.counter.lock().unwrap().receive(&counter);
+                reply.body.payload = Payload::GossipOk { seq };
+                reply.send(&self.output).context("sending GossipOk")?;
+            }
+            Payload::GossipOk { seq } => {
+                // reply.dst is the node that acked
+                self.counter.lock().unwrap().ack(&reply.dst, seq);
+            }
+            Payload::GossipTick => {
+                let outgoing = self.counter.lock().unwrap().flush();
+                for o in outgoing {
+                    let mut detector = self.detector.lock().unwrap();
+                    if !detector.is_alive(&o.peer) {
+                        continue;
+                    }
+                    detector.sent(&o.peer);
+                    drop(detector);
+                    let msg = Message::new(
+                        self.node.clone(),
+                        o.peer,
+                        Body {
+                            id: Some(self.id.fetch_add(1, Ordering::SeqCst)),
+                            in_reply_to: None,
+                            payload: Payload::Gossip {
+                                seq: o.seq,
+                                counter: o.delta,
+                            },
+                        },
+                    );
+                    msg.send(&self.output).context("sending Gossip")?;
+                }
+            }
+            Payload::HeartbeatTick => {
+                let mut detector = self.detector.lock().unwrap();
+                for event in detector.check() {
+                    eprintln!("MEMBERSHIP {:?}", event);
+                }
+                let peers = detector.due_pings();
+                for peer in &peers {
+                    detector.sent(peer);
+                }
+                drop(detector);
+                for peer in peers {
+                    let msg = Message::new(
+                        self.node.clone(),
+                        peer,
+                        Body {
+                            id: None,
+                            in_reply_to: None,
+                            payload: Payload::Heartbeat,
+                        },
+                    );
+                    msg.send(&self.output).context("sending Heartbeat")?;
+                }
+            }
+            Payload::Swim { swim } => {
+                let out = self
+                    .swim
+                    .lock()
+                    .unwrap()
+                    .handle(&reply.dst, swim, Instant::now());
+                self.swim_out(out)?;
+            }
+            Payload::SwimTick => {
+                let mut swim = self.swim.lock().unwrap();
+                let mut out = swim.tick(Instant::now());
+                if swim.members().is_empty() {
+                    // no seed has answered yet, or all of them are gone
+                    out.extend(swim.join(self.seeds.iter().cloned()));
+                }
+                drop(swim);
+                self.swim_out(out)?;
             }
-            Payload::ServerReadOk { value } => {
-                eprintln!(
-                    "RECEIVED ServerOk from {}, going to {}, with value {}",
-                    reply.dst, reply.src, value
-                );
-                // we don't want to send an actual message
-                // reply.dst is the actual src
-                self.node_values.insert(reply.dst, value);
+            Payload::Heartbeat | Payload::AddOk | Payload::ReadOk { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+impl GrowOnlyCounter {
+    /// Sends SWIM's messages and hands any membership change on to gossip
+    /// and the failure detector.
+    fn swim_out(&self, out: Vec<Envelope>) -> anyhow::Result<()> {
+        let mut swim = self.swim.lock().unwrap();
+        let events = swim.drain_events();
+        if !events.is_empty() {
+            for event in &events {
+                eprintln!("MEMBERSHIP {:?}", event);
             }
-            Payload::AddOk | Payload::ReadOk { .. } => {}
+            let peers = swim.members();
+            self.counter
+                .lock()
+                .unwrap()
+                .set_peers(peers.iter().cloned());
+            self.detector.lock().unwrap().set_peers(peers);
+        }
+        drop(swim);
+        for e in out {
+            let msg = Message::new(
+                self.node.clone(),
+                e.to,
+                Body {
+                    id: None,
+                    in_reply_to: None,
+                    payload: Payload::Swim { swim: e.msg },
+                },
+            );
+            msg.send(&self.output).context("sending Swim")?;
         }
         Ok(())
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    main_loop::<_, GrowOnlyCounter, _>(())
+    main_loop::<_, GrowOnlyCounter, _>(Config::from_env())
 }
@@ -0,0 +1,704 @@
+//! Serves a subset of the Kafka protocol over TCP in front of a local
+//! kafka-log cluster, so ordinary Kafka clients can produce to and fetch
+//! from it in tests.
+//!
+//! The nodes only speak Maelstrom JSON over stdin and stdout, so the gateway
+//! starts them as child processes and routes their messages between them,
+//! the way Maelstrom would. To clients the whole cluster is a single broker.
+//!
+//! Partition `p` of topic `t` is the kafka-log key `t-p`. Kafka offsets start
+//! at 0 and kafka-log offsets at 1, so they are shifted by one; a key's last
+//! offset doubles as the partition's high watermark. Record values and header
+//! values become JSON strings when they are UTF-8 and arrays of bytes
+//! otherwise, record keys become sub-keys, and messages sent to kafka-log
+//! directly are fetched as their JSON text. Timestamps are the append times
+//! kafka-log assigns.
+//!
+//! Consumers need manual partition assignment: there are no group
+//! membership requests, only commits and fetches of a group's offsets, which
+//! like kafka-log's commits never move backwards.
+
+use anyhow::{bail, Context};
+use nazgul::{
+    kafka_api::{
+        self, ApiVersionsResponse, Broker, FetchRequest, FetchResponse, Fetched,
+        FindCoordinatorRequest, FindCoordinatorResponse, ListOffsetsRequest, ListOffsetsResponse,
+        ListedOffset, MetadataRequest, MetadataResponse, OffsetCommitRequest, OffsetCommitResponse,
+        OffsetFetchRequest, OffsetFetchResponse, PartitionMetadata, ProduceRequest,
+        ProduceResponse, Produced, API_VERSIONS, CORRUPT_MESSAGE, FETCH, FIND_COORDINATOR,
+        LIST_OFFSETS, METADATA, NONE, NOT_LEADER_OR_FOLLOWER, OFFSET_COMMIT, OFFSET_FETCH,
+        OFFSET_OUT_OF_RANGE, PRODUCE, REQUEST_TIMED_OUT, UNKNOWN_SERVER_ERROR, UNSUPPORTED_VERSION,
+    },
+    kv::{RpcError, TEMPORARILY_UNAVAILABLE, TIMEOUT},
+    wire::{self, Reader, Record, Writer},
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeSet, HashMap},
+    env,
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    process::{ChildStdin, ChildStdout, Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:9092";
+const CLIENT: &str = "gateway";
+const RPC_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often a fetch waiting for messages polls again.
+const FETCH_RETRY: Duration = Duration::from_millis(50);
+/// Requests larger than this close the connection.
+const MAX_REQUEST_BYTES: usize = 64 << 20;
+const BROKER_ID: i32 = 0;
+
+#[derive(Debug)]
+struct Config {
+    addr: String,
+    nodes: usize,
+    partitions: i32,
+    kafka_log: PathBuf,
+}
+
+impl Config {
+    /// `NAZGUL_GATEWAY_ADDR` is the address to listen on,
+    /// `NAZGUL_GATEWAY_NODES` the number of kafka-log nodes to start and
+    /// `NAZGUL_GATEWAY_PARTITIONS` the partitions of every topic.
+    /// `NAZGUL_KAFKA_LOG` points at the kafka-log binary, by default the one
+    /// next to the gateway. The nodes inherit the environment, so the
+    /// kafka-log settings apply to them as usual.
+    fn from_env() -> anyhow::Result<Self> {
+        let number = |var: &str, default: usize| -> anyhow::Result<usize> {
+            match env::var(var) {
+                Ok(v) => v
+                    .parse()
+                    .with_context(|| format!("{} must be a number", var)),
+                Err(_) => Ok(default),
+            }
+        };
+        let kafka_log = match env::var_os("NAZGUL_KAFKA_LOG") {
+            Some(path) => PathBuf::from(path),
+            None => env::current_exe()
+                .context("locate the gateway binary")?
+                .with_file_name("kafka-log"),
+        };
+        let config = Self {
+            addr: env::var("NAZGUL_GATEWAY_ADDR").unwrap_or_else(|_| DEFAULT_ADDR.to_string()),
+            nodes: number("NAZGUL_GATEWAY_NODES", 1)?,
+            partitions: number("NAZGUL_GATEWAY_PARTITIONS", 1)? as i32,
+            kafka_log,
+        };
+        if config.nodes == 0 || config.partitions <= 0 {
+            bail!("the gateway needs at least one node and one partition");
+        }
+        Ok(config)
+    }
+}
+
+/// The kafka-log nodes and the messages in flight between them.
+struct Cluster {
+    nodes: Vec<String>,
+    /// The nodes exit when these close with the gateway.
+    inputs: HashMap<String, Mutex<ChildStdin>>,
+    id: AtomicUsize,
+    /// Round-robin position for spreading requests over the nodes.
+    next: AtomicUsize,
+    rpc: Mutex<HashMap<usize, oneshot::Sender<Value>>>,
+}
+
+impl Cluster {
+    fn start(bin: &PathBuf, n: usize) -> anyhow::Result<Arc<Self>> {
+        let nodes: Vec<String> = (1..=n).map(|i| format!("n{}", i)).collect();
+        let mut inputs = HashMap::new();
+        let mut outputs = Vec::new();
+        for node in &nodes {
+            let mut child = Command::new(bin)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .with_context(|| format!("start {} from {}", node, bin.display()))?;
+            let input = child.stdin.take().expect("stdin is piped");
+            outputs.push(child.stdout.take().expect("stdout is piped"));
+            inputs.insert(node.clone(), Mutex::new(input));
+        }
+        let cluster = Arc::new(Self {
+            nodes,
+            inputs,
+            id: AtomicUsize::new(1),
+            next: AtomicUsize::new(0),
+            rpc: Mutex::new(HashMap::new()),
+        });
+        for output in outputs {
+            let cluster = cluster.clone();
+            thread::spawn(move || cluster.route(output));
+        }
+        for node in &cluster.nodes {
+            let init = json!({"type": "init", "node_id": node, "node_ids": cluster.nodes});
+            cluster
+                .call_node(node, init)
+                .with_context(|| format!("initialize {}", node))?;
+        }
+        Ok(cluster)
+    }
+
+    /// Delivers everything a node writes, to another node or to a caller
+    /// waiting for its reply.
+    fn route(&self, output: ChildStdout) {
+        for line in BufReader::new(output).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            let msg: Value = match serde_json::from_str(&line) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    eprintln!("unreadable node output {:?}: {}", line, e);
+                    continue;
+                }
+            };
+            if let Some(input) = msg["dest"].as_str().and_then(|d| self.inputs.get(d)) {
+                let mut input = input.lock().unwrap();
+                if let Err(e) = writeln!(input, "{}", line) {
+                    eprintln!("delivering to {}: {}", msg["dest"], e);
+                }
+                continue;
+            }
+            let waiting = msg["body"]["in_reply_to"]
+                .as_u64()
+                .and_then(|id| self.rpc.lock().unwrap().remove(&(id as usize)));
+            if let Some(tx) = waiting {
+                let _ = tx.send(msg["body"].clone());
+            }
+        }
+    }
+
+    /// Sends a request to the next node in turn; kafka-log forwards it to
+    /// whichever node leads the keys involved.
+    fn call(&self, body: Value) -> anyhow::Result<Value> {
+        let i = self.next.fetch_add(1, Ordering::SeqCst);
+        let node = &self.nodes[i % self.nodes.len()];
+        self.call_node(node, body)
+    }
+
+    /// Error replies come back as [`RpcError`]s.
+    fn call_node(&self, node: &str, mut body: Value) -> anyhow::Result<Value> {
+        let id = self.id.fetch_add(1, Ordering::SeqCst);
+        body["msg_id"] = id.into();
+        let msg = json!({"src": CLIENT, "dest": node, "body": body});
+        let (tx, rx) = oneshot::channel();
+        self.rpc.lock().unwrap().insert(id, tx);
+        let sent = writeln!(self.inputs[node].lock().unwrap(), "{}", msg);
+        if let Err(e) = sent {
+            self.rpc.lock().unwrap().remove(&id);
+            return Err(e).with_context(|| format!("send to {}", node));
+        }
+        let Ok(reply) = rx.recv_timeout(RPC_TIMEOUT) else {
+            self.rpc.lock().unwrap().remove(&id);
+            return Err(RpcError {
+                code: TIMEOUT,
+                text: format!("{} did not answer", node),
+            }
+            .into());
+        };
+        if reply["type"] == "error" {
+            return Err(RpcError {
+                code: reply["code"].as_u64().unwrap_or_default() as usize,
+                text: reply["text"].as_str().unwrap_or_default().to_string(),
+            }
+            .into());
+        }
+        Ok(reply)
+    }
+}
+
+struct Gateway {
+    cluster: Arc<Cluster>,
+    partitions: i32,
+    host: String,
+    port: i32,
+    /// Topics clients have asked for or produced to, which may not have any
+    /// keys in the cluster yet.
+    topics: Mutex<BTreeSet<String>>,
+}
+
+fn key(topic: &str, partition: i32) -> String {
+    format!("{}-{}", topic, partition)
+}
+
+/// Kafka's error code for a failed kafka-log request. Retriable codes make
+/// clients try again.
+fn error_code(e: &anyhow::Error) -> i16 {
+    match RpcError::code_of(e) {
+        Some(TIMEOUT) => REQUEST_TIMED_OUT,
+        Some(TEMPORARILY_UNAVAILABLE) => NOT_LEADER_OR_FOLLOWER,
+        _ => UNKNOWN_SERVER_ERROR,
+    }
+}
+
+fn to_json(bytes: Option<&[u8]>) -> Value {
+    match bytes {
+        None => Value::Null,
+        Some(b) => match std::str::from_utf8(b) {
+            Ok(s) => s.into(),
+            Err(_) => b.to_vec().into(),
+        },
+    }
+}
+
+fn from_json(value: &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone().into_bytes()),
+        Value::Array(a) => a
+            .iter()
+            .map(|b| b.as_u64().and_then(|b| u8::try_from(b).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .or_else(|| Some(value.to_string().into_bytes())),
+        _ => Some(value.to_string().into_bytes()),
+    }
+}
+
+impl Gateway {
+    fn serve(&self, mut stream: TcpStream) -> anyhow::Result<()> {
+        loop {
+            let mut len = [0; 4];
+            match stream.read_exact(&mut len) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e).context("read request size"),
+            }
+            let len = i32::from_be_bytes(len);
+            if len < 0 || len as usize > MAX_REQUEST_BYTES {
+                bail!("request of {} bytes", len);
+            }
+            let mut request = vec![0; len as usize];
+            stream.read_exact(&mut request).context("read request")?;
+            let mut r = Reader::new(&request);
+            let api_key = r.i16()?;
+            let version = r.i16()?;
+            let correlation_id = r.i32()?;
+            let _client_id = r.nullable_string()?;
+            let Some(body) = self.handle(api_key, version, &mut r)? else {
+                continue;
+            };
+            let mut response = Writer::new();
+            response
+                .i32(body.len() as i32 + 4)
+                .i32(correlation_id)
+                .raw(&body);
+            stream
+                .write_all(&response.into_inner())
+                .context("write response")?;
+        }
+    }
+
+    /// The response body, if the request gets one.
+    fn handle(
+        &self,
+        api_key: i16,
+        version: i16,
+        r: &mut Reader,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        if !kafka_api::supported(api_key, version) {
+            // clients start with the newest ApiVersions they know and retry
+            // with the versions listed in the error
+            if api_key == API_VERSIONS {
+                let error = UNSUPPORTED_VERSION;
+                return Ok(Some(ApiVersionsResponse { error }.encode(0)));
+            }
+            bail!("unsupported api {} version {}", api_key, version);
+        }
+        let body = match api_key {
+            API_VERSIONS => ApiVersionsResponse { error: NONE }.encode(version),
+            METADATA => self
+                .metadata(MetadataRequest::decode(version, r)?)
+                .encode(version),
+            PRODUCE => {
+                let request = ProduceRequest::decode(version, r)?;
+                let acks = request.acks;
+                let response = self.produce(request);
+                // acks 0 asks for no response
+                if acks == 0 {
+                    return Ok(None);
+                }
+                response.encode(version)
+            }
+            FETCH => self
+                .fetch(FetchRequest::decode(version, r)?)
+                .encode(version),
+            LIST_OFFSETS => self
+                .list_offsets(ListOffsetsRequest::decode(version, r)?)
+                .encode(version),
+            OFFSET_COMMIT => self
+                .offset_commit(OffsetCommitRequest::decode(version, r)?)
+                .encode(version),
+            OFFSET_FETCH => self
+                .offset_fetch(OffsetFetchRequest::decode(version, r)?)
+                .encode(version),
+            FIND_COORDINATOR => {
+                let _request = FindCoordinatorRequest::decode(version, r)?;
+                self.find_coordinator().encode(version)
+            }
+            _ => unreachable!("checked against APIS"),
+        };
+        Ok(Some(body))
+    }
+
+    fn broker(&self) -> Broker {
+        Broker {
+            node_id: BROKER_ID,
+            host: self.host.clone(),
+            port: self.port,
+        }
+    }
+
+    fn metadata(&self, request: MetadataRequest) -> MetadataResponse {
+        let topics: Vec<String> = match request.topics {
+            Some(requested) => {
+                let mut known = self.topics.lock().unwrap();
+                known.extend(requested.iter().cloned());
+                requested
+            }
+            None => self.all_topics(),
+        };
+        let partitions: Vec<PartitionMetadata> = (0..self.partitions)
+            .map(|index| PartitionMetadata {
+                index,
+                leader: BROKER_ID,
+                replicas: vec![BROKER_ID],
+                isr: vec![BROKER_ID],
+            })
+            .collect();
+        MetadataResponse {
+            brokers: vec![self.broker()],
+            cluster_id: Some("nazgul".to_string()),
+            controller_id: BROKER_ID,
+            topics: topics
+                .into_iter()
+                .map(|t| (t, partitions.clone()))
+                .collect(),
+        }
+    }
+
+    /// Topics known here and those behind the cluster's keys. Keys that do
+    /// not name a partition of a topic are left out.
+    fn all_topics(&self) -> Vec<String> {
+        let mut topics = self.topics.lock().unwrap().clone();
+        match self.cluster.call(json!({"type": "list_keys"})) {
+            Ok(listed) => {
+                let keys = listed["keys"].as_array().into_iter().flatten();
+                for key in keys.filter_map(Value::as_str) {
+                    let Some((topic, partition)) = key.rsplit_once('-') else {
+                        continue;
+                    };
+                    if partition.parse().is_ok_and(|p: i32| p < self.partitions) {
+                        topics.insert(topic.to_string());
+                    }
+                }
+            }
+            Err(e) => eprintln!("listing keys: {:#}", e),
+        }
+        topics.into_iter().collect()
+    }
+
+    /// Sends the records of each partition as one batch, in order.
+    fn produce(&self, request: ProduceRequest) -> ProduceResponse {
+        let mut topics = Vec::new();
+        for (topic, partitions) in request.topics {
+            self.topics.lock().unwrap().insert(topic.clone());
+            let mut produced = Vec::new();
+            for (index, records) in partitions {
+                let (error, base_offset) = match self.append(&topic, index, records) {
+                    Ok(base_offset) => (NONE, base_offset),
+                    Err(e) => {
+                        eprintln!("produce to {}: {:#}", key(&topic, index), e);
+                        let code = match e.downcast_ref::<RpcError>() {
+                            None if RpcError::code_of(&e).is_none() => CORRUPT_MESSAGE,
+                            _ => error_code(&e),
+                        };
+                        (code, -1)
+                    }
+                };
+                produced.push(Produced {
+                    index,
+                    error,
+                    base_offset,
+                });
+            }
+            topics.push((topic, produced));
+        }
+        ProduceResponse { topics }
+    }
+
+    /// Sends the records in one request, so they get consecutive offsets.
+    /// Returns the Kafka offset of the first record.
+    fn append(&self, topic: &str, partition: i32, records: Option<&[u8]>) -> anyhow::Result<i64> {
+        let records = wire::decode_batches(records.unwrap_or_default())?;
+        if records.is_empty() {
+            return Ok(-1);
+        }
+        let msgs: Vec<Value> = records
+            .iter()
+            .map(|record| {
+                let headers: serde_json::Map<String, Value> = record
+                    .headers
+                    .iter()
+                    .map(|(name, value)| (name.clone(), to_json(value.as_deref())))
+                    .collect();
+                let mut msg = json!({
+                    "msg": to_json(record.value.as_deref()),
+                    "headers": headers,
+                });
+                if let Some(k) = &record.key {
+                    msg["sub_key"] = String::from_utf8_lossy(k).into();
+                }
+                msg
+            })
+            .collect();
+        let send = json!({
+            "type": "send_batch",
+            "key": key(topic, partition),
+            "msgs": msgs,
+        });
+        let reply = self.cluster.call(send)?;
+        let first = reply["offsets"][0]
+            .as_i64()
+            .context("send_batch_ok without offsets")?;
+        Ok(first - 1)
+    }
+
+    /// Polls until some partition has messages or `max_wait` passes.
+    fn fetch(&self, request: FetchRequest) -> FetchResponse {
+        let isolation = match request.read_committed {
+            false => "read_uncommitted",
+            true => "read_committed",
+        };
+        let offsets: HashMap<String, i64> = request
+            .topics
+            .iter()
+            .flat_map(|(t, ps)| ps.iter().map(move |(p, o)| (key(t, *p), *o + 1)))
+            .collect();
+        let deadline = Instant::now() + request.max_wait;
+        let polled = loop {
+            let poll = json!({
+                "type": "poll",
+                "offsets": offsets,
+                "isolation": isolation,
+                "metadata": true,
+            });
+            let polled = self.cluster.call(poll);
+            let empty = polled.as_ref().is_ok_and(|p| {
+                p["msgs"]
+                    .as_object()
+                    .is_some_and(|m| m.values().all(|v| v.as_array().is_some_and(Vec::is_empty)))
+            });
+            if !empty || Instant::now() >= deadline {
+                break polled;
+            }
+            thread::sleep(FETCH_RETRY);
+        };
+
+        let fetched = |topic: &str, (index, offset): (i32, i64)| {
+            let key = key(topic, index);
+            let (error, high_watermark, records) = match &polled {
+                Ok(p) => {
+                    let hw = p["ends"][&key].as_i64().unwrap_or_default();
+                    if offset > hw {
+                        (OFFSET_OUT_OF_RANGE, hw, Vec::new())
+                    } else {
+                        (NONE, hw, records(&p["metadata"][&key], &p["msgs"][&key]))
+                    }
+                }
+                Err(e) => (error_code(e), -1, Vec::new()),
+            };
+            Fetched {
+                index,
+                error,
+                high_watermark,
+                records,
+            }
+        };
+        let topics = request
+            .topics
+            .into_iter()
+            .map(|(topic, partitions)| {
+                let partitions = partitions.into_iter().map(|p| fetched(&topic, p)).collect();
+                (topic, partitions)
+            })
+            .collect();
+        FetchResponse { topics }
+    }
+
+    fn list_offsets(&self, request: ListOffsetsRequest) -> ListOffsetsResponse {
+        let queries: HashMap<String, Value> = request
+            .topics
+            .iter()
+            .flat_map(|(t, ps)| {
+                ps.iter().map(move |(p, timestamp)| {
+                    let query = match timestamp {
+                        -1 => "latest".into(),
+                        -2 => "earliest".into(),
+                        t => (*t).max(0).into(),
+                    };
+                    (key(t, *p), query)
+                })
+            })
+            .collect();
+        let listed = self
+            .cluster
+            .call(json!({"type": "list_offsets", "offsets": queries}));
+
+        let topics = request
+            .topics
+            .into_iter()
+            .map(|(topic, partitions)| {
+                let partitions = partitions
+                    .into_iter()
+                    .map(|(index, _)| {
+                        let (error, offset) = match &listed {
+                            Ok(l) => {
+                                let offset = l["offsets"][&key(&topic, index)].as_i64();
+                                (NONE, offset.map_or(-1, |o| o - 1))
+                            }
+                            Err(e) => (error_code(e), -1),
+                        };
+                        ListedOffset {
+                            index,
+                            error,
+                            offset,
+                        }
+                    })
+                    .collect();
+                (topic, partitions)
+            })
+            .collect();
+        ListOffsetsResponse { topics }
+    }
+
+    fn offset_commit(&self, request: OffsetCommitRequest) -> OffsetCommitResponse {
+        let offsets: HashMap<String, i64> = request
+            .topics
+            .iter()
+            .flat_map(|(t, ps)| ps.iter().map(move |(p, o)| (key(t, *p), *o)))
+            .filter(|(_, o)| *o >= 0)
+            .collect();
+        let commit = json!({
+            "type": "commit_offsets",
+            "offsets": offsets,
+            "group": request.group,
+        });
+        let error = match self.cluster.call(commit) {
+            Ok(_) => NONE,
+            Err(e) => error_code(&e),
+        };
+        let topics = request
+            .topics
+            .into_iter()
+            .map(|(topic, partitions)| {
+                let partitions = partitions.into_iter().map(|(p, _)| (p, error)).collect();
+                (topic, partitions)
+            })
+            .collect();
+        OffsetCommitResponse { topics }
+    }
+
+    fn offset_fetch(&self, request: OffsetFetchRequest) -> OffsetFetchResponse {
+        let topics = request.topics.unwrap_or_else(|| {
+            let all: Vec<i32> = (0..self.partitions).collect();
+            let known = self.topics.lock().unwrap();
+            known.iter().map(|t| (t.clone(), all.clone())).collect()
+        });
+
+        let keys: Vec<String> = topics
+            .iter()
+            .flat_map(|(t, ps)| ps.iter().map(move |p| key(t, *p)))
+            .collect();
+        let listed = self.cluster.call(json!({
+            "type": "list_committed_offsets",
+            "keys": keys,
+            "group": request.group,
+        }));
+        let error = listed.as_ref().err().map_or(NONE, error_code);
+        let topics = topics
+            .into_iter()
+            .map(|(topic, partitions)| {
+                let partitions = partitions
+                    .into_iter()
+                    .map(|p| {
+                        let offset = listed
+                            .as_ref()
+                            .ok()
+                            .and_then(|l| l["offsets"][&key(&topic, p)].as_i64());
+                        (p, offset.unwrap_or(-1))
+                    })
+                    .collect();
+                (topic, partitions)
+            })
+            .collect();
+        OffsetFetchResponse { topics, error }
+    }
+
+    /// Every group is coordinated by the gateway itself.
+    fn find_coordinator(&self) -> FindCoordinatorResponse {
+        FindCoordinatorResponse {
+            coordinator: self.broker(),
+        }
+    }
+}
+
+/// Records for a poll's messages, from its metadata and values.
+fn records(metadata: &Value, msgs: &Value) -> Vec<Record> {
+    let metadata = metadata.as_array().map(Vec::as_slice).unwrap_or_default();
+    let msgs = msgs.as_array().map(Vec::as_slice).unwrap_or_default();
+    metadata
+        .iter()
+        .zip(msgs)
+        .map(|(meta, msg)| {
+            let headers = meta["headers"].as_object().into_iter().flatten();
+            Record {
+                offset: meta["offset"].as_i64().unwrap_or_default() - 1,
+                timestamp: meta["timestamp"].as_i64().unwrap_or_default(),
+                key: meta["sub_key"].as_str().map(|k| k.as_bytes().to_vec()),
+                value: from_json(&msg[1]),
+                headers: headers.map(|(k, v)| (k.clone(), from_json(v))).collect(),
+            }
+        })
+        .collect()
+}
+
+fn main() -> anyhow::Result<()> {
+    let config = Config::from_env()?;
+    let listener =
+        TcpListener::bind(&config.addr).with_context(|| format!("listen on {}", config.addr))?;
+    let local = listener.local_addr().context("read listening address")?;
+    let cluster = Cluster::start(&config.kafka_log, config.nodes)?;
+    let gateway = Arc::new(Gateway {
+        cluster,
+        partitions: config.partitions,
+        host: local.ip().to_string(),
+        port: local.port().into(),
+        topics: Mutex::new(BTreeSet::new()),
+    });
+    eprintln!("serving the Kafka protocol on {}", local);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("accept: {}", e);
+                continue;
+            }
+        };
+        let gateway = gateway.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            if let Err(e) = gateway.serve(stream) {
+                eprintln!("connection from {:?}: {:#}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
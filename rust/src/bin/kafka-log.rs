@@ -3,40 +3,258 @@
 #![allow(unused_variables)]
 
 use std::{
-    collections::{HashMap, LinkedList},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env,
+    path::PathBuf,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Mutex,
     },
+    thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::{bail, Context};
-use nazgul::{main_loop, Body, Message, Node, KV};
+use nazgul::{
+    broker::{Broker, Outbox, Replicate, ReplicateOk},
+    failure_detector::{FailureDetector, DEFAULT_THRESHOLD},
+    group::{Assignment, ConsumerGroups},
+    kv::{
+        KvCommand, KvError, KvStore, RpcError, ABORT, KEY_DOES_NOT_EXIST, MALFORMED_REQUEST,
+        NOT_SUPPORTED, PRECONDITION_FAILED, TEMPORARILY_UNAVAILABLE,
+    },
+    log::{now_ms, Isolation, Log, LogStore, Outcome, TxnRef},
+    main_loop,
+    replication::Epoch,
+    ring::HashRing,
+    rsm::StateMachine,
+    spawn_ticker,
+    storage::{FsyncPolicy, Retention, Storage, StorageConfig},
+    subscription::{Due, Subscriptions},
+    Body, Message, Node, KV,
+};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+const DEFAULT_POLL_BATCH: usize = 5;
+/// Pending transactions older than this are aborted by their participants.
+const TXN_TIMEOUT: Duration = Duration::from_secs(5);
+const TXN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const CLEAN_INTERVAL: Duration = Duration::from_secs(1);
+const REPLICATE_INTERVAL: Duration = Duration::from_millis(50);
+/// How long a send waits for its offset to reach the high watermark. Longer
+/// than the lag timeout, so a dead follower leaves the ISR before it expires.
+const REPLICATION_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long an rpc to another node may take; longer than the replication
+/// timeout so a forwarded send gets the leader's own answer.
+const RPC_TIMEOUT: Duration = Duration::from_secs(4);
+const PUSH_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Mode {
+    /// Offsets are allocated by CAS on the key's metadata shard and messages
+    /// are stored in seq-kv.
+    Kv,
+    /// Every key is led by one of its replicas on the hash ring, which keeps
+    /// its log and committed offsets and copies them to the other replicas.
+    /// Other nodes forward to it.
+    #[default]
+    Owner,
+}
+
+#[derive(Debug, Clone)]
+struct Config {
+    mode: Mode,
+    /// Most messages returned per key by a single poll.
+    poll_batch: usize,
+    /// Number of nodes holding each key's log, the leader included.
+    replication_factor: usize,
+    /// In-sync replicas, the leader included, a key needs to take writes.
+    min_insync: usize,
+    /// Owners keep their logs in segment files under `{data_dir}/{node_id}`
+    /// instead of in memory when this is set.
+    data_dir: Option<PathBuf>,
+    segment_bytes: Option<u64>,
+    fsync: Option<FsyncPolicy>,
+    /// How much of each owned log is kept.
+    retention: Retention,
+    /// Whether owners drop messages superseded by a later one with the same
+    /// sub-key.
+    compact: bool,
+}
+
+impl Config {
+    /// Reads `NAZGUL_KAFKA_MODE` (`owner` or `kv`), `NAZGUL_POLL_BATCH`,
+    /// `NAZGUL_REPLICATION_FACTOR`, `NAZGUL_MIN_INSYNC` (2 by default, or 1
+    /// without replication), and
+    /// for disk-backed logs `NAZGUL_DATA_DIR`, `NAZGUL_SEGMENT_BYTES` and
+    /// `NAZGUL_FSYNC` (`always`, `never` or an interval like `100ms`).
+    /// Retention is set by `NAZGUL_RETENTION_MESSAGES`, `NAZGUL_RETENTION_MS`
+    /// and `NAZGUL_RETENTION_BYTES`; `NAZGUL_COMPACT=1` turns on compaction.
+    fn from_env() -> anyhow::Result<Self> {
+        let mode = match env::var("NAZGUL_KAFKA_MODE").as_deref() {
+            Ok("kv") => Mode::Kv,
+            Ok("owner") | Err(_) => Mode::Owner,
+            Ok(other) => bail!("unknown kafka-log mode: {}", other),
+        };
+        let poll_batch = match env::var("NAZGUL_POLL_BATCH") {
+            Ok(n) => n.parse().context("NAZGUL_POLL_BATCH is not a number")?,
+            Err(_) => DEFAULT_POLL_BATCH,
+        };
+        if poll_batch == 0 {
+            bail!("NAZGUL_POLL_BATCH must be at least 1");
+        }
+        let replication_factor = env_number("NAZGUL_REPLICATION_FACTOR")?.unwrap_or(1) as usize;
+        if replication_factor == 0 {
+            bail!("NAZGUL_REPLICATION_FACTOR must be at least 1");
+        }
+        let min_insync =
+            env_number("NAZGUL_MIN_INSYNC")?.map_or(replication_factor.min(2), |n| n as usize);
+        if min_insync == 0 || min_insync > replication_factor {
+            bail!("NAZGUL_MIN_INSYNC must be between 1 and the replication factor");
+        }
+        let segment_bytes = match env::var("NAZGUL_SEGMENT_BYTES") {
+            Ok(n) => Some(n.parse().context("NAZGUL_SEGMENT_BYTES is not a number")?),
+            Err(_) => None,
+        };
+        let fsync = match env::var("NAZGUL_FSYNC").as_deref() {
+            Ok("always") => Some(FsyncPolicy::Always),
+            Ok("never") => Some(FsyncPolicy::Never),
+            Ok(interval) => {
+                let ms = interval
+                    .strip_suffix("ms")
+                    .and_then(|ms| ms.parse().ok())
+                    .with_context(|| format!("bad NAZGUL_FSYNC: {}", interval))?;
+                Some(FsyncPolicy::Interval(Duration::from_millis(ms)))
+            }
+            Err(_) => None,
+        };
+        let retention = Retention {
+            max_records: env_number("NAZGUL_RETENTION_MESSAGES")?,
+            max_age: env_number("NAZGUL_RETENTION_MS")?.map(Duration::from_millis),
+            max_bytes: env_number("NAZGUL_RETENTION_BYTES")?,
+        };
+        let compact = matches!(
+            env::var("NAZGUL_COMPACT").as_deref(),
+            Ok("1" | "true" | "yes")
+        );
+        Ok(Config {
+            mode,
+            poll_batch,
+            replication_factor,
+            min_insync,
+            data_dir: env::var_os("NAZGUL_DATA_DIR").map(PathBuf::from),
+            segment_bytes,
+            fsync,
+            retention,
+            compact,
+        })
+    }
+
+    /// Whether owners need the background cleaner.
+    fn cleans(&self) -> bool {
+        self.compact || self.retention != Retention::default()
+    }
+
+    fn storage(&self, node: &str) -> Option<StorageConfig> {
+        let mut config = StorageConfig::new(self.data_dir.as_ref()?.join(node));
+        if let Some(segment_bytes) = self.segment_bytes {
+            config.segment_bytes = segment_bytes;
+        }
+        if let Some(fsync) = self.fsync {
+            config.fsync = fsync;
+        }
+        Some(config)
+    }
+}
+
+fn env_number(var: &str) -> anyhow::Result<Option<u64>> {
+    match env::var(var) {
+        Ok(n) => Ok(Some(
+            n.parse()
+                .with_context(|| format!("{} is not a number", var))?,
+        )),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Messages per key, each a run starting at the polled offset, and the last
+/// offset each key's log shows at the poll's isolation level: the high
+/// watermark, or for read-committed polls the last offset before a pending
+/// transaction if that is lower.
+#[derive(Debug, Default)]
+struct Polled {
+    msgs: HashMap<String, Vec<(usize, Value)>>,
+    ends: HashMap<String, usize>,
+    /// Timestamps and headers of the messages, in the same order.
+    metadata: HashMap<String, Vec<Metadata>>,
+}
+
+/// One message of a batched send.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Batched {
+    msg: Value,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sub_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Metadata {
+    offset: usize,
+    timestamp: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sub_key: Option<String>,
+}
 
 #[derive(Debug)]
 struct KafkaLog {
     id: AtomicUsize,
-    logs: HashMap<String, LinkedList<Log>>,
-    commit_offsets: HashMap<String, usize>,
+    mode: Mode,
+    poll_batch: usize,
+    retention: Retention,
+    compact: bool,
+    /// The logs, partitions and committed offsets, keyed by [`commit_key`],
+    /// of the keys this node holds a replica of.
+    broker: Mutex<Broker>,
+    /// Decides when a partition leader is gone.
+    detector: Mutex<FailureDetector>,
+    nodes: Vec<String>,
+    /// Groups this node coordinates.
+    groups: Mutex<ConsumerGroups>,
     node: String,
-    lin_store: String,
+    ring: HashRing,
+    /// This node's shard of the key/value metadata peers store with us.
+    shard: Mutex<KvStore>,
     seq_store: String,
     rpc: Mutex<HashMap<usize, oneshot::Sender<Message<Payload>>>>,
+    subscriptions: Mutex<Subscriptions>,
     output: Mutex<std::io::Stdout>,
 }
 
-impl nazgul::KV<usize> for KafkaLog {
+impl nazgul::KV<Value> for KafkaLog {
     fn sync_cas(
         &self,
         key: impl Into<String>,
         store: &String,
-        from: usize,
-        to: usize,
+        from: Value,
+        to: Value,
         put: bool,
     ) -> anyhow::Result<()> {
         let k: String = key.into();
         eprintln!("sync_cas|> {}", k);
+        if *store == self.node {
+            self.apply_local(KvCommand::Cas {
+                key: k.into(),
+                from,
+                to,
+                put,
+            })?;
+            return Ok(());
+        }
         let msg = Message::new(
             self.node.clone(),
             store.to_string(),
@@ -54,14 +272,19 @@ impl nazgul::KV<usize> for KafkaLog {
 
         let res = self.sync_rpc(msg).context("sending rpc for cas")?;
         match res.body.payload {
-            Payload::CasOk {} => Ok(()),
+            Payload::CasOk => Ok(()),
+            Payload::Error { code, text } => Err(RpcError { code, text }.into()),
             _ => anyhow::bail!("unexpected payload for CAS"),
         }
     }
 
-    fn sync_read(&self, key: impl Into<String>, store: &String) -> anyhow::Result<usize> {
+    fn sync_read(&self, key: impl Into<String>, store: &String) -> anyhow::Result<Value> {
         let k: String = key.into();
         eprintln!("sync_read|> {}", k);
+        if *store == self.node {
+            let value = self.apply_local(KvCommand::Read { key: k.into() })?;
+            return Ok(value.unwrap_or_default());
+        }
         let msg = Message::new(
             self.node.clone(),
             store.to_string(),
@@ -74,11 +297,19 @@ impl nazgul::KV<usize> for KafkaLog {
         let res = self.sync_rpc(msg).context("sending rpc")?;
         match res.body.payload {
             Payload::ReadOk { value } => Ok(value),
+            Payload::Error { code, text } => Err(RpcError { code, text }.into()),
             _ => bail!("unexpected return type"),
         }
     }
 
-    fn sync_write(&self, key: impl Into<String>, store: &String, val: usize) -> anyhow::Result<()> {
+    fn sync_write(&self, key: impl Into<String>, store: &String, val: Value) -> anyhow::Result<()> {
+        if *store == self.node {
+            self.apply_local(KvCommand::Write {
+                key: key.into().into(),
+                value: val,
+            })?;
+            return Ok(());
+        }
         let msg = Message::new(
             self.node.clone(),
             store.to_string(),
@@ -94,31 +325,1166 @@ impl nazgul::KV<usize> for KafkaLog {
 
         let res = self.sync_rpc(msg).context("sending rpc for write")?;
         match res.body.payload {
-            Payload::WriteOk {} => Ok(()),
+            Payload::WriteOk => Ok(()),
+            Payload::Error { code, text } => Err(RpcError { code, text }.into()),
             _ => bail!("unexpected payload for write RPC"),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-struct Log {
-    value: usize,
+/// Whether a compare-and-set failed because the value had moved on, as
+/// opposed to an outcome that is unknown.
+fn is_conflict(e: &anyhow::Error) -> bool {
+    matches!(
+        RpcError::code_of(e),
+        Some(PRECONDITION_FAILED | KEY_DOES_NOT_EXIST)
+    )
 }
 
-impl Log {
-    pub fn from(value: usize) -> Self {
-        Self { value }
+/// Where a key's committed offset lives, per consumer group. Commits without
+/// a group share one position per key.
+fn commit_key(group: Option<&str>, key: &str) -> String {
+    match group {
+        Some(group) => format!("commit@{}:{}", group, key),
+        None => format!("commit:{}", key),
     }
 }
 
+/// Where a consumer wants to start reading a key: `"earliest"`, `"latest"`
+/// or a timestamp in milliseconds since the epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+enum OffsetQuery {
+    Position(Position),
+    Timestamp(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Position {
+    /// The first offset still in the log.
+    Earliest,
+    /// The offset the next message will get.
+    Latest,
+}
+
 impl KafkaLog {
+    /// The node owning `key` on the ring: its metadata shard in `Kv` mode,
+    /// or a group's coordinator.
+    fn owner(&self, key: &str) -> &str {
+        self.ring.owner(key).expect("ring contains this node")
+    }
+
+    /// The node leading `key` as far as this node knows. Only replicas
+    /// tracking the key know which epoch it is in; other nodes guess the
+    /// first replica not suspected dead, which is the one a later epoch
+    /// goes to, and are told otherwise if they guessed wrong.
+    fn leader(&self, key: &str) -> String {
+        let broker = self.broker.lock().unwrap();
+        if let Some(p) = broker.partition(key) {
+            return p.leader().to_string();
+        }
+        let replicas = broker.replicas(key);
+        drop(broker);
+        let detector = self.detector.lock().unwrap();
+        replicas
+            .iter()
+            .find(|r| **r == self.node || detector.is_alive(r))
+            .unwrap_or(&replicas[0])
+            .clone()
+    }
+
+    /// Like [`Self::leader`] for requests that write to `key`. A replica
+    /// starts tracking the key first, so it takes the key over should its
+    /// leader be gone.
+    fn write_leader(&self, key: &str) -> String {
+        self.broker.lock().unwrap().track(key, Instant::now());
+        self.leader(key)
+    }
+
+    fn group_by_leader<V>(
+        &self,
+        items: impl IntoIterator<Item = (String, V)>,
+    ) -> HashMap<String, HashMap<String, V>> {
+        let mut groups: HashMap<String, HashMap<String, V>> = HashMap::new();
+        for (key, v) in items {
+            groups.entry(self.leader(&key)).or_default().insert(key, v);
+        }
+        groups
+    }
+
+    /// Requests forwarded by another node are not forwarded again; views of
+    /// who leads differ while an epoch changes hands.
+    fn check_leader(&self, leader: &str, from_peer: bool) -> anyhow::Result<()> {
+        if from_peer && leader != self.node {
+            bail!("{} is not the leader, {} is", self.node, leader);
+        }
+        Ok(())
+    }
+
+    fn apply_local(&self, command: KvCommand) -> Result<Option<Value>, KvError> {
+        self.shard.lock().unwrap().apply(&command)
+    }
+
+    /// Reads an offset stored in a key/value service.
+    fn read_offset(&self, key: String, store: &String) -> anyhow::Result<usize> {
+        let value = self.sync_read(key.clone(), store)?;
+        let offset = value
+            .as_u64()
+            .with_context(|| format!("{} holds {} instead of an offset", key, value))?;
+        Ok(offset as usize)
+    }
+
     fn sync_rpc(&self, msg: Message<Payload>) -> anyhow::Result<Message<Payload>> {
         let (tx, rx) = oneshot::channel::<Message<Payload>>();
 
         // register transmitter
-        self.rpc.lock().unwrap().insert(msg.body.id.unwrap(), tx);
+        let id = msg.body.id.unwrap();
+        self.rpc.lock().unwrap().insert(id, tx);
         msg.send(&self.output).context("sending rpc")?;
-        Ok(rx.recv()?)
+        match rx.recv_timeout(RPC_TIMEOUT) {
+            Ok(res) => Ok(res),
+            Err(_) => {
+                self.rpc.lock().unwrap().remove(&id);
+                bail!("rpc to {} timed out", msg.dst)
+            }
+        }
+    }
+
+    /// Sends `payload` to the node owning the keys involved and returns its
+    /// answer, turning `error` replies into errors.
+    fn forward(&self, owner: &str, payload: Payload) -> anyhow::Result<Payload> {
+        let msg = Message::new(
+            self.node.clone(),
+            owner.to_string(),
+            Body {
+                id: self.id.fetch_add(1, Ordering::SeqCst).into(),
+                in_reply_to: None,
+                payload,
+            },
+        );
+        let res = self.sync_rpc(msg).context("forwarding to owner")?;
+        match res.body.payload {
+            Payload::Error { code, text } => {
+                Err(anyhow::Error::new(RpcError { code, text })
+                    .context(format!("{} answered", owner)))
+            }
+            payload => Ok(payload),
+        }
+    }
+
+    /// Acknowledged once the offset is below the high watermark, i.e. every
+    /// in-sync replica has it. A send repeating a producer's sequence number
+    /// gets the offset of the first one instead of appending again.
+    fn send(&self, key: String, log: Log, from_peer: bool) -> anyhow::Result<usize> {
+        if log.producer.is_some() != log.seq.is_some() {
+            return Err(RpcError {
+                code: MALFORMED_REQUEST,
+                text: "producer and seq must be given together".to_string(),
+            }
+            .into());
+        }
+        if self.mode == Mode::Kv {
+            if log.producer.is_some() {
+                return Err(RpcError {
+                    code: NOT_SUPPORTED,
+                    text: "idempotent sends need owner mode".to_string(),
+                }
+                .into());
+            }
+            return self.send_kv(key, log);
+        }
+        let leader = self.write_leader(&key);
+        self.check_leader(&leader, from_peer)?;
+        if leader != self.node {
+            let send = Payload::Send {
+                key,
+                msg: log.value,
+                headers: log.headers,
+                sub_key: log.sub_key,
+                producer: log.producer,
+                seq: log.seq,
+            };
+            return match self.forward(&leader, send)? {
+                Payload::SendOk { offset } => Ok(offset),
+                _ => bail!("unexpected payload for send"),
+            };
+        }
+        let (epoch, offset) = self
+            .broker
+            .lock()
+            .unwrap()
+            .append(&key, log, Instant::now())?;
+        self.replicate(&key)?;
+        self.await_replication(&key, &epoch, offset)?;
+        Ok(offset)
+    }
+
+    /// Appends `msgs` at consecutive offsets and returns them, acknowledged
+    /// like [`Self::send`]. Only owner mode can keep the offsets together.
+    fn send_batch(
+        &self,
+        key: String,
+        msgs: Vec<Batched>,
+        from_peer: bool,
+    ) -> anyhow::Result<Vec<usize>> {
+        if self.mode == Mode::Kv {
+            return Err(RpcError {
+                code: NOT_SUPPORTED,
+                text: "batched sends need owner mode".to_string(),
+            }
+            .into());
+        }
+        let leader = self.write_leader(&key);
+        self.check_leader(&leader, from_peer)?;
+        if leader != self.node {
+            return match self.forward(&leader, Payload::SendBatch { key, msgs })? {
+                Payload::SendBatchOk { offsets } => Ok(offsets),
+                _ => bail!("unexpected payload for send_batch"),
+            };
+        }
+        let mut broker = self.broker.lock().unwrap();
+        let mut appended = Vec::new();
+        for m in msgs {
+            let log = Log {
+                headers: m.headers,
+                sub_key: m.sub_key,
+                ..Log::new(m.msg)
+            };
+            appended.push(broker.append(&key, log, Instant::now())?);
+        }
+        drop(broker);
+        let Some((epoch, last)) = appended.last().cloned() else {
+            return Ok(Vec::new());
+        };
+        self.replicate(&key)?;
+        self.await_replication(&key, &epoch, last)?;
+        Ok(appended.into_iter().map(|(_, offset)| offset).collect())
+    }
+
+    fn await_replication(&self, key: &str, epoch: &Epoch, offset: usize) -> anyhow::Result<()> {
+        self.await_isr(key, epoch, |b| b.hw(key) >= offset)
+            .with_context(|| format!("replicate {} offset {}", key, offset))
+    }
+
+    /// Waits until `replicated` holds while `key` stays in `epoch`, which
+    /// this node leads.
+    fn await_isr(
+        &self,
+        key: &str,
+        epoch: &Epoch,
+        replicated: impl Fn(&Broker) -> bool,
+    ) -> anyhow::Result<()> {
+        let deadline = Instant::now() + REPLICATION_TIMEOUT;
+        loop {
+            let broker = self.broker.lock().unwrap();
+            let Some(current) = broker.partition(key).map(|p| p.epoch().clone()) else {
+                bail!("{} was deleted", key);
+            };
+            // a newer leader may have cut the entry or missed the commit
+            if current != *epoch {
+                bail!("lost leadership of {}", key);
+            }
+            if replicated(&broker) {
+                return Ok(());
+            }
+            drop(broker);
+            if Instant::now() >= deadline {
+                bail!("timed out waiting for the ISR of {}", key);
+            }
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    /// Sends every follower of `key` the entries it is missing, the ISR, the
+    /// high watermark and the committed offsets.
+    fn replicate(&self, key: &str) -> anyhow::Result<()> {
+        let out = self.broker.lock().unwrap().replicate(key)?;
+        self.send_replicate(out)
+    }
+
+    fn send_replicate(&self, out: Outbox) -> anyhow::Result<()> {
+        let mut detector = self.detector.lock().unwrap();
+        for msg in &out {
+            detector.sent(&msg.dst);
+        }
+        drop(detector);
+        for m in out {
+            let msg = Message::new(
+                m.src,
+                m.dst,
+                Body {
+                    id: None,
+                    in_reply_to: None,
+                    payload: Payload::Replicate(m.body.payload),
+                },
+            );
+            msg.send(&self.output).context("send replicate")?;
+        }
+        Ok(())
+    }
+
+    /// Shrinks ISRs, takes over partitions whose leader is suspected dead,
+    /// and replicates every partition this node leads.
+    fn replicate_tick(&self) -> anyhow::Result<()> {
+        let mut detector = self.detector.lock().unwrap();
+        for event in detector.check() {
+            eprintln!("MEMBERSHIP {:?}", event);
+        }
+        let alive: HashSet<String> = detector
+            .peers()
+            .filter(|p| detector.is_alive(p))
+            .cloned()
+            .collect();
+        drop(detector);
+
+        let out = self
+            .broker
+            .lock()
+            .unwrap()
+            .tick(|n| alive.contains(n), Instant::now())?;
+        self.send_replicate(out)?;
+
+        // keep hearing from peers we do not replicate with
+        let pings = self.detector.lock().unwrap().due_pings();
+        for peer in pings {
+            self.detector.lock().unwrap().sent(&peer);
+            let msg = Message::new(
+                self.node.clone(),
+                peer,
+                Body {
+                    id: None,
+                    in_reply_to: None,
+                    payload: Payload::Heartbeat,
+                },
+            );
+            msg.send(&self.output).context("send heartbeat")?;
+        }
+        Ok(())
+    }
+
+    /// Coordinates a transaction: appends every message, pending, through
+    /// the leaders of their keys, then has the first key's leader decide the
+    /// outcome and marks the other keys with it. Offsets are returned in the
+    /// order of `msgs` once the transaction committed.
+    fn send_txn(&self, msgs: Vec<(String, Value)>) -> anyhow::Result<Vec<usize>> {
+        if self.mode == Mode::Kv {
+            return Err(RpcError {
+                code: NOT_SUPPORTED,
+                text: "transactions need owner mode".to_string(),
+            }
+            .into());
+        }
+        let Some((home, _)) = msgs.first() else {
+            return Ok(Vec::new());
+        };
+        let txn = TxnRef {
+            id: format!(
+                "{}:{}:{}",
+                self.node,
+                now_ms(),
+                self.id.fetch_add(1, Ordering::SeqCst)
+            ),
+            home: home.clone(),
+            outcome: None,
+        };
+
+        let mut by_leader: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, (key, _)) in msgs.iter().enumerate() {
+            by_leader.entry(self.leader(key)).or_default().push(i);
+        }
+        let mut offsets = vec![0; msgs.len()];
+        let mut failed = None;
+        for (leader, indices) in by_leader {
+            let batch: Vec<_> = indices.iter().map(|i| msgs[*i].clone()).collect();
+            let appended = if leader == self.node {
+                self.txn_append(&txn, batch)
+            } else {
+                let append = Payload::TxnAppend {
+                    txn: txn.clone(),
+                    msgs: batch,
+                };
+                match self.forward(&leader, append) {
+                    Ok(Payload::TxnAppendOk { offsets }) => Ok(offsets),
+                    Ok(_) => Err(anyhow::anyhow!("unexpected payload for txn append")),
+                    Err(e) => Err(e),
+                }
+            };
+            match appended {
+                Ok(appended) => {
+                    for (i, offset) in indices.into_iter().zip(appended) {
+                        offsets[i] = offset;
+                    }
+                }
+                Err(e) => {
+                    failed = Some(e);
+                    break;
+                }
+            }
+        }
+
+        let proposed = match failed {
+            None => Outcome::Commit,
+            Some(_) => Outcome::Abort,
+        };
+        let outcome = self
+            .end_txn(&txn.home, &txn, proposed, false)
+            .with_context(|| format!("decide transaction {}", txn.id))?;
+        let keys: BTreeSet<&String> = msgs.iter().map(|(k, _)| k).collect();
+        for key in keys.into_iter().filter(|k| **k != txn.home) {
+            // a key left pending asks the home key once it times out
+            if let Err(e) = self.end_txn(key, &txn, outcome, false) {
+                eprintln!("ending {} on {}: {:#}", txn.id, key, e);
+            }
+        }
+        match (outcome, failed) {
+            (Outcome::Commit, _) => Ok(offsets),
+            (Outcome::Abort, failed) => {
+                let text = match failed {
+                    Some(e) => format!("transaction {} aborted: {:#}", txn.id, e),
+                    None => format!("transaction {} timed out", txn.id),
+                };
+                Err(RpcError { code: ABORT, text }.into())
+            }
+        }
+    }
+
+    /// Appends a transaction's messages to keys this node leads. They stay
+    /// pending until the transaction ends. The coordinator already routed
+    /// them by leader, so they are never forwarded again.
+    fn txn_append(&self, txn: &TxnRef, msgs: Vec<(String, Value)>) -> anyhow::Result<Vec<usize>> {
+        let mut appended = Vec::new();
+        for (key, msg) in msgs {
+            self.check_leader(&self.write_leader(&key), true)?;
+            let mut broker = self.broker.lock().unwrap();
+            if broker.logs().ended(&key, &txn.id).is_some() {
+                bail!("transaction {} already ended on {}", txn.id, key);
+            }
+            let log = Log {
+                txn: Some(txn.clone()),
+                ..Log::new(msg)
+            };
+            let (epoch, offset) = broker.append(&key, log, Instant::now())?;
+            drop(broker);
+            appended.push((key, epoch, offset));
+        }
+        let keys: BTreeSet<&String> = appended.iter().map(|(k, _, _)| k).collect();
+        for key in keys {
+            self.replicate(key)?;
+        }
+        let mut offsets = Vec::new();
+        for (key, epoch, offset) in appended {
+            self.await_replication(&key, &epoch, offset)?;
+            offsets.push(offset);
+        }
+        Ok(offsets)
+    }
+
+    /// Ends `txn` on `key` with `outcome` unless it already ended there, and
+    /// returns how it ended. On the transaction's home key this decides the
+    /// outcome for every key.
+    fn end_txn(
+        &self,
+        key: &str,
+        txn: &TxnRef,
+        outcome: Outcome,
+        from_peer: bool,
+    ) -> anyhow::Result<Outcome> {
+        let leader = self.write_leader(key);
+        self.check_leader(&leader, from_peer)?;
+        if leader != self.node {
+            let end = Payload::EndTxn {
+                key: key.to_string(),
+                txn: txn.clone(),
+                outcome,
+            };
+            return match self.forward(&leader, end)? {
+                Payload::EndTxnOk { outcome } => Ok(outcome),
+                _ => bail!("unexpected payload for end txn"),
+            };
+        }
+        let mut broker = self.broker.lock().unwrap();
+        let (epoch, outcome, offset) = match broker.logs().ended(key, &txn.id) {
+            Some((ended, marker)) => {
+                let Some(p) = broker.partition(key).filter(|p| p.is_leader()) else {
+                    bail!("lost leadership of {}", key);
+                };
+                (p.epoch().clone(), ended, marker)
+            }
+            None => {
+                let marker = Log {
+                    txn: Some(TxnRef {
+                        outcome: Some(outcome),
+                        ..txn.clone()
+                    }),
+                    ..Log::new(Value::Null)
+                };
+                let (epoch, offset) = broker.append(key, marker, Instant::now())?;
+                (epoch, outcome, offset)
+            }
+        };
+        drop(broker);
+        self.replicate(key)?;
+        // the marker only counts once a new leader cannot lose it
+        self.await_replication(key, &epoch, offset)?;
+        Ok(outcome)
+    }
+
+    /// Ends transactions left pending on keys this node leads for longer
+    /// than the timeout, e.g. because their coordinator crashed. The home
+    /// key aborts them unless it already decided otherwise.
+    fn txn_tick(&self, now: u64) {
+        let before = now.saturating_sub(TXN_TIMEOUT.as_millis() as u64);
+        let pending = self.broker.lock().unwrap().logs().pending_since(before);
+        for (key, txn) in pending {
+            let leads = self
+                .broker
+                .lock()
+                .unwrap()
+                .partition(&key)
+                .is_some_and(|p| p.is_leader());
+            // ending it takes the lock again
+            if !leads {
+                continue;
+            }
+            let ended = self
+                .end_txn(&txn.home, &txn, Outcome::Abort, false)
+                .and_then(|outcome| self.end_txn(&key, &txn, outcome, false));
+            if let Err(e) = ended {
+                eprintln!("ending {} on {}: {:#}", txn.id, key, e);
+            }
+        }
+    }
+
+    /// Every key some live node holds a replica of.
+    fn list_keys(&self, from_peer: bool) -> anyhow::Result<Vec<String>> {
+        if self.mode == Mode::Kv {
+            return Err(RpcError {
+                code: NOT_SUPPORTED,
+                text: "seq-kv cannot list keys".to_string(),
+            }
+            .into());
+        }
+        let mut keys: BTreeSet<String> = self
+            .broker
+            .lock()
+            .unwrap()
+            .logs()
+            .keys()
+            .into_iter()
+            .collect();
+        if !from_peer {
+            let detector = self.detector.lock().unwrap();
+            let peers: Vec<String> = detector
+                .peers()
+                .filter(|p| detector.is_alive(p))
+                .cloned()
+                .collect();
+            drop(detector);
+            for peer in peers {
+                match self.forward(&peer, Payload::ListKeys) {
+                    Ok(Payload::ListKeysOk { keys: theirs }) => keys.extend(theirs),
+                    Ok(_) => bail!("unexpected payload for list_keys"),
+                    // its keys are on other replicas, or gone with it
+                    Err(e) => eprintln!("listing keys of {}: {:#}", peer, e),
+                }
+            }
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    /// Runs an admin request on the leader of its key. Logs are only kept
+    /// per key in owner mode.
+    fn admin(&self, request: Payload, from_peer: bool) -> anyhow::Result<Payload> {
+        let (Payload::DescribeKey { key }
+        | Payload::DeleteKey { key }
+        | Payload::TruncateKey { key, .. }) = &request
+        else {
+            bail!("not an admin request: {:?}", request);
+        };
+        if self.mode == Mode::Kv {
+            return Err(RpcError {
+                code: NOT_SUPPORTED,
+                text: "admin requests need owner mode".to_string(),
+            }
+            .into());
+        }
+        let leader = match request {
+            Payload::DescribeKey { .. } => self.leader(key),
+            _ => self.write_leader(key),
+        };
+        self.check_leader(&leader, from_peer)?;
+        if leader != self.node {
+            return self.forward(&leader, request);
+        }
+        match request {
+            Payload::DescribeKey { key } => self.describe_key(key, leader),
+            Payload::DeleteKey { key } => {
+                self.drop_from_replicas(&key, None)?;
+                Ok(Payload::DeleteKeyOk)
+            }
+            Payload::TruncateKey { key, before } => {
+                // entries above the high watermark are not the clients' yet
+                let hw = self.broker.lock().unwrap().hw(&key);
+                let before = before.min(hw + 1);
+                self.drop_from_replicas(&key, Some(before))?;
+                let start = self.broker.lock().unwrap().logs().start(&key)?;
+                Ok(Payload::TruncateKeyOk { start })
+            }
+            _ => unreachable!("matched above"),
+        }
+    }
+
+    fn describe_key(&self, key: String, owner: String) -> anyhow::Result<Payload> {
+        let broker = self.broker.lock().unwrap();
+        let end = broker.hw(&key);
+        let logs = broker.logs();
+        let commits = broker.committed(&key);
+        if logs.end(&key) == 0 && commits.is_none() {
+            return Err(RpcError {
+                code: KEY_DOES_NOT_EXIST,
+                text: format!("no log for {}", key),
+            }
+            .into());
+        }
+        let start = logs.start(&key)?.min(end + 1);
+        let (mut committed, mut groups) = (None, HashMap::new());
+        let suffix = format!(":{}", key);
+        for (commit_key, offset) in commits.into_iter().flatten() {
+            match commit_key.strip_prefix("commit@") {
+                Some(group) => {
+                    let group = group.strip_suffix(&suffix).unwrap_or(group);
+                    groups.insert(group.to_string(), *offset);
+                }
+                None => committed = Some(*offset),
+            }
+        }
+        Ok(Payload::DescribeKeyOk {
+            start,
+            end,
+            committed,
+            groups,
+            owner,
+        })
+    }
+
+    /// Drops `key`'s entries before `before`, or all of the key, on every
+    /// replica and then here. Fails unless every other member of the ISR
+    /// confirms, before touching this replica, so the request can be
+    /// retried. Replicas out of the ISR are asked too but may miss it.
+    fn drop_from_replicas(&self, key: &str, before: Option<usize>) -> anyhow::Result<()> {
+        let broker = self.broker.lock().unwrap();
+        let replicas = broker.replicas(key);
+        let isr = broker.partition(key).map(|p| p.isr().clone());
+        drop(broker);
+        let Some(isr) = isr else {
+            bail!("{} is not tracking {}", self.node, key);
+        };
+        for replica in replicas.iter().filter(|r| **r != self.node) {
+            let request = Payload::DropReplica {
+                key: key.to_string(),
+                before,
+            };
+            let e = match self.forward(replica, request) {
+                Ok(Payload::DropReplicaOk) => continue,
+                Ok(other) => anyhow::anyhow!("unexpected payload {:?}", other),
+                Err(e) => e,
+            };
+            let e = e.context(format!("dropping {} on {}", key, replica));
+            if isr.contains(replica) {
+                return Err(e);
+            }
+            eprintln!("{:#}", e);
+        }
+        self.drop_replica(key, before)
+    }
+
+    fn drop_replica(&self, key: &str, before: Option<usize>) -> anyhow::Result<()> {
+        self.broker.lock().unwrap().drop_entries(key, before)
+    }
+
+    /// Polls for every subscriber that is due and pushes what it finds,
+    /// one credit per message. Each subscriber is polled on a thread of its
+    /// own, and one still waiting on a slow poll is skipped until it is done.
+    fn push_tick(&self) {
+        let due = self.subscriptions.lock().unwrap().due(Instant::now());
+        thread::scope(|s| {
+            for due in &due {
+                s.spawn(move || self.push(due));
+            }
+        });
+    }
+
+    fn push(&self, due: &Due) {
+        let mut msgs = match self.poll(due.offsets.clone(), due.isolation, false) {
+            Ok(polled) => polled.msgs,
+            Err(e) => {
+                eprintln!("polling for {}: {:#}", due.client, e);
+                self.subscriptions.lock().unwrap().failed(due);
+                return;
+            }
+        };
+        let pushed = self
+            .subscriptions
+            .lock()
+            .unwrap()
+            .pushed(due, &mut msgs, Instant::now());
+        let Some(credits) = pushed else {
+            return;
+        };
+        let msg = Message::new(
+            self.node.clone(),
+            due.client.clone(),
+            Body {
+                id: Some(self.id.fetch_add(1, Ordering::SeqCst)),
+                in_reply_to: None,
+                payload: Payload::Push { msgs, credits },
+            },
+        );
+        // a lost push is sent again once the client fails to confirm it
+        if let Err(e) = msg.send(&self.output) {
+            eprintln!("pushing to {}: {:#}", due.client, e);
+        }
+    }
+
+    /// seq-kv holds each whole entry under `{key}:{offset}`. Offsets are
+    /// allocated by moving `{key}:latest` on its owner forward one at a time,
+    /// and an entry is only created where none exists yet. A send that cannot
+    /// tell whether its allocation landed therefore claims the offset like
+    /// any other: either it gets the entry in, or whoever did owns the offset.
+    fn send_kv(&self, key: String, mut log: Log) -> anyhow::Result<usize> {
+        let latest_key = format!("{}:latest", key);
+        let owner = self.owner(&latest_key).to_string();
+        let mut offset = self.latest_kv(&latest_key, &owner)? + 1;
+        loop {
+            let res = self
+                .sync_cas(
+                    latest_key.clone(),
+                    &owner,
+                    (offset - 1).into(),
+                    offset.into(),
+                    true,
+                )
+                .context("cas offset");
+            match res {
+                Ok(()) => {}
+                Err(e) if is_conflict(&e) => {
+                    offset = self.latest_kv(&latest_key, &owner)? + 1;
+                    continue;
+                }
+                // a timed out cas may have landed or not; only an offset
+                // already allocated can be claimed
+                Err(e) => {
+                    eprintln!("allocating {} offset {}: {:#}", key, offset, e);
+                    if self.latest_kv(&latest_key, &owner)? < offset {
+                        continue;
+                    }
+                }
+            }
+            log.offset = offset;
+            log.timestamp = now_ms();
+            if self.claim_kv(&format!("{}:{}", key, offset), &log)? {
+                break;
+            }
+            offset = self.latest_kv(&latest_key, &owner)?.max(offset) + 1;
+        }
+
+        self.sync_write(latest_key, &self.seq_store, offset.into())
+            .context("write latest key with offset")?;
+        Ok(offset)
+    }
+
+    /// The last offset allocated for a key in `Kv` mode, 0 before the first.
+    fn latest_kv(&self, latest_key: &str, owner: &String) -> anyhow::Result<usize> {
+        match self.read_offset(latest_key.to_string(), owner) {
+            Ok(latest) => Ok(latest),
+            Err(e) if RpcError::is_missing(&e) => Ok(0),
+            Err(e) => Err(e.context("read offset")),
+        }
+    }
+
+    /// Creates `entry_key` holding `log` unless another entry got there
+    /// first, and returns whether `log` is what it holds.
+    fn claim_kv(&self, entry_key: &str, log: &Log) -> anyhow::Result<bool> {
+        let entry = serde_json::to_value(log).context("serialize log entry")?;
+        loop {
+            let res = self.sync_cas(
+                entry_key.to_string(),
+                &self.seq_store,
+                Value::Null,
+                entry.clone(),
+                true,
+            );
+            match res {
+                Ok(()) => return Ok(true),
+                Err(e) if is_conflict(&e) => {}
+                // it may have been created anyway, so look
+                Err(e) => eprintln!("writing {}: {:#}", entry_key, e),
+            }
+            match self.sync_read(entry_key, &self.seq_store) {
+                Ok(held) => return Ok(held == entry),
+                // seq-kv reads may lag behind the cas that failed
+                Err(e) if RpcError::is_missing(&e) => {}
+                Err(e) => return Err(e.context("read msg_key offset")),
+            }
+        }
+    }
+
+    /// Only offsets up to the high watermark are returned.
+    fn poll(
+        &self,
+        offsets: HashMap<String, usize>,
+        isolation: Isolation,
+        from_peer: bool,
+    ) -> anyhow::Result<Polled> {
+        if self.mode == Mode::Kv {
+            return self.poll_kv(offsets);
+        }
+        let mut resp = Polled::default();
+        for (leader, offsets) in self.group_by_leader(offsets) {
+            self.check_leader(&leader, from_peer)?;
+            if leader != self.node {
+                let poll = Payload::Poll {
+                    offsets,
+                    group: None,
+                    isolation,
+                    metadata: true,
+                };
+                match self.forward(&leader, poll)? {
+                    Payload::PollOk {
+                        msgs,
+                        ends,
+                        metadata,
+                        ..
+                    } => {
+                        resp.msgs.extend(msgs);
+                        resp.ends.extend(ends);
+                        resp.metadata.extend(metadata);
+                    }
+                    _ => bail!("unexpected payload for poll"),
+                }
+                continue;
+            }
+            let broker = self.broker.lock().unwrap();
+            for (key, from) in offsets {
+                let (entries, end) = broker.poll(&key, from, isolation, self.poll_batch)?;
+                let msgs = entries
+                    .iter()
+                    .map(|l| (l.offset, l.value.clone()))
+                    .collect();
+                let metadata = entries
+                    .into_iter()
+                    .map(|l| Metadata {
+                        offset: l.offset,
+                        timestamp: l.timestamp,
+                        headers: l.headers,
+                        sub_key: l.sub_key,
+                    })
+                    .collect();
+                resp.ends.insert(key.clone(), end);
+                resp.msgs.insert(key.clone(), msgs);
+                resp.metadata.insert(key, metadata);
+            }
+        }
+        Ok(resp)
+    }
+
+    /// seq-kv has no multi-key reads, so this reads the published end of the
+    /// log once and then offset by offset, stopping at the first one that is
+    /// allocated but not written yet.
+    fn poll_kv(&self, offsets: HashMap<String, usize>) -> anyhow::Result<Polled> {
+        let mut resp = Polled::default();
+
+        for (k, v) in offsets {
+            let end = match self.read_offset(format!("{}:latest", k), &self.seq_store) {
+                Ok(end) => end,
+                Err(e) if RpcError::is_missing(&e) => 0,
+                Err(e) => return Err(e.context("read end of log")),
+            };
+            let (mut m, mut metadata) = (Vec::new(), Vec::new());
+            for i in v..=end.min(v + self.poll_batch - 1) {
+                match self.sync_read(format!("{}:{}", k, i), &self.seq_store) {
+                    Ok(entry) => {
+                        let log: Log =
+                            serde_json::from_value(entry).context("corrupt log entry")?;
+                        metadata.push(Metadata {
+                            offset: i,
+                            timestamp: log.timestamp,
+                            headers: log.headers,
+                            sub_key: log.sub_key,
+                        });
+                        m.push((i, log.value));
+                    }
+                    Err(e) if RpcError::is_missing(&e) => break,
+                    Err(e) => return Err(e.context("read msg_key offset")),
+                }
+            }
+            resp.msgs.insert(k.clone(), m);
+            resp.metadata.insert(k.clone(), metadata);
+            resp.ends.insert(k, end);
+        }
+        Ok(resp)
+    }
+
+    /// Resolves each query to the offset a poll should start from. Offsets
+    /// only count up to the high watermark, and a timestamp after every
+    /// message resolves to the latest offset.
+    fn list_offsets(
+        &self,
+        queries: HashMap<String, OffsetQuery>,
+        from_peer: bool,
+    ) -> anyhow::Result<HashMap<String, usize>> {
+        if self.mode == Mode::Kv {
+            return queries
+                .into_iter()
+                .map(|(key, query)| {
+                    let offset = self
+                        .list_offset_kv(&key, query)
+                        .with_context(|| format!("list offset for {}", key))?;
+                    Ok((key, offset))
+                })
+                .collect();
+        }
+        let mut resp = HashMap::new();
+        for (leader, queries) in self.group_by_leader(queries) {
+            self.check_leader(&leader, from_peer)?;
+            if leader != self.node {
+                match self.forward(&leader, Payload::ListOffsets { offsets: queries })? {
+                    Payload::ListOffsetsOk { offsets } => resp.extend(offsets),
+                    _ => bail!("unexpected payload for list_offsets"),
+                }
+                continue;
+            }
+            for (key, query) in queries {
+                let broker = self.broker.lock().unwrap();
+                let (hw, logs) = (broker.hw(&key), broker.logs());
+                let offset = match query {
+                    OffsetQuery::Position(Position::Latest) => None,
+                    OffsetQuery::Position(Position::Earliest) => {
+                        logs.read(&key, 0, 1)?.first().map(|l| l.offset)
+                    }
+                    OffsetQuery::Timestamp(t) => logs.offset_for_time(&key, t, hw)?,
+                };
+                resp.insert(key, offset.filter(|o| *o <= hw).unwrap_or(hw + 1));
+            }
+        }
+        Ok(resp)
+    }
+
+    /// seq-kv never drops messages, so the earliest offset is always 1.
+    /// Timestamps are looked up by binary search, which assumes they rise
+    /// with the offsets. Each node stamps its own sends, so they only do up
+    /// to the skew between the nodes' clocks, and for a timestamp within that
+    /// skew of an entry the offset found may be a few entries off either way.
+    /// An offset allocated but not written yet counts as later than any
+    /// timestamp.
+    fn list_offset_kv(&self, key: &str, query: OffsetQuery) -> anyhow::Result<usize> {
+        let end = match self.read_offset(format!("{}:latest", key), &self.seq_store) {
+            Ok(end) => end,
+            Err(e) if RpcError::is_missing(&e) => 0,
+            Err(e) => return Err(e.context("read end of log")),
+        };
+        let timestamp = match query {
+            OffsetQuery::Position(Position::Earliest) => return Ok(1),
+            OffsetQuery::Position(Position::Latest) => return Ok(end + 1),
+            OffsetQuery::Timestamp(t) => t,
+        };
+        let (mut lo, mut hi) = (1, end + 1);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let before = match self.sync_read(format!("{}:{}", key, mid), &self.seq_store) {
+                Ok(entry) => {
+                    let log: Log = serde_json::from_value(entry).context("corrupt log entry")?;
+                    log.timestamp < timestamp
+                }
+                Err(e) if RpcError::is_missing(&e) => false,
+                Err(e) => return Err(e.context("read msg_key offset")),
+            };
+            if before {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        Ok(lo)
+    }
+
+    /// Committed offsets only move forward: a stale commit arriving late is
+    /// acknowledged but leaves the newer offset in place.
+    fn commit_offsets(
+        &self,
+        offsets: HashMap<String, usize>,
+        group: Option<String>,
+        from_peer: bool,
+    ) -> anyhow::Result<()> {
+        if self.mode == Mode::Kv {
+            for (key, offset) in offsets {
+                self.commit_kv(&commit_key(group.as_deref(), &key), offset)
+                    .with_context(|| format!("commit offset for {}", key))?;
+            }
+            return Ok(());
+        }
+        for key in offsets.keys() {
+            self.broker.lock().unwrap().track(key, Instant::now());
+        }
+        for (leader, offsets) in self.group_by_leader(offsets) {
+            self.check_leader(&leader, from_peer)?;
+            if leader != self.node {
+                let group = group.clone();
+                self.forward(&leader, Payload::CommitOffsets { offsets, group })?;
+                continue;
+            }
+            let mut versions = Vec::new();
+            for (key, offset) in offsets {
+                let commit_key = commit_key(group.as_deref(), &key);
+                let mut broker = self.broker.lock().unwrap();
+                let (epoch, version) = broker.commit(&key, commit_key, offset, Instant::now())?;
+                versions.push((key, epoch, version));
+            }
+            // acknowledged only once a follower taking over has the commit
+            for (key, epoch, version) in versions {
+                self.replicate(&key)?;
+                self.await_isr(&key, &epoch, |b| b.commit_replicated(&key, version))
+                    .with_context(|| format!("replicate committed offset of {}", key))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn commit_kv(&self, commit_key: &str, offset: usize) -> anyhow::Result<()> {
+        let commit_key = commit_key.to_string();
+        let owner = self.owner(&commit_key).to_string();
+        loop {
+            let current = match self.read_offset(commit_key.clone(), &owner) {
+                Ok(current) => Some(current),
+                Err(e) if RpcError::is_missing(&e) => None,
+                Err(e) => return Err(e),
+            };
+            if current.is_some_and(|c| c >= offset) {
+                return Ok(());
+            }
+            let res = self.sync_cas(
+                commit_key.clone(),
+                &owner,
+                current.unwrap_or_default().into(),
+                offset.into(),
+                current.is_none(),
+            );
+            match res {
+                Ok(()) => return Ok(()),
+                // someone else committed in between; compare against theirs
+                Err(e) if is_conflict(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Keys without a committed offset are left out of the result.
+    fn list_committed_offsets(
+        &self,
+        keys: Vec<String>,
+        group: Option<String>,
+        from_peer: bool,
+    ) -> anyhow::Result<HashMap<String, usize>> {
+        let mut resp = HashMap::new();
+        if self.mode == Mode::Kv {
+            for key in keys {
+                let commit_key = commit_key(group.as_deref(), &key);
+                let owner = self.owner(&commit_key).to_string();
+                match self.read_offset(commit_key, &owner) {
+                    Ok(offset) => {
+                        resp.insert(key, offset);
+                    }
+                    Err(e) if RpcError::is_missing(&e) => {}
+                    Err(e) => return Err(e.context(format!("read committed offset for {}", key))),
+                }
+            }
+            return Ok(resp);
+        }
+        for (leader, keys) in self.group_by_leader(keys.into_iter().map(|k| (k, ()))) {
+            self.check_leader(&leader, from_peer)?;
+            let keys: Vec<String> = keys.into_keys().collect();
+            if leader != self.node {
+                let group = group.clone();
+                match self.forward(&leader, Payload::ListCommittedOffsets { keys, group })? {
+                    Payload::ListCommittedOffsetsOk { offsets } => resp.extend(offsets),
+                    _ => bail!("unexpected payload for list_committed_offsets"),
+                }
+                continue;
+            }
+            let broker = self.broker.lock().unwrap();
+            for key in keys {
+                let commit_key = commit_key(group.as_deref(), &key);
+                if let Some(offset) = broker.committed(&key).and_then(|c| c.get(&commit_key)) {
+                    resp.insert(key, *offset);
+                }
+            }
+        }
+        Ok(resp)
+    }
+
+    /// Polls on behalf of `consumer`. In a group, only the keys assigned to
+    /// the consumer are read, and polling keeps its membership alive.
+    fn poll_as(
+        &self,
+        consumer: &str,
+        mut offsets: HashMap<String, usize>,
+        group: Option<String>,
+        isolation: Isolation,
+        from_peer: bool,
+    ) -> anyhow::Result<(Polled, Option<Assignment>)> {
+        let Some(group) = group else {
+            return Ok((self.poll(offsets, isolation, from_peer)?, None));
+        };
+        let heartbeat = Payload::GroupHeartbeat {
+            group,
+            consumer: consumer.to_string(),
+            keys: offsets.keys().cloned().collect(),
+        };
+        let Payload::GroupHeartbeatOk { assignment } = self.coordinate(heartbeat)? else {
+            bail!("unexpected payload for group heartbeat");
+        };
+        offsets.retain(|key, _| assignment.keys.contains(key));
+        Ok((self.poll(offsets, isolation, from_peer)?, Some(assignment)))
+    }
+
+    /// Runs a group request on the group's coordinator, the node owning
+    /// `group:{id}` on the ring.
+    fn coordinate(&self, request: Payload) -> anyhow::Result<Payload> {
+        let (Payload::JoinGroup { group, .. }
+        | Payload::LeaveGroup { group, .. }
+        | Payload::GroupHeartbeat { group, .. }) = &request
+        else {
+            bail!("not a group request: {:?}", request);
+        };
+        let coordinator = self.owner(&format!("group:{}", group)).to_string();
+        if coordinator != self.node {
+            return self.forward(&coordinator, request);
+        }
+
+        let mut groups = self.groups.lock().unwrap();
+        let now = Instant::now();
+        Ok(match request {
+            Payload::JoinGroup {
+                group,
+                consumer,
+                keys,
+            } => Payload::JoinGroupOk {
+                assignment: groups.join(&group, &consumer.context("no consumer")?, keys, now),
+            },
+            Payload::LeaveGroup { group, consumer } => {
+                groups.leave(&group, &consumer.context("no consumer")?, now);
+                Payload::LeaveGroupOk
+            }
+            Payload::GroupHeartbeat {
+                group,
+                consumer,
+                keys,
+            } => Payload::GroupHeartbeatOk {
+                assignment: groups.heartbeat(&group, &consumer, keys, now),
+            },
+            _ => unreachable!(),
+        })
     }
 }
 
@@ -128,38 +1494,176 @@ impl KafkaLog {
 enum Payload {
     Send {
         key: String,
-        msg: usize,
+        msg: Value,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        headers: BTreeMap<String, Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sub_key: Option<String>,
+        /// Idempotent producers number their sends per key.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        producer: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
     },
     SendOk {
         offset: usize,
     },
+    /// Appends `msgs` to `key` at consecutive offsets, in owner mode.
+    SendBatch {
+        key: String,
+        msgs: Vec<Batched>,
+    },
+    SendBatchOk {
+        offsets: Vec<usize>,
+    },
     Poll {
         offsets: HashMap<String, usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+        #[serde(default)]
+        isolation: Isolation,
+        /// Whether to return the timestamps and headers of the messages.
+        #[serde(default)]
+        metadata: bool,
     },
     PollOk {
-        msgs: HashMap<String, Vec<[usize; 2]>>,
+        msgs: HashMap<String, Vec<(usize, Value)>>,
+        #[serde(default)]
+        ends: HashMap<String, usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        assignment: Option<Assignment>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        metadata: HashMap<String, Vec<Metadata>>,
+    },
+    /// Appends each `[key, msg]` pair; all of them commit or none do.
+    SendTxn {
+        msgs: Vec<(String, Value)>,
+    },
+    SendTxnOk {
+        offsets: Vec<usize>,
     },
     CommitOffsets {
         offsets: HashMap<String, usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
     },
     CommitOffsetsOk,
     ListCommittedOffsets {
         keys: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
     },
     ListCommittedOffsetsOk {
         offsets: HashMap<String, usize>,
     },
+    ListOffsets {
+        offsets: HashMap<String, OffsetQuery>,
+    },
+    ListOffsetsOk {
+        offsets: HashMap<String, usize>,
+    },
+
+    // subscriptions: the node pushes messages from `offsets` on to the
+    // client, as many as it has credits for, and pushes them again unless
+    // the client confirms it consumed them
+    Subscribe {
+        offsets: HashMap<String, usize>,
+        credits: usize,
+        #[serde(default)]
+        isolation: Isolation,
+    },
+    SubscribeOk,
+    /// Grants more credits to a subscription and confirms the last offset
+    /// consumed of each key in `offsets`.
+    Credit {
+        credits: usize,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        offsets: HashMap<String, usize>,
+    },
+    CreditOk {
+        credits: usize,
+    },
+    /// Drops `keys` from a subscription, or all of it without them.
+    Unsubscribe {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keys: Option<Vec<String>>,
+    },
+    UnsubscribeOk,
+    /// Sent by the node; `credits` are the ones left afterwards.
+    Push {
+        msgs: HashMap<String, Vec<(usize, Value)>>,
+        credits: usize,
+    },
+    PushTick,
+
+    // administration, on the leader of the key
+    ListKeys,
+    ListKeysOk {
+        keys: Vec<String>,
+    },
+    DescribeKey {
+        key: String,
+    },
+    /// `end` is the high watermark; `committed` is the offset committed
+    /// without a group and `groups` those of each group.
+    DescribeKeyOk {
+        start: usize,
+        end: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        committed: Option<usize>,
+        groups: HashMap<String, usize>,
+        owner: String,
+    },
+    /// Drops every entry and committed offset of `key`. Later sends go on
+    /// from the offset after its old end.
+    DeleteKey {
+        key: String,
+    },
+    DeleteKeyOk,
+    /// Drops the entries before `before`.
+    TruncateKey {
+        key: String,
+        before: usize,
+    },
+    TruncateKeyOk {
+        start: usize,
+    },
+
+    // consumer groups; `consumer` defaults to the client sending the request
+    JoinGroup {
+        group: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        consumer: Option<String>,
+        keys: Vec<String>,
+    },
+    JoinGroupOk {
+        assignment: Assignment,
+    },
+    LeaveGroup {
+        group: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        consumer: Option<String>,
+    },
+    LeaveGroupOk,
+    GroupHeartbeat {
+        group: String,
+        consumer: String,
+        keys: Vec<String>,
+    },
+    GroupHeartbeatOk {
+        assignment: Assignment,
+    },
 
     // store
     Read {
         key: String,
     },
     ReadOk {
-        value: usize,
+        value: Value,
     },
     Write {
         key: String,
-        value: usize,
+        value: Value,
     },
     Error {
         code: usize,
@@ -168,162 +1672,482 @@ enum Payload {
     WriteOk,
     Cas {
         key: String,
-        from: usize,
-        to: usize,
-        #[serde(rename = "create_if_not_exists")]
+        from: Value,
+        to: Value,
+        #[serde(default, rename = "create_if_not_exists")]
         put: bool,
     },
     CasOk,
+
+    CleanTick,
+
+    // replication between the replicas of a key
+    Replicate(Replicate),
+    ReplicateOk(ReplicateOk),
+    ReplicateTick,
+    Heartbeat,
+    /// From a leader: drop the entries before `before`, or the whole key.
+    DropReplica {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        before: Option<usize>,
+    },
+    DropReplicaOk,
+
+    // transactions, between the coordinator and the leaders of their keys
+    TxnAppend {
+        txn: TxnRef,
+        msgs: Vec<(String, Value)>,
+    },
+    TxnAppendOk {
+        offsets: Vec<usize>,
+    },
+    EndTxn {
+        key: String,
+        txn: TxnRef,
+        outcome: Outcome,
+    },
+    EndTxnOk {
+        outcome: Outcome,
+    },
+    TxnTick,
 }
 
-impl Node<(), Payload> for KafkaLog {
+impl Payload {
+    fn error(e: KvError) -> Self {
+        Payload::Error {
+            code: e.code(),
+            text: e.to_string(),
+        }
+    }
+
+    /// Errors without a Maelstrom code of their own are reported as
+    /// temporarily unavailable.
+    fn unavailable(e: anyhow::Error) -> Self {
+        Payload::Error {
+            code: RpcError::code_of(&e).unwrap_or(TEMPORARILY_UNAVAILABLE),
+            text: format!("{:#}", e),
+        }
+    }
+}
+
+impl Node<Config, Payload> for KafkaLog {
     fn from_init(
-        _state: (),
+        config: Config,
         init: nazgul::Init,
-        _tx: std::sync::mpsc::Sender<nazgul::Message<Payload>>,
+        tx: std::sync::mpsc::Sender<nazgul::Message<Payload>>,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
+        let logs = match config.storage(&init.node_id) {
+            Some(storage) => {
+                let dir = storage.dir.clone();
+                let storage = Storage::open(storage)
+                    .with_context(|| format!("open log storage in {}", dir.display()))?;
+                LogStore::on_disk(storage).context("read producer sequences")?
+            }
+            None => LogStore::in_memory(),
+        };
+        if config.mode == Mode::Owner && config.cleans() {
+            let tx = tx.clone();
+            spawn_ticker(init.node_id.clone(), CLEAN_INTERVAL, Payload::CleanTick, tx);
+        }
+        if config.mode == Mode::Owner {
+            let tx = tx.clone();
+            spawn_ticker(
+                init.node_id.clone(),
+                TXN_CHECK_INTERVAL,
+                Payload::TxnTick,
+                tx,
+            );
+        }
+        if config.mode == Mode::Owner && config.replication_factor > 1 {
+            let tick = Payload::ReplicateTick;
+            spawn_ticker(init.node_id.clone(), REPLICATE_INTERVAL, tick, tx.clone());
+        }
+        spawn_ticker(init.node_id.clone(), PUSH_INTERVAL, Payload::PushTick, tx);
+        let mut detector = FailureDetector::new(REPLICATE_INTERVAL, DEFAULT_THRESHOLD);
+        detector.set_peers(
+            init.node_ids
+                .iter()
+                .filter(|n| **n != init.node_id)
+                .cloned(),
+        );
         Ok(Self {
             id: AtomicUsize::new(1),
-            logs: HashMap::new(),
-            commit_offsets: HashMap::new(),
+            mode: config.mode,
+            poll_batch: config.poll_batch,
+            retention: config.retention,
+            compact: config.compact,
+            broker: Mutex::new(Broker::new(
+                init.node_id.clone(),
+                &init.node_ids,
+                config.replication_factor,
+                config.min_insync,
+                logs,
+                Instant::now(),
+            )),
+            detector: Mutex::new(detector),
+            nodes: init.node_ids.clone(),
+            groups: Mutex::new(ConsumerGroups::default()),
             node: init.node_id,
-            lin_store: "lin-kv".to_string(),
+            ring: HashRing::new(&init.node_ids),
+            shard: Mutex::new(KvStore::default()),
             seq_store: "seq-kv".to_string(),
             rpc: Mutex::new(HashMap::new()),
+            subscriptions: Mutex::new(Subscriptions::default()),
             output: Mutex::new(std::io::stdout()),
         })
     }
 
     fn step(&self, input: nazgul::Message<Payload>) -> anyhow::Result<()> {
-        if input.body.in_reply_to.is_some() {
-            let tx = self
-                .rpc
-                .lock()
-                .unwrap()
-                .remove(&input.body.in_reply_to.unwrap())
-                .unwrap();
+        // every message from a peer doubles as a heartbeat
+        if let Some(event) = self.detector.lock().unwrap().heartbeat(&input.src) {
+            eprintln!("MEMBERSHIP {:?}", event);
+        }
+        if let Some(in_reply_to) = input.body.in_reply_to {
+            let Some(tx) = self.rpc.lock().unwrap().remove(&in_reply_to) else {
+                eprintln!("reply to unknown rpc {}: {:?}", in_reply_to, input);
+                return Ok(());
+            };
             if let Err(e) = tx.send(input).context("sending rpc") {
-                bail!("channel closed: {}", e.to_string());
+                bail!("channel closed: {}", e);
             }
             return Ok(());
         }
 
         let i = input.clone();
+        let from_peer = self.nodes.contains(&input.src);
         let mut reply = input.into_reply(Some(&self.id));
         match reply.body.payload {
-            Payload::Send { key, msg } => {
-                let latest_key = format!("{}:latest", key);
-                let offset = self
-                    .sync_read(latest_key.clone(), &self.lin_store)
-                    .context("read offset");
-                let mut offset = match offset {
-                    Ok(o) => o,
-                    Err(_) => 1,
+            Payload::Send {
+                key,
+                msg,
+                headers,
+                sub_key,
+                producer,
+                seq,
+            } => {
+                let log = Log {
+                    headers,
+                    sub_key,
+                    producer,
+                    seq,
+                    ..Log::new(msg)
+                };
+                reply.body.payload = match self.send(key, log, from_peer) {
+                    Ok(offset) => Payload::SendOk { offset },
+                    Err(e) => Payload::unavailable(e),
                 };
-
-                loop {
-                    let curr = offset;
-                    eprintln!("Curr|> {}", curr);
-                    let (prev, now) = (curr - 1, curr as usize);
-                    let res = self
-                        .sync_cas(latest_key.clone(), &self.lin_store, prev, now, true)
-                        .context("cas offset");
-
-                    match res {
-                        Ok(_) => break,
-                        Err(_) => offset += 1,
-                    };
-                }
-
-                eprintln!("KafkaLog4|> {:?}", i);
-                let msg_key = format!("{}:{}", key, offset);
-
-                self.sync_write(msg_key, &self.seq_store, msg)
-                    .context("write msg_key offset")?;
-
-                self.sync_write(latest_key, &self.seq_store, offset)
-                    .context("write latest key with offset")?;
-
-                reply.body.payload = Payload::SendOk { offset };
                 reply.send(&self.output).context("reply Send")?;
             }
-            Payload::Poll { offsets } => {
-                let o = offsets.clone();
-                let mut resp: HashMap<String, Vec<[usize; 2]>> = HashMap::new();
-
-                for (k, v) in offsets {
-                    let mut m = Vec::new();
-                    for i in v..(v + 5) {
-                        let val = self
-                            .sync_read(format!("{}:{}", k, i), &self.seq_store)
-                            .context("read msg_key offset");
-                        let val = match val {
-                            Ok(o) => o,
-                            Err(_) => continue,
-                        };
-                        m.push([i, val]);
-                    }
-                    resp.insert(k, m);
-                }
-
-                eprintln!("Poll|> {:?}, RESP|>{:?}", o, resp);
-                reply.body.payload = Payload::PollOk { msgs: resp };
+            Payload::SendBatch { key, msgs } => {
+                reply.body.payload = match self.send_batch(key, msgs, from_peer) {
+                    Ok(offsets) => Payload::SendBatchOk { offsets },
+                    Err(e) => Payload::unavailable(e),
+                };
+                reply.send(&self.output).context("reply SendBatch")?;
+            }
+            Payload::Poll {
+                offsets,
+                group,
+                isolation,
+                metadata,
+            } => {
+                let polled = self.poll_as(&reply.dst, offsets, group, isolation, from_peer);
+                reply.body.payload = match polled {
+                    Ok((polled, assignment)) => Payload::PollOk {
+                        msgs: polled.msgs,
+                        ends: polled.ends,
+                        assignment,
+                        metadata: if metadata {
+                            polled.metadata
+                        } else {
+                            HashMap::new()
+                        },
+                    },
+                    Err(e) => Payload::unavailable(e),
+                };
                 reply.send(&self.output).context("reply Poll")?;
             }
-            Payload::CommitOffsets { offsets } => {
-                offsets.into_iter().for_each(|(key, offset)| {
-                    let _ = self
-                        .sync_write(format!("commit:{}", key), &self.seq_store, offset)
-                        .context("write offset");
-                });
-
-                reply.body.payload = Payload::CommitOffsetsOk;
+            Payload::SendTxn { msgs } => {
+                reply.body.payload = match self.send_txn(msgs) {
+                    Ok(offsets) => Payload::SendTxnOk { offsets },
+                    Err(e) => Payload::unavailable(e),
+                };
+                reply.send(&self.output).context("reply SendTxn")?;
+            }
+            Payload::TxnAppend { txn, msgs } => {
+                reply.body.payload = match self.txn_append(&txn, msgs) {
+                    Ok(offsets) => Payload::TxnAppendOk { offsets },
+                    Err(e) => Payload::unavailable(e),
+                };
+                reply.send(&self.output).context("reply TxnAppend")?;
+            }
+            Payload::EndTxn { key, txn, outcome } => {
+                reply.body.payload = match self.end_txn(&key, &txn, outcome, from_peer) {
+                    Ok(outcome) => Payload::EndTxnOk { outcome },
+                    Err(e) => Payload::unavailable(e),
+                };
+                reply.send(&self.output).context("reply EndTxn")?;
+            }
+            Payload::TxnTick => self.txn_tick(now_ms()),
+            Payload::CommitOffsets { offsets, group } => {
+                reply.body.payload = match self.commit_offsets(offsets, group, from_peer) {
+                    Ok(()) => Payload::CommitOffsetsOk,
+                    Err(e) => Payload::unavailable(e),
+                };
                 reply.send(&self.output).context("reply CommitOffsets")?;
             }
-            Payload::ListCommittedOffsets { keys } => {
-                let mut resp = HashMap::new();
-                for key in keys {
-                    let offset = self
-                        .sync_read(format!("commit:{}", key), &self.seq_store)
-                        .context("list committed offset");
-                    let offset = match offset {
-                        Ok(o) => o,
-                        Err(_) => 0,
-                    };
-                    resp.insert(key, offset);
-                }
-
-                reply.body.payload = Payload::ListCommittedOffsetsOk { offsets: resp };
+            Payload::ListCommittedOffsets { keys, group } => {
+                reply.body.payload = match self.list_committed_offsets(keys, group, from_peer) {
+                    Ok(offsets) => Payload::ListCommittedOffsetsOk { offsets },
+                    Err(e) => Payload::unavailable(e),
+                };
                 reply
                     .send(&self.output)
                     .context("reply ListCommittedOffsets")?;
             }
+            Payload::ListOffsets { offsets } => {
+                reply.body.payload = match self.list_offsets(offsets, from_peer) {
+                    Ok(offsets) => Payload::ListOffsetsOk { offsets },
+                    Err(e) => Payload::unavailable(e),
+                };
+                reply.send(&self.output).context("reply ListOffsets")?;
+            }
+            Payload::Subscribe {
+                offsets,
+                credits,
+                isolation,
+            } => {
+                self.subscriptions
+                    .lock()
+                    .unwrap()
+                    .subscribe(&reply.dst, offsets, credits, isolation);
+                reply.body.payload = Payload::SubscribeOk;
+                reply.send(&self.output).context("reply Subscribe")?;
+            }
+            Payload::Credit { credits, offsets } => {
+                let credited = self
+                    .subscriptions
+                    .lock()
+                    .unwrap()
+                    .credit(&reply.dst, credits, &offsets);
+                reply.body.payload = match credited {
+                    Some(credits) => Payload::CreditOk { credits },
+                    None => Payload::Error {
+                        code: PRECONDITION_FAILED,
+                        text: "not subscribed".to_string(),
+                    },
+                };
+                reply.send(&self.output).context("reply Credit")?;
+            }
+            Payload::Unsubscribe { keys } => {
+                self.subscriptions
+                    .lock()
+                    .unwrap()
+                    .unsubscribe(&reply.dst, keys.as_deref());
+                reply.body.payload = Payload::UnsubscribeOk;
+                reply.send(&self.output).context("reply Unsubscribe")?;
+            }
+            Payload::PushTick => self.push_tick(),
+            Payload::ListKeys => {
+                reply.body.payload = match self.list_keys(from_peer) {
+                    Ok(keys) => Payload::ListKeysOk { keys },
+                    Err(e) => Payload::unavailable(e),
+                };
+                reply.send(&self.output).context("reply ListKeys")?;
+            }
+            request @ (Payload::DescribeKey { .. }
+            | Payload::DeleteKey { .. }
+            | Payload::TruncateKey { .. }) => {
+                reply.body.payload = self
+                    .admin(request, from_peer)
+                    .unwrap_or_else(Payload::unavailable);
+                reply.send(&self.output).context("reply to admin request")?;
+            }
+            Payload::JoinGroup {
+                group,
+                consumer,
+                keys,
+            } => {
+                let request = Payload::JoinGroup {
+                    group,
+                    consumer: consumer.or(Some(reply.dst.clone())),
+                    keys,
+                };
+                reply.body.payload = self
+                    .coordinate(request)
+                    .unwrap_or_else(Payload::unavailable);
+                reply.send(&self.output).context("reply JoinGroup")?;
+            }
+            Payload::LeaveGroup { group, consumer } => {
+                let request = Payload::LeaveGroup {
+                    group,
+                    consumer: consumer.or(Some(reply.dst.clone())),
+                };
+                reply.body.payload = self
+                    .coordinate(request)
+                    .unwrap_or_else(Payload::unavailable);
+                reply.send(&self.output).context("reply LeaveGroup")?;
+            }
+            request @ Payload::GroupHeartbeat { .. } => {
+                reply.body.payload = self
+                    .coordinate(request)
+                    .unwrap_or_else(Payload::unavailable);
+                reply.send(&self.output).context("reply GroupHeartbeat")?;
+            }
+            Payload::Replicate(replicate) => {
+                let ok = self
+                    .broker
+                    .lock()
+                    .unwrap()
+                    .follow(replicate, Instant::now());
+                reply.body.payload = match ok {
+                    Ok(ok) => Payload::ReplicateOk(ok),
+                    Err(e) => Payload::unavailable(e),
+                };
+                reply.send(&self.output).context("reply Replicate")?;
+            }
+            Payload::ReplicateOk(ok) => {
+                self.broker
+                    .lock()
+                    .unwrap()
+                    .replicated(&reply.dst, ok, Instant::now())?;
+            }
+            Payload::ReplicateTick => self.replicate_tick()?,
+            Payload::Heartbeat => {}
+            Payload::DropReplica { key, before } => {
+                reply.body.payload = match self.drop_replica(&key, before) {
+                    Ok(()) => Payload::DropReplicaOk,
+                    Err(e) => Payload::unavailable(e),
+                };
+                reply.send(&self.output).context("reply DropReplica")?;
+            }
+            Payload::CleanTick => {
+                let mut broker = self.broker.lock().unwrap();
+                if let Err(e) = broker.logs_mut().clean(&self.retention, self.compact) {
+                    eprintln!("cleaning logs: {:#}", e);
+                }
+            }
             Payload::Error { code, text } => {
                 eprintln!("Error {}: {}", code, text);
             }
             Payload::PollOk { .. }
             | Payload::SendOk { .. }
+            | Payload::SendBatchOk { .. }
+            | Payload::SendTxnOk { .. }
+            | Payload::TxnAppendOk { .. }
+            | Payload::EndTxnOk { .. }
             | Payload::CommitOffsetsOk
-            | Payload::ListCommittedOffsetsOk { .. } => {}
-            Payload::Read { key: _ } => todo!(),
-            Payload::ReadOk { value: _ } => todo!(),
-            Payload::Write { key: _, value: _ } => todo!(),
-            Payload::WriteOk => todo!(),
-            Payload::Cas {
-                key: _,
-                from: _,
-                to: _,
-                put: _,
-            } => todo!(),
-            Payload::CasOk => todo!(),
+            | Payload::ListCommittedOffsetsOk { .. }
+            | Payload::ListOffsetsOk { .. }
+            | Payload::SubscribeOk
+            | Payload::CreditOk { .. }
+            | Payload::UnsubscribeOk
+            | Payload::Push { .. }
+            | Payload::ListKeysOk { .. }
+            | Payload::DescribeKeyOk { .. }
+            | Payload::DeleteKeyOk
+            | Payload::TruncateKeyOk { .. }
+            | Payload::DropReplicaOk
+            | Payload::JoinGroupOk { .. }
+            | Payload::LeaveGroupOk
+            | Payload::GroupHeartbeatOk { .. } => {}
+            Payload::Read { key } => {
+                let res = self.apply_local(KvCommand::Read { key: key.into() });
+                reply.body.payload = match res {
+                    Ok(value) => Payload::ReadOk {
+                        value: value.unwrap_or_default(),
+                    },
+                    Err(e) => Payload::error(e),
+                };
+                reply.send(&self.output).context("reply Read")?;
+            }
+            Payload::Write { key, value } => {
+                let res = self.apply_local(KvCommand::Write {
+                    key: key.into(),
+                    value,
+                });
+                reply.body.payload = match res {
+                    Ok(_) => Payload::WriteOk,
+                    Err(e) => Payload::error(e),
+                };
+                reply.send(&self.output).context("reply Write")?;
+            }
+            Payload::Cas { key, from, to, put } => {
+                let res = self.apply_local(KvCommand::Cas {
+                    key: key.into(),
+                    from,
+                    to,
+                    put,
+                });
+                reply.body.payload = match res {
+                    Ok(_) => Payload::CasOk,
+                    Err(e) => Payload::error(e),
+                };
+                reply.send(&self.output).context("reply Cas")?;
+            }
+            // replies whose rpc was already answered or never registered
+            Payload::ReadOk { .. } | Payload::WriteOk | Payload::CasOk => {
+                eprintln!("unexpected store reply: {:?}", i);
+            }
         }
         Ok(())
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    main_loop::<_, KafkaLog, _>(())
+    main_loop::<_, KafkaLog, _>(Config::from_env()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn single_owner() -> KafkaLog {
+        let config = Config {
+            mode: Mode::Owner,
+            poll_batch: DEFAULT_POLL_BATCH,
+            replication_factor: 1,
+            min_insync: 1,
+            data_dir: None,
+            segment_bytes: None,
+            fsync: None,
+            retention: Retention::default(),
+            compact: false,
+        };
+        let init = nazgul::Init {
+            node_id: "n1".to_string(),
+            node_ids: vec!["n1".to_string()],
+        };
+        let (tx, _rx) = std::sync::mpsc::channel();
+        KafkaLog::from_init(config, init, tx).unwrap()
+    }
+
+    #[test]
+    fn txn_tick_aborts_expired_transactions() {
+        let node = single_owner();
+        let txn = TxnRef {
+            id: "c1:1".to_string(),
+            home: "k".to_string(),
+            outcome: None,
+        };
+        node.txn_append(&txn, vec![("k".to_string(), Value::from(1))])
+            .unwrap();
+
+        node.txn_tick(now_ms());
+        assert!(node
+            .broker
+            .lock()
+            .unwrap()
+            .logs()
+            .ended("k", &txn.id)
+            .is_none());
+
+        node.txn_tick(now_ms() + TXN_TIMEOUT.as_millis() as u64 + 1);
+        let ended = node.broker.lock().unwrap().logs().ended("k", &txn.id);
+        assert!(matches!(ended, Some((Outcome::Abort, _))));
+    }
 }
@@ -0,0 +1,214 @@
+use anyhow::Context;
+use nazgul::{
+    kv::{KvCommand, KvStore, TEMPORARILY_UNAVAILABLE, TIMEOUT},
+    rsm::{Consensus, ConsensusMsg, Protocol, Replica, RsmError},
+    *,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::Sender,
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(1000);
+
+struct LinKv {
+    id: AtomicUsize,
+    node: String,
+    nodes: Vec<String>,
+    replica: Mutex<Replica<KvStore>>,
+    rpc: Mutex<HashMap<usize, oneshot::Sender<Message<Payload>>>>,
+    output: Mutex<std::io::Stdout>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default, rename = "create_if_not_exists")]
+        put: bool,
+    },
+    CasOk,
+    Error {
+        code: usize,
+        text: String,
+    },
+    Consensus {
+        msg: ConsensusMsg<KvCommand>,
+    },
+    ConsensusTick,
+}
+
+impl LinKv {
+    fn send_consensus(&self, out: Vec<Message<ConsensusMsg<KvCommand>>>) -> anyhow::Result<()> {
+        for m in out {
+            let msg = Message::new(
+                m.src,
+                m.dst,
+                Body {
+                    id: None,
+                    in_reply_to: None,
+                    payload: Payload::Consensus {
+                        msg: m.body.payload,
+                    },
+                },
+            );
+            msg.send(&self.output)
+                .context("sending consensus message")?;
+        }
+        Ok(())
+    }
+
+    /// Runs `command` through the log, or hands the request to the leader
+    /// when a client asked a follower.
+    fn execute(&self, request: &Message<Payload>, command: KvCommand) -> Payload {
+        let ok = match command {
+            KvCommand::Write { .. } => Payload::WriteOk,
+            _ => Payload::CasOk,
+        };
+        let proposal = self.replica.lock().unwrap().propose(command);
+        let rx = match proposal {
+            Ok(rx) => rx,
+            Err(RsmError::NotLeader(Some(leader))) if !self.nodes.contains(&request.src) => {
+                return self.forward(&leader, request.body.payload.clone());
+            }
+            Err(e) => return error(TEMPORARILY_UNAVAILABLE, e),
+        };
+        match rx.recv_timeout(REQUEST_TIMEOUT) {
+            Ok(Ok(Ok(Some(value)))) => Payload::ReadOk { value },
+            Ok(Ok(Ok(None))) => ok,
+            Ok(Ok(Err(e))) => error(e.code(), e),
+            // a dropped proposal may still commit under the next leader, so
+            // the outcome is as unknown as after a timeout
+            Ok(Err(e)) => error(TIMEOUT, e),
+            Err(_) => error(TIMEOUT, "proposal timed out"),
+        }
+    }
+
+    fn forward(&self, leader: &str, payload: Payload) -> Payload {
+        let id = self.id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        self.rpc.lock().unwrap().insert(id, tx);
+        let msg = Message::new(
+            self.node.clone(),
+            leader.to_string(),
+            Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload,
+            },
+        );
+        if let Err(e) = msg.send(&self.output) {
+            self.rpc.lock().unwrap().remove(&id);
+            return error(TEMPORARILY_UNAVAILABLE, e);
+        }
+        match rx.recv_timeout(REQUEST_TIMEOUT) {
+            Ok(res) => res.body.payload,
+            Err(_) => {
+                self.rpc.lock().unwrap().remove(&id);
+                error(TIMEOUT, format!("forwarding to {} timed out", leader))
+            }
+        }
+    }
+}
+
+fn error(code: usize, text: impl ToString) -> Payload {
+    Payload::Error {
+        code,
+        text: text.to_string(),
+    }
+}
+
+impl Node<Protocol, Payload> for LinKv {
+    fn from_init(
+        protocol: Protocol,
+        init: Init,
+        tx: Sender<Message<Payload>>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        spawn_ticker(
+            init.node_id.clone(),
+            TICK_INTERVAL,
+            Payload::ConsensusTick,
+            tx,
+        );
+        let replica = Replica::new(
+            protocol,
+            init.node_id.clone(),
+            &init.node_ids,
+            KvStore::default(),
+        );
+        Ok(LinKv {
+            id: AtomicUsize::new(1),
+            node: init.node_id,
+            nodes: init.node_ids,
+            replica: Mutex::new(replica),
+            rpc: Mutex::new(HashMap::new()),
+            output: Mutex::new(std::io::stdout()),
+        })
+    }
+
+    fn step(&self, input: Message<Payload>) -> anyhow::Result<()> {
+        if let Some(in_reply_to) = input.body.in_reply_to {
+            if let Some(tx) = self.rpc.lock().unwrap().remove(&in_reply_to) {
+                let _ = tx.send(input);
+            }
+            return Ok(());
+        }
+
+        let request = input.clone();
+        let mut reply = input.into_reply(Some(&self.id));
+        let command = match reply.body.payload {
+            Payload::Consensus { msg } => {
+                let out = self
+                    .replica
+                    .lock()
+                    .unwrap()
+                    .handle(&request.src, msg, Instant::now());
+                return self.send_consensus(out);
+            }
+            Payload::ConsensusTick => {
+                let out = self.replica.lock().unwrap().tick(Instant::now());
+                return self.send_consensus(out);
+            }
+            Payload::Read { key } => KvCommand::Read { key },
+            Payload::Write { key, value } => KvCommand::Write { key, value },
+            Payload::Cas { key, from, to, put } => KvCommand::Cas { key, from, to, put },
+            Payload::Error { code, text } => {
+                eprintln!("Error {}: {}", code, text);
+                return Ok(());
+            }
+            Payload::ReadOk { .. } | Payload::WriteOk | Payload::CasOk => return Ok(()),
+        };
+        reply.body.payload = self.execute(&request, command);
+        reply.send(&self.output).context("reply to kv request")
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    main_loop::<_, LinKv, _>(Protocol::from_env()?)
+}
@@ -1,14 +1,164 @@
-use std::sync::{atomic::AtomicUsize, mpsc::Sender, Mutex};
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::Sender,
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use anyhow::{Context, Ok};
-use nazgul::*;
+use anyhow::{bail, Context};
+use nazgul::{
+    ids::{FileHighWater, IdGenerator, Reserve, Snowflake, DEFAULT_BLOCK_SIZE},
+    kv::{RpcError, KEY_DOES_NOT_EXIST, PRECONDITION_FAILED, TEMPORARILY_UNAVAILABLE, TIMEOUT},
+    *,
+};
 
 use serde::{Deserialize, Serialize};
 
-struct UniqueIdNode {
+const LIN_KV: &str = "lin-kv";
+const RPC_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// How ids are generated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Mode {
+    /// `"{node}-{n}"` with blocks of `n` reserved in a high-water mark file
+    /// under this directory.
+    File(PathBuf),
+    /// `"{node}-{n}"` with blocks of `n` reserved in lin-kv.
+    LinKv,
+    /// Time-ordered 64-bit numbers.
+    Snowflake,
+    /// `"{node}-{n}"` from an in-memory counter. Needs no coordination at
+    /// all, but starts over when the node restarts, so ids repeat.
+    Counter,
+}
+
+/// `NAZGUL_ID_MODE` picks `lin-kv`, `snowflake` or `counter`. Without it,
+/// blocks are reserved in a file under `NAZGUL_DATA_DIR` when that is set,
+/// and in lin-kv otherwise; either way ids stay unique across restarts.
+struct Config {
+    mode: Mode,
+}
+
+impl Config {
+    fn from_env() -> anyhow::Result<Self> {
+        let mode = match env::var("NAZGUL_ID_MODE").ok().as_deref() {
+            None => match env::var_os("NAZGUL_DATA_DIR") {
+                Some(dir) => Mode::File(PathBuf::from(dir)),
+                None => Mode::LinKv,
+            },
+            Some("lin-kv") => Mode::LinKv,
+            Some("snowflake") => Mode::Snowflake,
+            Some("counter") => Mode::Counter,
+            Some(other) => bail!("unknown NAZGUL_ID_MODE {:?}", other),
+        };
+        Ok(Self { mode })
+    }
+}
+
+/// Sends requests to services and waits for their replies.
+struct Client {
+    node: String,
     id: AtomicUsize,
+    rpc: Mutex<HashMap<usize, oneshot::Sender<Message<Payload>>>>,
     output: Mutex<std::io::Stdout>,
-    node: String,
+}
+
+impl Client {
+    /// Error replies come back as [`RpcError`]s.
+    fn call(&self, dest: &str, payload: Payload) -> anyhow::Result<Payload> {
+        let id = self.id.fetch_add(1, Ordering::SeqCst);
+        let (tx, rx) = oneshot::channel();
+        self.rpc.lock().unwrap().insert(id, tx);
+        let msg = Message::new(
+            self.node.clone(),
+            dest.to_string(),
+            Body {
+                id: Some(id),
+                in_reply_to: None,
+                payload,
+            },
+        );
+        if let Err(e) = msg.send(&self.output) {
+            self.rpc.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+        let Ok(reply) = rx.recv_timeout(RPC_TIMEOUT) else {
+            self.rpc.lock().unwrap().remove(&id);
+            return Err(RpcError {
+                code: TIMEOUT,
+                text: format!("{} did not answer", dest),
+            }
+            .into());
+        };
+        match reply.body.payload {
+            Payload::Error { code, text } => Err(RpcError { code, text }.into()),
+            payload => Ok(payload),
+        }
+    }
+}
+
+/// Reserves blocks by moving the node's high-water mark in lin-kv forward
+/// with compare-and-set. A request that timed out may still have moved it;
+/// the block is then skipped, never handed out twice.
+struct LinKvBlocks {
+    client: Arc<Client>,
+    key: String,
+}
+
+impl Reserve for LinKvBlocks {
+    fn reserve(&self, n: u64) -> anyhow::Result<u64> {
+        loop {
+            let read = Payload::Read {
+                key: self.key.clone(),
+            };
+            let current = match self.client.call(LIN_KV, read) {
+                Ok(Payload::ReadOk { value }) => Some(value),
+                Ok(other) => bail!("unexpected reply to read: {:?}", other),
+                Err(e) if RpcError::is_missing(&e) => None,
+                Err(e) => return Err(e.context("read high-water mark")),
+            };
+            let from = current.unwrap_or_default();
+            let cas = Payload::Cas {
+                key: self.key.clone(),
+                from,
+                to: from + n,
+                put: current.is_none(),
+            };
+            match self.client.call(LIN_KV, cas) {
+                Ok(Payload::CasOk) => return Ok(from + 1),
+                Ok(other) => bail!("unexpected reply to cas: {:?}", other),
+                Err(e)
+                    if matches!(
+                        RpcError::code_of(&e),
+                        Some(PRECONDITION_FAILED | KEY_DOES_NOT_EXIST)
+                    ) => {}
+                Err(e) => return Err(e.context("move high-water mark")),
+            }
+        }
+    }
+}
+
+enum Ids {
+    Counter(AtomicU64),
+    Blocks(IdGenerator),
+    Snowflake(Snowflake),
+}
+
+struct UniqueIdNode {
+    client: Arc<Client>,
+    ids: Ids,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Guid {
+    Block(String),
+    Snowflake(u64),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,43 +168,113 @@ enum Payload {
     Generate,
     GenerateOk {
         #[serde(rename = "id")]
-        guid: String,
+        guid: Guid,
+    },
+    Error {
+        code: usize,
+        text: String,
     },
+
+    // lin-kv
+    Read {
+        key: String,
+    },
+    ReadOk {
+        value: u64,
+    },
+    Cas {
+        key: String,
+        from: u64,
+        to: u64,
+        #[serde(default, rename = "create_if_not_exists")]
+        put: bool,
+    },
+    CasOk,
 }
 
-impl Node<(), Payload> for UniqueIdNode {
-    fn from_init(_state: (), init: Init, _tx: Sender<Message<Payload>>) -> anyhow::Result<Self>
+impl Node<Config, Payload> for UniqueIdNode {
+    fn from_init(config: Config, init: Init, _tx: Sender<Message<Payload>>) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        Ok(UniqueIdNode {
+        let client = Arc::new(Client {
+            node: init.node_id.clone(),
             id: AtomicUsize::new(1),
+            rpc: Mutex::new(HashMap::new()),
             output: Mutex::new(std::io::stdout()),
-            node: init.node_id,
-        })
+        });
+        let ids = match config.mode {
+            Mode::Counter => Ids::Counter(AtomicU64::new(1)),
+            Mode::Snowflake => {
+                let node = init
+                    .node_ids
+                    .iter()
+                    .position(|n| *n == init.node_id)
+                    .context("node_ids does not list this node")?;
+                Ids::Snowflake(Snowflake::new(node)?)
+            }
+            Mode::File(dir) => {
+                let dir = dir.join(&init.node_id);
+                fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+                Ids::Blocks(IdGenerator::new(
+                    FileHighWater::new(dir.join("unique-ids")),
+                    DEFAULT_BLOCK_SIZE,
+                ))
+            }
+            Mode::LinKv => {
+                let blocks = LinKvBlocks {
+                    client: client.clone(),
+                    key: format!("unique-ids:{}", init.node_id),
+                };
+                Ids::Blocks(IdGenerator::new(blocks, DEFAULT_BLOCK_SIZE))
+            }
+        };
+        Ok(UniqueIdNode { client, ids })
     }
 
     fn step(&self, input: Message<Payload>) -> anyhow::Result<()> {
-        let mut reply = input.into_reply(Some(&self.id));
+        if let Some(in_reply_to) = input.body.in_reply_to {
+            if let Some(tx) = self.client.rpc.lock().unwrap().remove(&in_reply_to) {
+                let _ = tx.send(input);
+            }
+            return Ok(());
+        }
+        let mut reply = input.into_reply(Some(&self.client.id));
         match reply.body.payload {
             Payload::Generate => {
-                reply.body.payload = Payload::GenerateOk {
-                    guid: format!(
+                let guid = match &self.ids {
+                    Ids::Counter(n) => Ok(Guid::Block(format!(
                         "{}-{}",
-                        self.node,
-                        self.id.load(std::sync::atomic::Ordering::Relaxed)
-                    ),
+                        self.client.node,
+                        n.fetch_add(1, Ordering::Relaxed)
+                    ))),
+                    Ids::Blocks(ids) => ids
+                        .next()
+                        .map(|n| Guid::Block(format!("{}-{}", self.client.node, n))),
+                    Ids::Snowflake(ids) => ids.next().map(Guid::Snowflake),
+                };
+                reply.body.payload = match guid {
+                    Ok(guid) => Payload::GenerateOk { guid },
+                    Err(e) => Payload::Error {
+                        code: RpcError::code_of(&e).unwrap_or(TEMPORARILY_UNAVAILABLE),
+                        text: format!("{:#}", e),
+                    },
                 };
                 reply
-                    .send(&self.output)
+                    .send(&self.client.output)
                     .context("failed to serialize response")?;
             }
-            Payload::GenerateOk { .. } => {}
+            Payload::GenerateOk { .. }
+            | Payload::Error { .. }
+            | Payload::Read { .. }
+            | Payload::ReadOk { .. }
+            | Payload::Cas { .. }
+            | Payload::CasOk => {}
         }
         Ok(())
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    main_loop::<_, UniqueIdNode, _>(())
+    main_loop::<_, UniqueIdNode, _>(Config::from_env()?)
 }
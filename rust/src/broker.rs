@@ -0,0 +1,707 @@
+//! One kafka-log node's share of the replicated logs.
+//!
+//! Every key is held by `replication_factor` nodes, the first ones at or
+//! after the key on the hash ring. The first of them leads epoch 0 on every
+//! node; after that leadership only moves through [`Partition::take_over`],
+//! once the leader is suspected dead. The leader appends to its log and sends
+//! each follower the entries it is missing along with the ISR, the high
+//! watermark and the key's committed offsets; followers copy them and answer
+//! with their end and the version of the offsets they copied. A commit holds
+//! once every ISR member has copied its version. [`crate::replication`]
+//! describes how the ISR and the high watermark move.
+//!
+//! The broker does no I/O: calls return the messages to send, and the node
+//! hands it the ones that arrive.
+
+use crate::{
+    log::{Isolation, Log, LogStore},
+    replication::{Epoch, Follow, Partition, DEFAULT_LAG_TIMEOUT},
+    ring::HashRing,
+    Body, Message,
+};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+/// Most entries sent to a follower in one replicate message.
+pub const MAX_REPLICATE_BATCH: usize = 100;
+
+/// Entries from a key's leader, sent on every replication tick and after
+/// every append.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replicate {
+    pub key: String,
+    pub epoch: Epoch,
+    pub isr: BTreeSet<String>,
+    pub hw: usize,
+    /// The entries follow this offset.
+    pub prev: usize,
+    pub entries: Vec<Log>,
+    #[serde(default)]
+    pub commits: HashMap<String, usize>,
+    /// Counts the leader's commits on the key in its epoch.
+    #[serde(default)]
+    pub version: usize,
+}
+
+/// A follower's answer: the epoch it is in and the last offset it has.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicateOk {
+    pub key: String,
+    pub epoch: Epoch,
+    pub end: usize,
+    /// The version of the committed offsets the follower copied.
+    #[serde(default)]
+    pub version: usize,
+}
+
+/// The versions of a key's committed offsets its leader has sent and each
+/// follower has copied, within one epoch.
+#[derive(Debug)]
+struct CommitVersions {
+    epoch: Epoch,
+    version: usize,
+    copied: HashMap<String, usize>,
+}
+
+impl CommitVersions {
+    fn new(epoch: Epoch) -> Self {
+        Self {
+            epoch,
+            version: 0,
+            copied: HashMap::new(),
+        }
+    }
+}
+
+pub type Outbox = Vec<Message<Replicate>>;
+
+#[derive(Debug)]
+pub struct Broker {
+    me: String,
+    ring: HashRing,
+    replication_factor: usize,
+    min_insync: usize,
+    lag_timeout: Duration,
+    logs: LogStore,
+    /// Replication state of every key this node holds a replica of and has
+    /// heard of since it started.
+    partitions: HashMap<String, Partition>,
+    /// Per log key, keyed by commit key. Replicated with the log.
+    commits: HashMap<String, HashMap<String, usize>>,
+    /// Of the keys this node leads and has committed offsets on.
+    versions: HashMap<String, CommitVersions>,
+}
+
+impl Broker {
+    /// Keys already in `logs` are picked up in epoch 0, like new ones. Each
+    /// key takes writes while at least `min_insync` of its replicas are in
+    /// sync.
+    pub fn new(
+        me: impl Into<String>,
+        nodes: &[String],
+        replication_factor: usize,
+        min_insync: usize,
+        logs: LogStore,
+        now: Instant,
+    ) -> Self {
+        let mut broker = Self {
+            me: me.into(),
+            ring: HashRing::new(nodes),
+            replication_factor,
+            min_insync,
+            lag_timeout: DEFAULT_LAG_TIMEOUT,
+            logs,
+            partitions: HashMap::new(),
+            commits: HashMap::new(),
+            versions: HashMap::new(),
+        };
+        for key in broker.logs.keys() {
+            broker.track(&key, now);
+        }
+        broker
+    }
+
+    pub fn logs(&self) -> &LogStore {
+        &self.logs
+    }
+
+    pub fn logs_mut(&mut self) -> &mut LogStore {
+        &mut self.logs
+    }
+
+    /// The nodes holding `key`, in the order they take over leading it.
+    pub fn replicas(&self, key: &str) -> Vec<String> {
+        self.ring
+            .replicas(key, self.replication_factor)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// `key`'s partition, if this node tracks it. Never creates one.
+    pub fn partition(&self, key: &str) -> Option<&Partition> {
+        self.partitions.get(key)
+    }
+
+    /// The node leading `key` as far as this one knows: the first replica
+    /// until an epoch change says otherwise.
+    pub fn leader(&self, key: &str) -> Option<String> {
+        match self.partitions.get(key) {
+            Some(p) => Some(p.leader().to_string()),
+            None => self.replicas(key).into_iter().next(),
+        }
+    }
+
+    /// The high watermark of `key`, 0 for keys this node does not track.
+    pub fn hw(&self, key: &str) -> usize {
+        self.partitions.get(key).map_or(0, |p| p.hw())
+    }
+
+    /// Starts tracking `key` if this node holds a replica of it, so it can
+    /// follow, lead or take it over. Returns whether it does.
+    pub fn track(&mut self, key: &str, now: Instant) -> bool {
+        if self.partitions.contains_key(key) {
+            return true;
+        }
+        let replicas = self.replicas(key);
+        if !replicas.contains(&self.me) {
+            return false;
+        }
+        let leader = replicas[0].clone();
+        let end = self.logs.end(key);
+        let p = Partition::new(
+            self.me.clone(),
+            replicas,
+            leader,
+            end,
+            self.lag_timeout,
+            self.min_insync,
+            now,
+        );
+        self.partitions.insert(key.to_string(), p);
+        true
+    }
+
+    /// Appends `log` to `key`, which this node must lead, and returns the
+    /// epoch and offset it went in at. A send repeating a producer's
+    /// sequence number gets the offset of the first one instead.
+    pub fn append(&mut self, key: &str, log: Log, now: Instant) -> anyhow::Result<(Epoch, usize)> {
+        self.track(key, now);
+        let Some(p) = self.partitions.get_mut(key).filter(|p| p.is_leader()) else {
+            bail!("{} does not lead {}", self.me, key);
+        };
+        if !p.writable() {
+            bail!("{} has too few in-sync replicas", key);
+        }
+        if let (Some(producer), Some(seq)) = (&log.producer, log.seq) {
+            if let Some(offset) = self.logs.duplicate(key, producer, seq)? {
+                return Ok((p.epoch().clone(), offset));
+            }
+        }
+        let offset = self.logs.append(key.to_string(), log)?;
+        p.appended(offset);
+        Ok((p.epoch().clone(), offset))
+    }
+
+    /// The entries a poll at `isolation` gets from `from` on, at most `max`,
+    /// and the last offset it may see: the high watermark, or the last
+    /// offset before a pending transaction if that is lower.
+    pub fn poll(
+        &self,
+        key: &str,
+        from: usize,
+        isolation: Isolation,
+        max: usize,
+    ) -> anyhow::Result<(Vec<Log>, usize)> {
+        let hw = self.hw(key);
+        let end = match isolation {
+            Isolation::ReadUncommitted => hw,
+            Isolation::ReadCommitted => hw.min(self.logs.stable_end(key)),
+        };
+        let entries = self.logs.read_visible(key, from, end, isolation, max)?;
+        Ok((entries, end))
+    }
+
+    /// Moves `commit_key`'s committed offset on `key`, which this node must
+    /// lead, forward to `offset`. Stale commits leave it in place. Returns
+    /// the epoch and the version the ISR must copy before the commit holds;
+    /// see [`Self::commit_replicated`].
+    pub fn commit(
+        &mut self,
+        key: &str,
+        commit_key: String,
+        offset: usize,
+        now: Instant,
+    ) -> anyhow::Result<(Epoch, usize)> {
+        self.track(key, now);
+        let Some(p) = self.partitions.get(key).filter(|p| p.is_leader()) else {
+            bail!("{} does not lead {}", self.me, key);
+        };
+        if !p.writable() {
+            bail!("{} has too few in-sync replicas", key);
+        }
+        let epoch = p.epoch().clone();
+        let versions = self
+            .versions
+            .entry(key.to_string())
+            .or_insert_with(|| CommitVersions::new(epoch.clone()));
+        if versions.epoch != epoch {
+            *versions = CommitVersions::new(epoch.clone());
+        }
+        versions.version += 1;
+        let version = versions.version;
+        let current = self
+            .commits
+            .entry(key.to_string())
+            .or_default()
+            .entry(commit_key)
+            .or_default();
+        *current = (*current).max(offset);
+        Ok((epoch, version))
+    }
+
+    /// Whether every follower in `key`'s ISR has copied `version` of its
+    /// committed offsets. False once this node no longer leads `key`, and
+    /// while its ISR is below the minimum.
+    pub fn commit_replicated(&self, key: &str, version: usize) -> bool {
+        let (Some(p), Some(versions)) = (self.partitions.get(key), self.versions.get(key)) else {
+            return false;
+        };
+        p.writable()
+            && versions.epoch == *p.epoch()
+            && p.isr()
+                .iter()
+                .filter(|r| **r != self.me)
+                .all(|r| versions.copied.get(r).is_some_and(|v| *v >= version))
+    }
+
+    /// The committed offsets of `key` by commit key.
+    pub fn committed(&self, key: &str) -> Option<&HashMap<String, usize>> {
+        self.commits.get(key)
+    }
+
+    /// Messages sending every follower of `key` the entries it is missing,
+    /// if this node leads it.
+    pub fn replicate(&self, key: &str) -> anyhow::Result<Outbox> {
+        let Some(p) = self.partitions.get(key).filter(|p| p.is_leader()) else {
+            return Ok(Vec::new());
+        };
+        let commits = self.commits.get(key).cloned().unwrap_or_default();
+        let version = self
+            .versions
+            .get(key)
+            .filter(|v| v.epoch == *p.epoch())
+            .map_or(0, |v| v.version);
+        let mut out = Vec::new();
+        for (follower, prev) in p.followers() {
+            let replicate = Replicate {
+                key: key.to_string(),
+                epoch: p.epoch().clone(),
+                isr: p.isr().clone(),
+                hw: p.hw(),
+                prev,
+                entries: self.logs.read(key, prev + 1, MAX_REPLICATE_BATCH)?,
+                commits: commits.clone(),
+                version,
+            };
+            out.push(Message::new(
+                self.me.clone(),
+                follower,
+                Body {
+                    id: None,
+                    in_reply_to: None,
+                    payload: replicate,
+                },
+            ));
+        }
+        Ok(out)
+    }
+
+    /// Copies entries from the leader and answers with this replica's epoch
+    /// and end.
+    pub fn follow(&mut self, replicate: Replicate, now: Instant) -> anyhow::Result<ReplicateOk> {
+        let Replicate {
+            key,
+            epoch,
+            isr,
+            hw,
+            prev,
+            entries,
+            commits,
+            version,
+        } = replicate;
+        if !self.track(&key, now) {
+            bail!("{} holds no replica of {}", self.me, key);
+        }
+        let p = self.partitions.get_mut(&key).expect("tracked above");
+        let follow = p.follow(&epoch, isr, hw, prev, now);
+        if let Follow::Append {
+            truncate: Some(offset),
+        }
+        | Follow::Behind {
+            truncate: Some(offset),
+        } = follow
+        {
+            self.logs.truncate_after(&key, offset)?;
+        }
+        let mut copied = 0;
+        if let Follow::Append { .. } = follow {
+            let end = p.end();
+            for entry in entries.into_iter().filter(|e| e.offset > end) {
+                self.logs.append_at(key.clone(), entry)?;
+            }
+            p.replicated(self.logs.end(&key), hw);
+            let committed = self.commits.entry(key.clone()).or_default();
+            for (commit_key, offset) in commits {
+                let current = committed.entry(commit_key).or_default();
+                *current = (*current).max(offset);
+            }
+            copied = version;
+        }
+        Ok(ReplicateOk {
+            epoch: p.epoch().clone(),
+            end: p.end(),
+            key,
+            version: copied,
+        })
+    }
+
+    /// A follower answered a replicate message.
+    pub fn replicated(
+        &mut self,
+        follower: &str,
+        ok: ReplicateOk,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        let Some(p) = self.partitions.get_mut(&ok.key) else {
+            return Ok(());
+        };
+        if ok.epoch == *p.epoch() {
+            p.acked(follower, ok.end, now);
+            if let Some(versions) = self.versions.get_mut(&ok.key) {
+                if versions.epoch == ok.epoch {
+                    let copied = versions.copied.entry(follower.to_string()).or_default();
+                    *copied = (*copied).max(ok.version);
+                }
+            }
+        } else if let Some(offset) = p.observe(&ok.epoch, now) {
+            self.logs.truncate_after(&ok.key, offset)?;
+        }
+        Ok(())
+    }
+
+    /// Shrinks ISRs, takes over partitions whose leader is not `alive`, and
+    /// replicates every partition this node leads.
+    pub fn tick(&mut self, alive: impl Fn(&str) -> bool, now: Instant) -> anyhow::Result<Outbox> {
+        let mut leading = Vec::new();
+        for (key, p) in self.partitions.iter_mut() {
+            p.tick(now);
+            p.take_over(&alive, now);
+            if p.is_leader() {
+                leading.push(key.clone());
+            }
+        }
+        let mut out = Vec::new();
+        for key in leading {
+            out.extend(self.replicate(&key)?);
+        }
+        Ok(out)
+    }
+
+    /// Drops `key`'s entries before `before`, or all of the key.
+    pub fn drop_entries(&mut self, key: &str, before: Option<usize>) -> anyhow::Result<()> {
+        match before {
+            Some(before) => self.logs.truncate_before(key, before),
+            None => {
+                // the partition stays, so appends go on after the old end
+                self.commits.remove(key);
+                self.versions.remove(key);
+                self.logs.delete(key)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    const KEY: &str = "k";
+
+    struct Cluster {
+        brokers: BTreeMap<String, Broker>,
+        nodes: Vec<String>,
+        down: BTreeSet<String>,
+        now: Instant,
+    }
+
+    impl Cluster {
+        fn new(n: usize) -> Self {
+            let nodes: Vec<String> = (1..=n).map(|i| format!("n{}", i)).collect();
+            let now = Instant::now();
+            let brokers = nodes
+                .iter()
+                .map(|id| {
+                    let broker = Broker::new(id, &nodes, 3, 1, LogStore::in_memory(), now);
+                    (id.clone(), broker)
+                })
+                .collect();
+            Self {
+                brokers,
+                nodes,
+                down: BTreeSet::new(),
+                now,
+            }
+        }
+
+        fn replicas(&self) -> Vec<String> {
+            self.brokers["n1"].replicas(KEY)
+        }
+
+        fn broker(&mut self, id: &str) -> &mut Broker {
+            self.brokers.get_mut(id).unwrap()
+        }
+
+        /// Appends `value` on `id` and sends it to the followers, like a
+        /// send does before it waits for the high watermark.
+        fn send(&mut self, id: &str, value: u64) -> usize {
+            let now = self.now;
+            let broker = self.broker(id);
+            let (_, offset) = broker.append(KEY, Log::new(json!(value)), now).unwrap();
+            let out = broker.replicate(KEY).unwrap();
+            self.deliver(out);
+            offset
+        }
+
+        fn deliver(&mut self, out: Outbox) {
+            for msg in out {
+                if self.down.contains(&msg.src) || self.down.contains(&msg.dst) {
+                    continue;
+                }
+                let now = self.now;
+                let ok = self.broker(&msg.dst).follow(msg.body.payload, now).unwrap();
+                self.broker(&msg.src).replicated(&msg.dst, ok, now).unwrap();
+            }
+        }
+
+        /// Replication ticks on every live node, 50ms apart, for `duration`.
+        fn run(&mut self, duration: Duration) {
+            let end = self.now + duration;
+            while self.now < end {
+                self.now += Duration::from_millis(50);
+                let mut out = Vec::new();
+                for (id, broker) in &mut self.brokers {
+                    if !self.down.contains(id) {
+                        let down = &self.down;
+                        out.extend(broker.tick(|n| !down.contains(n), self.now).unwrap());
+                    }
+                }
+                self.deliver(out);
+            }
+        }
+
+        /// Restarts `id` with the log it had, as a node with a disk does.
+        fn restart(&mut self, id: &str) {
+            let old = self.brokers.remove(id).unwrap();
+            let broker = Broker::new(id, &self.nodes, 3, 1, old.logs, self.now);
+            self.brokers.insert(id.to_string(), broker);
+            self.down.remove(id);
+        }
+
+        fn values(&self, id: &str) -> Vec<u64> {
+            let logs = &self.brokers[id].logs;
+            let entries = logs.read(KEY, 0, usize::MAX).unwrap();
+            entries.iter().map(|l| l.value.as_u64().unwrap()).collect()
+        }
+
+        fn polled(&self, id: &str) -> Vec<usize> {
+            let broker = &self.brokers[id];
+            let (entries, _) = broker
+                .poll(KEY, 1, Isolation::ReadUncommitted, 100)
+                .unwrap();
+            entries.iter().map(|l| l.offset).collect()
+        }
+    }
+
+    #[test]
+    fn the_first_replica_leads_epoch_zero_everywhere() {
+        let mut c = Cluster::new(5);
+        let replicas = c.replicas();
+        let now = c.now;
+        for id in replicas.clone() {
+            // no lookup creates a partition, on replicas or elsewhere
+            assert_eq!(c.broker(&id).leader(KEY), Some(replicas[0].clone()));
+            assert_eq!(c.broker(&id).hw(KEY), 0);
+            assert!(c.broker(&id).partition(KEY).is_none());
+
+            assert!(c.broker(&id).track(KEY, now));
+            let p = c.broker(&id).partition(KEY).unwrap();
+            assert_eq!((p.epoch().number, p.leader()), (0, replicas[0].as_str()));
+        }
+        let outsider = c.nodes.iter().find(|n| !replicas.contains(n)).unwrap();
+        let outsider = outsider.clone();
+        assert!(!c.broker(&outsider).track(KEY, now));
+        assert!(c
+            .broker(&outsider)
+            .append(KEY, Log::new(json!(1)), now)
+            .is_err());
+    }
+
+    #[test]
+    fn polls_stop_at_the_high_watermark() {
+        let mut c = Cluster::new(3);
+        let leader = c.replicas()[0].clone();
+        c.down.insert(c.replicas()[2].clone());
+        assert_eq!(c.send(&leader, 10), 1);
+        assert_eq!(c.send(&leader, 11), 2);
+
+        // the ISR still holds the follower that is down
+        assert_eq!(c.brokers[&leader].hw(KEY), 0);
+        assert!(c.polled(&leader).is_empty());
+
+        c.down.clear();
+        c.run(Duration::from_millis(100));
+        assert_eq!(c.brokers[&leader].hw(KEY), 2);
+        assert_eq!(c.polled(&leader), vec![1, 2]);
+        for id in c.replicas() {
+            assert_eq!(c.values(&id), vec![10, 11]);
+        }
+    }
+
+    #[test]
+    fn isr_shrinks_around_a_lagging_follower() {
+        let mut c = Cluster::new(3);
+        let replicas = c.replicas();
+        let (leader, lagging) = (replicas[0].clone(), replicas[2].clone());
+        c.run(Duration::from_millis(100));
+        c.down.insert(lagging.clone());
+        c.send(&leader, 1);
+        c.run(Duration::from_millis(500));
+        assert_eq!(c.brokers[&leader].hw(KEY), 0);
+
+        c.run(DEFAULT_LAG_TIMEOUT);
+        let p = c.brokers[&leader].partition(KEY).unwrap();
+        assert!(!p.isr().contains(&lagging));
+        assert_eq!(p.hw(), 1);
+
+        // it is back in once it has caught up
+        c.down.clear();
+        c.run(Duration::from_millis(200));
+        let p = c.brokers[&leader].partition(KEY).unwrap();
+        assert!(p.isr().contains(&lagging));
+        assert_eq!(c.values(&lagging), vec![1]);
+    }
+
+    #[test]
+    fn a_follower_takes_over_without_losing_acked_offsets() {
+        let mut c = Cluster::new(3);
+        let replicas = c.replicas();
+        let (old, next) = (replicas[0].clone(), replicas[1].clone());
+        for v in 1..=3 {
+            c.send(&old, v);
+        }
+        c.run(Duration::from_millis(100));
+        assert_eq!(c.brokers[&old].hw(KEY), 3);
+
+        // appended but never replicated, so never acknowledged
+        c.down.insert(next.clone());
+        c.down.insert(replicas[2].clone());
+        c.send(&old, 4);
+        c.down = BTreeSet::from([old.clone()]);
+
+        c.run(Duration::from_millis(100));
+        let p = c.brokers[&next].partition(KEY).unwrap();
+        assert_eq!((p.epoch().number, p.is_leader()), (1, true));
+        assert_eq!(c.polled(&next), vec![1, 2, 3]);
+        assert_eq!(c.send(&next, 40), 4);
+        c.run(Duration::from_millis(100));
+        assert_eq!(c.brokers[&next].hw(KEY), 4);
+
+        // the old leader comes back, learns of epoch 1 and drops its 4
+        c.restart(&old);
+        c.run(Duration::from_millis(300));
+        assert_eq!(c.brokers[&old].leader(KEY), Some(next.clone()));
+        for id in &replicas {
+            assert_eq!(c.values(id), vec![1, 2, 3, 40], "log of {}", id);
+        }
+    }
+
+    #[test]
+    fn commits_reach_the_followers() {
+        let mut c = Cluster::new(3);
+        let replicas = c.replicas();
+        let now = c.now;
+        let leader = c.broker(&replicas[0]);
+        leader.commit(KEY, "commit:k".to_string(), 5, now).unwrap();
+        leader.commit(KEY, "commit:k".to_string(), 3, now).unwrap();
+        assert!(c
+            .broker(&replicas[1])
+            .commit(KEY, "commit:k".to_string(), 1, now)
+            .is_err());
+        c.run(Duration::from_millis(100));
+        for id in &replicas {
+            assert_eq!(c.brokers[id].committed(KEY).unwrap()["commit:k"], 5);
+        }
+    }
+
+    #[test]
+    fn deleted_keys_go_on_from_their_old_end() {
+        let mut c = Cluster::new(3);
+        let replicas = c.replicas();
+        let leader = replicas[0].clone();
+        c.send(&leader, 1);
+        c.send(&leader, 2);
+        let now = c.now;
+        c.broker(&leader)
+            .commit(KEY, "commit:k".to_string(), 2, now)
+            .unwrap();
+        c.run(Duration::from_millis(100));
+
+        for id in &replicas {
+            c.broker(id).drop_entries(KEY, None).unwrap();
+            assert!(c.values(id).is_empty());
+            assert!(c.brokers[id].committed(KEY).is_none());
+        }
+        assert_eq!(c.send(&leader, 3), 3);
+        c.run(Duration::from_millis(100));
+        for id in &replicas {
+            assert_eq!(c.values(id), vec![3], "log of {}", id);
+        }
+        assert_eq!(c.polled(&leader), vec![3]);
+    }
+
+    #[test]
+    fn commits_hold_once_the_isr_copied_them() {
+        let mut c = Cluster::new(3);
+        let replicas = c.replicas();
+        let (leader, lagging) = (replicas[0].clone(), replicas[2].clone());
+        c.run(Duration::from_millis(100));
+        c.down.insert(lagging.clone());
+        let now = c.now;
+        let (_, version) = c
+            .broker(&leader)
+            .commit(KEY, "commit:k".to_string(), 2, now)
+            .unwrap();
+        assert!(!c.brokers[&leader].commit_replicated(KEY, version));
+
+        // one follower copying it is not enough while the other is in the ISR
+        c.run(Duration::from_millis(100));
+        assert!(!c.brokers[&leader].commit_replicated(KEY, version));
+        assert_eq!(
+            c.brokers[&replicas[1]].committed(KEY).unwrap()["commit:k"],
+            2
+        );
+
+        c.run(DEFAULT_LAG_TIMEOUT);
+        assert!(c.brokers[&leader].commit_replicated(KEY, version));
+
+        // only the leader confirms commits
+        assert!(!c.brokers[&replicas[1]].commit_replicated(KEY, version));
+    }
+}
@@ -0,0 +1,588 @@
+//! State-based CRDTs shared by the nodes.
+//!
+//! Every type is a join-semilattice: `merge` is commutative, associative and
+//! idempotent, so replicas can exchange full states or deltas in any order and
+//! still converge. Mutators return the delta they produced, which callers can
+//! ship to peers instead of the whole state.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+pub trait Crdt: Clone + Default + Serialize + DeserializeOwned {
+    type Value;
+
+    /// Joins `other` into `self`.
+    fn merge(&mut self, other: &Self);
+
+    /// The value observed by clients.
+    fn value(&self) -> Self::Value;
+
+    /// Returns the smallest state that, merged into `since`, yields the same
+    /// result as merging `self` into it.
+    fn delta(&self, since: &Self) -> Self;
+
+    /// Whether the state carries no information, i.e. merging it is a no-op.
+    fn is_empty(&self) -> bool;
+}
+
+/// Grow-only counter: one monotonic count per node, summed on read.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GCounter {
+    counts: BTreeMap<String, usize>,
+}
+
+impl GCounter {
+    pub fn increment(&mut self, node: &str, delta: usize) -> Self {
+        let count = self.counts.entry(node.to_string()).or_insert(0);
+        *count += delta;
+        Self {
+            counts: BTreeMap::from([(node.to_string(), *count)]),
+        }
+    }
+
+    pub fn get(&self, node: &str) -> usize {
+        self.counts.get(node).copied().unwrap_or(0)
+    }
+}
+
+impl Crdt for GCounter {
+    type Value = usize;
+
+    fn merge(&mut self, other: &Self) {
+        for (node, count) in &other.counts {
+            let c = self.counts.entry(node.clone()).or_insert(0);
+            *c = (*c).max(*count);
+        }
+    }
+
+    fn value(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        Self {
+            counts: self
+                .counts
+                .iter()
+                .filter(|(node, count)| since.counts.get(*node).is_none_or(|c| c < count))
+                .map(|(node, count)| (node.clone(), *count))
+                .collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+}
+
+/// Counter supporting decrements, built from two grow-only counters.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PNCounter {
+    p: GCounter,
+    n: GCounter,
+}
+
+impl PNCounter {
+    pub fn increment(&mut self, node: &str, delta: usize) -> Self {
+        Self {
+            p: self.p.increment(node, delta),
+            n: GCounter::default(),
+        }
+    }
+
+    pub fn decrement(&mut self, node: &str, delta: usize) -> Self {
+        Self {
+            p: GCounter::default(),
+            n: self.n.increment(node, delta),
+        }
+    }
+}
+
+impl Crdt for PNCounter {
+    type Value = i64;
+
+    fn merge(&mut self, other: &Self) {
+        self.p.merge(&other.p);
+        self.n.merge(&other.n);
+    }
+
+    fn value(&self) -> i64 {
+        self.p.value() as i64 - self.n.value() as i64
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        Self {
+            p: self.p.delta(&since.p),
+            n: self.n.delta(&since.n),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.p.is_empty() && self.n.is_empty()
+    }
+}
+
+/// Grow-only set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound(deserialize = "T: Ord + Deserialize<'de>"))]
+pub struct GSet<T: Ord> {
+    elements: BTreeSet<T>,
+}
+
+impl<T: Ord> Default for GSet<T> {
+    fn default() -> Self {
+        Self {
+            elements: BTreeSet::new(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> GSet<T> {
+    /// Inserts `value`, returning an empty delta if it was already present.
+    pub fn insert(&mut self, value: T) -> Self {
+        let mut delta = Self::default();
+        if self.elements.insert(value.clone()) {
+            delta.elements.insert(value);
+        }
+        delta
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.elements.contains(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.elements.iter()
+    }
+}
+
+impl<T> Crdt for GSet<T>
+where
+    T: Ord + Clone + Serialize + DeserializeOwned,
+{
+    type Value = BTreeSet<T>;
+
+    fn merge(&mut self, other: &Self) {
+        self.elements.extend(other.elements.iter().cloned());
+    }
+
+    fn value(&self) -> BTreeSet<T> {
+        self.elements.clone()
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        Self {
+            elements: self.elements.difference(&since.elements).cloned().collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+}
+
+/// Two-phase set: an element can be added and removed, but never re-added.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound(deserialize = "T: Ord + Deserialize<'de>"))]
+pub struct TwoPSet<T: Ord> {
+    added: GSet<T>,
+    removed: GSet<T>,
+}
+
+impl<T: Ord> Default for TwoPSet<T> {
+    fn default() -> Self {
+        Self {
+            added: GSet::default(),
+            removed: GSet::default(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> TwoPSet<T> {
+    pub fn insert(&mut self, value: T) -> Self {
+        Self {
+            added: self.added.insert(value),
+            removed: GSet::default(),
+        }
+    }
+
+    /// Removes `value`. Removing an element that was never added is a no-op,
+    /// otherwise it would be impossible to add it later.
+    pub fn remove(&mut self, value: T) -> Self {
+        if !self.added.contains(&value) {
+            return Self::default();
+        }
+        Self {
+            added: GSet::default(),
+            removed: self.removed.insert(value),
+        }
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.added.contains(value) && !self.removed.contains(value)
+    }
+}
+
+impl<T> Crdt for TwoPSet<T>
+where
+    T: Ord + Clone + Serialize + DeserializeOwned,
+{
+    type Value = BTreeSet<T>;
+
+    fn merge(&mut self, other: &Self) {
+        self.added.merge(&other.added);
+        self.removed.merge(&other.removed);
+    }
+
+    fn value(&self) -> BTreeSet<T> {
+        self.added
+            .elements
+            .difference(&self.removed.elements)
+            .cloned()
+            .collect()
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        Self {
+            added: self.added.delta(&since.added),
+            removed: self.removed.delta(&since.removed),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Unique tag of a single add operation in an [`ORSet`].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Dot {
+    pub node: String,
+    pub counter: u64,
+}
+
+/// Observed-remove set with add-wins semantics: a remove only cancels the adds
+/// it has seen, so a concurrent add survives it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound(deserialize = "T: Ord + Deserialize<'de>"))]
+pub struct ORSet<T: Ord> {
+    adds: BTreeSet<(T, Dot)>,
+    removes: BTreeSet<Dot>,
+    clock: BTreeMap<String, u64>,
+}
+
+impl<T: Ord> Default for ORSet<T> {
+    fn default() -> Self {
+        Self {
+            adds: BTreeSet::new(),
+            removes: BTreeSet::new(),
+            clock: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T: Ord + Clone> ORSet<T> {
+    pub fn insert(&mut self, node: &str, value: T) -> Self {
+        let counter = self.clock.entry(node.to_string()).or_insert(0);
+        *counter += 1;
+        let dot = Dot {
+            node: node.to_string(),
+            counter: *counter,
+        };
+        self.adds.insert((value.clone(), dot.clone()));
+
+        let mut delta = Self::default();
+        delta.clock.insert(dot.node.clone(), dot.counter);
+        delta.adds.insert((value, dot));
+        delta
+    }
+
+    pub fn remove(&mut self, value: &T) -> Self {
+        let mut delta = Self::default();
+        for (v, dot) in &self.adds {
+            if v == value && !self.removes.contains(dot) {
+                delta.removes.insert(dot.clone());
+            }
+        }
+        self.removes.extend(delta.removes.iter().cloned());
+        delta
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.adds
+            .iter()
+            .any(|(v, dot)| v == value && !self.removes.contains(dot))
+    }
+}
+
+impl<T> Crdt for ORSet<T>
+where
+    T: Ord + Clone + Serialize + DeserializeOwned,
+{
+    type Value = BTreeSet<T>;
+
+    fn merge(&mut self, other: &Self) {
+        self.adds.extend(other.adds.iter().cloned());
+        self.removes.extend(other.removes.iter().cloned());
+        for (node, counter) in &other.clock {
+            let c = self.clock.entry(node.clone()).or_insert(0);
+            *c = (*c).max(*counter);
+        }
+    }
+
+    fn value(&self) -> BTreeSet<T> {
+        self.adds
+            .iter()
+            .filter(|(_, dot)| !self.removes.contains(dot))
+            .map(|(v, _)| v.clone())
+            .collect()
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        Self {
+            adds: self.adds.difference(&since.adds).cloned().collect(),
+            removes: self.removes.difference(&since.removes).cloned().collect(),
+            clock: self
+                .clock
+                .iter()
+                .filter(|(node, counter)| since.clock.get(*node).is_none_or(|c| c < counter))
+                .map(|(node, counter)| (node.clone(), *counter))
+                .collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.adds.is_empty() && self.removes.is_empty() && self.clock.is_empty()
+    }
+}
+
+/// Last-writer-wins register. Writes are ordered by timestamp, then by node id,
+/// then by value so that merge stays deterministic even on exact ties.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LwwRegister<T> {
+    timestamp: u64,
+    node: String,
+    value: Option<T>,
+}
+
+impl<T> Default for LwwRegister<T> {
+    fn default() -> Self {
+        Self {
+            timestamp: 0,
+            node: String::new(),
+            value: None,
+        }
+    }
+}
+
+impl<T: Ord + Clone> LwwRegister<T> {
+    pub fn set(&mut self, node: &str, timestamp: u64, value: T) -> Self {
+        let write = Self {
+            timestamp,
+            node: node.to_string(),
+            value: Some(value),
+        };
+        if write.key() > self.key() {
+            *self = write.clone();
+            write
+        } else {
+            Self::default()
+        }
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    fn key(&self) -> (u64, &str, Option<&T>) {
+        (self.timestamp, self.node.as_str(), self.value.as_ref())
+    }
+}
+
+impl<T> Crdt for LwwRegister<T>
+where
+    T: Ord + Clone + Serialize + DeserializeOwned,
+{
+    type Value = Option<T>;
+
+    fn merge(&mut self, other: &Self) {
+        if other.key() > self.key() {
+            *self = other.clone();
+        }
+    }
+
+    fn value(&self) -> Option<T> {
+        self.value.clone()
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        if self.key() > since.key() {
+            self.clone()
+        } else {
+            Self::default()
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.value.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const NODES: [&str; 3] = ["n0", "n1", "n2"];
+
+    fn node() -> impl Strategy<Value = &'static str> {
+        prop::sample::select(&NODES[..])
+    }
+
+    fn gcounter() -> impl Strategy<Value = GCounter> {
+        prop::collection::vec((node(), 0..10usize), 0..8).prop_map(|ops| {
+            let mut c = GCounter::default();
+            for (n, d) in ops {
+                c.increment(n, d);
+            }
+            c
+        })
+    }
+
+    fn pncounter() -> impl Strategy<Value = PNCounter> {
+        prop::collection::vec((node(), 0..10usize, any::<bool>()), 0..8).prop_map(|ops| {
+            let mut c = PNCounter::default();
+            for (n, d, inc) in ops {
+                if inc {
+                    c.increment(n, d);
+                } else {
+                    c.decrement(n, d);
+                }
+            }
+            c
+        })
+    }
+
+    fn gset() -> impl Strategy<Value = GSet<u8>> {
+        prop::collection::vec(0..16u8, 0..8).prop_map(|ops| {
+            let mut s = GSet::default();
+            for v in ops {
+                s.insert(v);
+            }
+            s
+        })
+    }
+
+    fn twopset() -> impl Strategy<Value = TwoPSet<u8>> {
+        prop::collection::vec((0..16u8, any::<bool>()), 0..8).prop_map(|ops| {
+            let mut s = TwoPSet::default();
+            for (v, add) in ops {
+                if add {
+                    s.insert(v);
+                } else {
+                    s.remove(v);
+                }
+            }
+            s
+        })
+    }
+
+    // Each replica only issues dots for its own node id, as in a real cluster.
+    fn orset() -> impl Strategy<Value = ORSet<u8>> {
+        (node(), prop::collection::vec((0..8u8, any::<bool>()), 0..8)).prop_map(|(n, ops)| {
+            let mut s = ORSet::default();
+            for (v, add) in ops {
+                if add {
+                    s.insert(n, v);
+                } else {
+                    s.remove(&v);
+                }
+            }
+            s
+        })
+    }
+
+    fn lww() -> impl Strategy<Value = LwwRegister<u8>> {
+        prop::collection::vec((node(), 0..5u64, any::<u8>()), 0..4).prop_map(|ops| {
+            let mut r = LwwRegister::default();
+            for (n, ts, v) in ops {
+                r.set(n, ts, v);
+            }
+            r
+        })
+    }
+
+    fn joined<C: Crdt>(a: &C, b: &C) -> C {
+        let mut out = a.clone();
+        out.merge(b);
+        out
+    }
+
+    fn check_laws<C: Crdt + PartialEq + std::fmt::Debug>(
+        a: C,
+        b: C,
+        c: C,
+    ) -> Result<(), TestCaseError> {
+        prop_assert_eq!(joined(&a, &b), joined(&b, &a), "commutativity");
+        prop_assert_eq!(
+            joined(&joined(&a, &b), &c),
+            joined(&a, &joined(&b, &c)),
+            "associativity"
+        );
+        prop_assert_eq!(joined(&a, &a), a.clone(), "idempotence");
+        prop_assert_eq!(joined(&b, &a.delta(&b)), joined(&b, &a), "delta");
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn gcounter_laws(a in gcounter(), b in gcounter(), c in gcounter()) {
+            check_laws(a, b, c)?;
+        }
+
+        #[test]
+        fn pncounter_laws(a in pncounter(), b in pncounter(), c in pncounter()) {
+            check_laws(a, b, c)?;
+        }
+
+        #[test]
+        fn gset_laws(a in gset(), b in gset(), c in gset()) {
+            check_laws(a, b, c)?;
+        }
+
+        #[test]
+        fn twopset_laws(a in twopset(), b in twopset(), c in twopset()) {
+            check_laws(a, b, c)?;
+        }
+
+        #[test]
+        fn orset_laws(a in orset(), b in orset(), c in orset()) {
+            check_laws(a, b, c)?;
+        }
+
+        #[test]
+        fn lww_laws(a in lww(), b in lww(), c in lww()) {
+            check_laws(a, b, c)?;
+        }
+    }
+
+    #[test]
+    fn orset_add_wins_over_concurrent_remove() {
+        let mut a = ORSet::default();
+        a.insert("n0", 1u8);
+        let mut b = a.clone();
+        b.remove(&1);
+        a.insert("n0", 1);
+        a.merge(&b);
+        assert!(a.contains(&1));
+    }
+
+    #[test]
+    fn twopset_remove_is_final() {
+        let mut s = TwoPSet::default();
+        s.insert(1u8);
+        s.remove(1);
+        s.insert(1);
+        assert!(!s.contains(&1));
+    }
+}
@@ -0,0 +1,221 @@
+//! Phi-accrual failure detector.
+//!
+//! Any message from a peer counts as a heartbeat, so nodes only need explicit
+//! pings towards peers they have not sent anything to recently. Suspicion is
+//! the phi value of Hayashibara et al.: `-log10` of the probability that a
+//! heartbeat would still arrive this late, given the observed inter-arrival
+//! times. A peer is considered down once phi crosses the threshold.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_THRESHOLD: f64 = 8.0;
+const WINDOW: usize = 100;
+const MIN_STD_DEV_MS: f64 = 100.0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MembershipEvent {
+    Up(String),
+    Down(String),
+}
+
+#[derive(Debug)]
+struct History {
+    intervals: VecDeque<f64>,
+    last_heartbeat: Instant,
+    last_sent: Option<Instant>,
+    alive: bool,
+}
+
+impl History {
+    fn new(now: Instant, expected: Duration) -> Self {
+        // seed with the expected interval so the first few checks are not
+        // computed from a single sample
+        let ms = expected.as_secs_f64() * 1000.0;
+        Self {
+            intervals: VecDeque::from([ms - ms / 4.0, ms + ms / 4.0]),
+            last_heartbeat: now,
+            last_sent: None,
+            alive: true,
+        }
+    }
+
+    fn phi(&self, now: Instant) -> f64 {
+        let n = self.intervals.len() as f64;
+        let mean = self.intervals.iter().sum::<f64>() / n;
+        let variance = self
+            .intervals
+            .iter()
+            .map(|i| (i - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        let std_dev = variance.sqrt().max(MIN_STD_DEV_MS);
+
+        let elapsed = now.duration_since(self.last_heartbeat).as_secs_f64() * 1000.0;
+        // logistic approximation of the normal CDF, as used by Akka and Cassandra
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        if elapsed > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FailureDetector {
+    threshold: f64,
+    interval: Duration,
+    peers: HashMap<String, History>,
+}
+
+impl FailureDetector {
+    /// `interval` is how often peers are expected to hear from each other.
+    pub fn new(interval: Duration, threshold: f64) -> Self {
+        Self {
+            threshold,
+            interval,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Replaces the monitored peers. Peers that were already known keep their
+    /// history; new ones start out alive.
+    pub fn set_peers(&mut self, peers: impl IntoIterator<Item = String>) {
+        let now = Instant::now();
+        let mut old = std::mem::take(&mut self.peers);
+        for peer in peers {
+            let history = old
+                .remove(&peer)
+                .unwrap_or_else(|| History::new(now, self.interval));
+            self.peers.insert(peer, history);
+        }
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &String> {
+        self.peers.keys()
+    }
+
+    pub fn heartbeat(&mut self, node: &str) -> Option<MembershipEvent> {
+        self.heartbeat_at(node, Instant::now())
+    }
+
+    /// Records that `node` was heard from. Returns `Up` if it was suspected.
+    pub fn heartbeat_at(&mut self, node: &str, now: Instant) -> Option<MembershipEvent> {
+        let history = self.peers.get_mut(node)?;
+        let interval = now.duration_since(history.last_heartbeat).as_secs_f64() * 1000.0;
+        if history.intervals.len() == WINDOW {
+            history.intervals.pop_front();
+        }
+        history.intervals.push_back(interval);
+        history.last_heartbeat = now;
+        if history.alive {
+            return None;
+        }
+        history.alive = true;
+        Some(MembershipEvent::Up(node.to_string()))
+    }
+
+    /// Records that something was sent to `node`, which doubles as a heartbeat.
+    pub fn sent(&mut self, node: &str) {
+        if let Some(history) = self.peers.get_mut(node) {
+            history.last_sent = Some(Instant::now());
+        }
+    }
+
+    pub fn phi(&self, node: &str) -> f64 {
+        self.peers
+            .get(node)
+            .map_or(f64::INFINITY, |h| h.phi(Instant::now()))
+    }
+
+    pub fn is_alive(&self, node: &str) -> bool {
+        self.peers.get(node).is_some_and(|h| h.alive)
+    }
+
+    pub fn check(&mut self) -> Vec<MembershipEvent> {
+        self.check_at(Instant::now())
+    }
+
+    /// Re-evaluates every peer and returns the ones that just went down.
+    pub fn check_at(&mut self, now: Instant) -> Vec<MembershipEvent> {
+        let mut events = Vec::new();
+        for (node, history) in self.peers.iter_mut() {
+            if history.alive && history.phi(now) > self.threshold {
+                history.alive = false;
+                events.push(MembershipEvent::Down(node.clone()));
+            }
+        }
+        events
+    }
+
+    /// Peers nothing was sent to during the last interval. Suspected peers are
+    /// included so they can notice us again once they are reachable.
+    pub fn due_pings(&self) -> Vec<String> {
+        let now = Instant::now();
+        self.peers
+            .iter()
+            .filter(|(_, h)| {
+                h.last_sent
+                    .is_none_or(|sent| now.duration_since(sent) >= self.interval)
+            })
+            .map(|(node, _)| node.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_millis(500);
+
+    fn detector() -> (FailureDetector, Instant) {
+        let mut fd = FailureDetector::new(INTERVAL, DEFAULT_THRESHOLD);
+        fd.set_peers(["n1".to_string()]);
+        let start = fd.peers["n1"].last_heartbeat;
+        (fd, start)
+    }
+
+    #[test]
+    fn regular_heartbeats_keep_peer_alive() {
+        let (mut fd, start) = detector();
+        for i in 1..=20 {
+            fd.heartbeat_at("n1", start + INTERVAL * i);
+        }
+        let now = start + INTERVAL * 20 + INTERVAL / 2;
+        assert!(fd.check_at(now).is_empty());
+        assert!(fd.is_alive("n1"));
+    }
+
+    #[test]
+    fn silence_raises_suspicion_and_heartbeat_clears_it() {
+        let (mut fd, start) = detector();
+        for i in 1..=20 {
+            fd.heartbeat_at("n1", start + INTERVAL * i);
+        }
+        let last = start + INTERVAL * 20;
+        assert!(fd.peers["n1"].phi(last + INTERVAL * 2) < fd.peers["n1"].phi(last + INTERVAL * 4));
+
+        assert_eq!(
+            fd.check_at(last + INTERVAL * 10),
+            vec![MembershipEvent::Down("n1".to_string())]
+        );
+        assert!(!fd.is_alive("n1"));
+        assert_eq!(
+            fd.heartbeat_at("n1", last + INTERVAL * 11),
+            Some(MembershipEvent::Up("n1".to_string()))
+        );
+        assert!(fd.is_alive("n1"));
+    }
+
+    #[test]
+    fn unknown_nodes_are_ignored() {
+        let (mut fd, _) = detector();
+        assert_eq!(fd.heartbeat("c1"), None);
+        assert!(!fd.is_alive("c1"));
+    }
+}
@@ -0,0 +1,190 @@
+//! Delta-state gossip for [`Crdt`] values.
+//!
+//! Local updates are batched into one delta per flush interval and tagged with
+//! a sequence number. Each peer acknowledges the highest sequence it merged, so
+//! a flush only sends the join of the deltas a peer has not acknowledged yet.
+//! Deltas acknowledged by every peer are dropped; once a peer falls behind the
+//! retained buffer (e.g. it was partitioned away) it is sent the full state.
+
+use crate::crdt::Crdt;
+use std::collections::{HashMap, VecDeque};
+
+/// A message the node should send to `peer` and expect a `seq` ack for.
+#[derive(Debug, Clone)]
+pub struct Outgoing<C> {
+    pub peer: String,
+    pub seq: u64,
+    pub delta: C,
+    pub full: bool,
+}
+
+#[derive(Debug)]
+pub struct DeltaGossip<C: Crdt> {
+    state: C,
+    pending: C,
+    deltas: VecDeque<(u64, C)>,
+    seq: u64,
+    acked: HashMap<String, u64>,
+    max_buffered: usize,
+}
+
+impl<C: Crdt> DeltaGossip<C> {
+    pub fn new(max_buffered: usize) -> Self {
+        Self {
+            state: C::default(),
+            pending: C::default(),
+            deltas: VecDeque::new(),
+            seq: 0,
+            acked: HashMap::new(),
+            max_buffered,
+        }
+    }
+
+    /// Replaces the peer set. Peers that were already known keep their acks.
+    pub fn set_peers(&mut self, peers: impl IntoIterator<Item = String>) {
+        let mut acked = HashMap::new();
+        for peer in peers {
+            let seq = self.acked.get(&peer).copied().unwrap_or(0);
+            acked.insert(peer, seq);
+        }
+        self.acked = acked;
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &String> {
+        self.acked.keys()
+    }
+
+    pub fn state(&self) -> &C {
+        &self.state
+    }
+
+    /// Applies a local mutation. `f` returns the delta it produced.
+    pub fn update(&mut self, f: impl FnOnce(&mut C) -> C) {
+        let delta = f(&mut self.state);
+        self.pending.merge(&delta);
+    }
+
+    /// Merges a delta received from a peer. Anything new to us is queued so it
+    /// keeps spreading to peers that are not directly connected to the sender.
+    pub fn receive(&mut self, delta: &C) {
+        let new = delta.delta(&self.state);
+        if !new.is_empty() {
+            self.state.merge(&new);
+            self.pending.merge(&new);
+        }
+    }
+
+    pub fn ack(&mut self, peer: &str, seq: u64) {
+        if let Some(acked) = self.acked.get_mut(peer) {
+            *acked = (*acked).max(seq);
+        }
+        let min = self.acked.values().copied().min().unwrap_or(self.seq);
+        while self.deltas.front().is_some_and(|(s, _)| *s <= min) {
+            self.deltas.pop_front();
+        }
+    }
+
+    /// Closes the current batch and returns what every lagging peer needs.
+    pub fn flush(&mut self) -> Vec<Outgoing<C>> {
+        if !self.pending.is_empty() {
+            self.seq += 1;
+            let batch = std::mem::take(&mut self.pending);
+            self.deltas.push_back((self.seq, batch));
+            if self.deltas.len() > self.max_buffered {
+                self.deltas.pop_front();
+            }
+        }
+
+        let mut out = Vec::new();
+        for (peer, acked) in &self.acked {
+            if *acked >= self.seq {
+                continue;
+            }
+            let behind = self.deltas.front().is_none_or(|(s, _)| *s > acked + 1);
+            let delta = if behind {
+                self.state.clone()
+            } else {
+                let mut delta = C::default();
+                for (_, d) in self.deltas.iter().filter(|(s, _)| s > acked) {
+                    delta.merge(d);
+                }
+                delta
+            };
+            out.push(Outgoing {
+                peer: peer.clone(),
+                seq: self.seq,
+                delta,
+                full: behind,
+            });
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crdt::GSet;
+
+    fn engine(peers: &[&str]) -> DeltaGossip<GSet<usize>> {
+        let mut g = DeltaGossip::new(4);
+        g.set_peers(peers.iter().map(|p| p.to_string()));
+        g
+    }
+
+    #[test]
+    fn sends_only_unacked_deltas() {
+        let mut g = engine(&["n1"]);
+        g.update(|s| s.insert(1));
+        let out = g.flush();
+        assert_eq!(out.len(), 1);
+        g.ack("n1", out[0].seq);
+
+        g.update(|s| s.insert(2));
+        let out = g.flush();
+        assert!(!out[0].full);
+        assert_eq!(out[0].delta.iter().copied().collect::<Vec<_>>(), vec![2]);
+
+        g.ack("n1", out[0].seq);
+        assert!(g.flush().is_empty());
+    }
+
+    #[test]
+    fn resends_until_acked() {
+        let mut g = engine(&["n1"]);
+        g.update(|s| s.insert(1));
+        g.flush();
+        g.update(|s| s.insert(2));
+        let out = g.flush();
+        assert_eq!(out[0].delta.iter().count(), 2);
+    }
+
+    #[test]
+    fn falls_back_to_full_state_when_peer_is_behind() {
+        let mut g = engine(&["n1", "n2"]);
+        for i in 0..6 {
+            g.update(|s| s.insert(i));
+            for o in g.flush() {
+                if o.peer == "n2" {
+                    g.ack("n2", o.seq);
+                }
+            }
+        }
+        let out: Vec<_> = g.flush().into_iter().filter(|o| o.peer == "n1").collect();
+        assert!(out[0].full);
+        assert_eq!(out[0].delta.iter().count(), 6);
+    }
+
+    #[test]
+    fn forwards_received_deltas() {
+        let mut a = engine(&["n1"]);
+        let mut b = engine(&["n2"]);
+        a.update(|s| s.insert(7));
+        for o in a.flush() {
+            b.receive(&o.delta);
+        }
+        let out = b.flush();
+        assert_eq!(out[0].peer, "n2");
+        assert!(out[0].delta.contains(&7));
+    }
+}
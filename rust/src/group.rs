@@ -0,0 +1,216 @@
+//! Consumer groups: membership and key assignment.
+//!
+//! Each member subscribes to a set of keys; every subscribed key is assigned
+//! to exactly one member of the group, balancing the number of keys per
+//! member. Any change in membership (join, leave, or a member missing its
+//! session timeout) bumps the group's generation and reassigns all keys.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Assignment {
+    pub generation: u64,
+    pub keys: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Member {
+    keys: BTreeSet<String>,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Group {
+    generation: u64,
+    members: BTreeMap<String, Member>,
+    assignment: HashMap<String, Vec<String>>,
+}
+
+impl Group {
+    fn rebalance(&mut self) {
+        self.generation += 1;
+        self.assignment = self.members.keys().map(|m| (m.clone(), vec![])).collect();
+        let keys: BTreeSet<&String> = self.members.values().flat_map(|m| &m.keys).collect();
+        for key in keys {
+            // the subscribed member with the fewest keys so far, by name on ties
+            let member = self
+                .members
+                .iter()
+                .filter(|(_, m)| m.keys.contains(key))
+                .map(|(name, _)| name)
+                .min_by_key(|name| self.assignment[*name].len())
+                .expect("some member subscribed to the key");
+            self.assignment.get_mut(member).unwrap().push(key.clone());
+        }
+    }
+
+    fn assignment_of(&self, consumer: &str) -> Assignment {
+        Assignment {
+            generation: self.generation,
+            keys: self.assignment.get(consumer).cloned().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConsumerGroups {
+    session_timeout: Duration,
+    groups: HashMap<String, Group>,
+}
+
+impl Default for ConsumerGroups {
+    fn default() -> Self {
+        Self::new(DEFAULT_SESSION_TIMEOUT)
+    }
+}
+
+impl ConsumerGroups {
+    pub fn new(session_timeout: Duration) -> Self {
+        Self {
+            session_timeout,
+            groups: HashMap::new(),
+        }
+    }
+
+    /// Adds `consumer` to `group`, or replaces its subscription, and
+    /// rebalances the group.
+    pub fn join(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        keys: impl IntoIterator<Item = String>,
+        now: Instant,
+    ) -> Assignment {
+        self.expire(now);
+        let g = self.groups.entry(group.to_string()).or_default();
+        g.members.insert(
+            consumer.to_string(),
+            Member {
+                keys: keys.into_iter().collect(),
+                last_seen: now,
+            },
+        );
+        g.rebalance();
+        g.assignment_of(consumer)
+    }
+
+    /// Keeps `consumer`'s session alive and returns its current assignment.
+    /// A consumer that is not a member yet joins with `keys`.
+    pub fn heartbeat(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        keys: impl IntoIterator<Item = String>,
+        now: Instant,
+    ) -> Assignment {
+        self.expire(now);
+        let member = self
+            .groups
+            .get_mut(group)
+            .and_then(|g| g.members.get_mut(consumer));
+        match member {
+            Some(member) => {
+                member.last_seen = now;
+                self.groups[group].assignment_of(consumer)
+            }
+            None => self.join(group, consumer, keys, now),
+        }
+    }
+
+    pub fn leave(&mut self, group: &str, consumer: &str, now: Instant) {
+        self.expire(now);
+        if let Some(g) = self.groups.get_mut(group) {
+            if g.members.remove(consumer).is_some() {
+                g.rebalance();
+            }
+        }
+    }
+
+    pub fn members(&self, group: &str) -> Vec<String> {
+        self.groups
+            .get(group)
+            .map(|g| g.members.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Drops members that missed their session timeout, rebalancing the
+    /// groups they were in.
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.session_timeout;
+        for g in self.groups.values_mut() {
+            let before = g.members.len();
+            g.members
+                .retain(|_, m| now.duration_since(m.last_seen) < timeout);
+            if g.members.len() != before {
+                g.rebalance();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(ks: &[&str]) -> Vec<String> {
+        ks.iter().map(|k| k.to_string()).collect()
+    }
+
+    #[test]
+    fn splits_keys_between_members_and_rebalances_on_leave() {
+        let mut groups = ConsumerGroups::default();
+        let now = Instant::now();
+        let all = keys(&["a", "b", "c", "d"]);
+        groups.join("g", "c1", all.clone(), now);
+        let a2 = groups.join("g", "c2", all.clone(), now);
+        let a1 = groups.heartbeat("g", "c1", vec![], now);
+        assert_eq!(a1.generation, 2);
+        assert_eq!(a1.keys.len(), 2);
+        assert_eq!(a2.keys.len(), 2);
+        assert!(a1.keys.iter().all(|k| !a2.keys.contains(k)));
+
+        groups.leave("g", "c2", now);
+        let a1 = groups.heartbeat("g", "c1", vec![], now);
+        assert_eq!(
+            a1,
+            Assignment {
+                generation: 3,
+                keys: all
+            }
+        );
+    }
+
+    #[test]
+    fn only_assigns_subscribed_keys() {
+        let mut groups = ConsumerGroups::default();
+        let now = Instant::now();
+        groups.join("g", "c1", keys(&["a"]), now);
+        let a2 = groups.join("g", "c2", keys(&["a", "b"]), now);
+        assert_eq!(a2.keys, keys(&["b"]));
+        assert_eq!(groups.heartbeat("g", "c1", vec![], now).keys, keys(&["a"]));
+    }
+
+    #[test]
+    fn silent_members_expire() {
+        let mut groups = ConsumerGroups::new(Duration::from_secs(1));
+        let now = Instant::now();
+        groups.join("g", "c1", keys(&["a", "b"]), now);
+        groups.join("g", "c2", keys(&["a", "b"]), now);
+        let later = now + Duration::from_millis(1500);
+        let a1 = groups.heartbeat("g", "c1", vec![], now + Duration::from_millis(800));
+        assert_eq!(a1.keys.len(), 1);
+        let a1 = groups.heartbeat("g", "c1", vec![], later);
+        assert_eq!(a1.keys, keys(&["a", "b"]));
+        assert_eq!(groups.members("g"), keys(&["c1"]));
+
+        // separate groups keep separate membership
+        groups.join("other", "c2", keys(&["a"]), later);
+        assert_eq!(groups.members("other"), keys(&["c2"]));
+    }
+}
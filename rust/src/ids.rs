@@ -0,0 +1,331 @@
+//! Unique ids that stay unique across restarts.
+//!
+//! A generator hands out ids from a block reserved ahead of time. The end of
+//! a block is made durable before any id in it is handed out, so a node that
+//! crashes and comes back starts after every id it may have used, skipping
+//! whatever was left of its last block. Handing out ids within a block is a
+//! single locked increment, so concurrent requests never share one.
+//!
+//! [`Snowflake`] ids need no reservation at all: they pack the time, the
+//! node and a per-millisecond sequence into a single `u64`, so they sort
+//! roughly by creation time across the whole cluster.
+
+use anyhow::Context;
+use std::{
+    fs::{self, File},
+    io::Write,
+    ops::Range,
+    path::PathBuf,
+    sync::Mutex,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const DEFAULT_BLOCK_SIZE: u64 = 1000;
+
+/// Durably reserves ranges of ids.
+pub trait Reserve {
+    /// Reserves `n` ids and returns the first of them. No id in the range is
+    /// ever returned again, even after a restart.
+    fn reserve(&self, n: u64) -> anyhow::Result<u64>;
+}
+
+/// Keeps the highest id reserved so far in a file. Only one generator may
+/// use a file at a time.
+#[derive(Debug)]
+pub struct FileHighWater {
+    path: PathBuf,
+}
+
+impl FileHighWater {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Reserve for FileHighWater {
+    fn reserve(&self, n: u64) -> anyhow::Result<u64> {
+        let current: u64 = match fs::read_to_string(&self.path) {
+            Ok(s) => s
+                .trim()
+                .parse()
+                .with_context(|| format!("corrupt high-water mark in {}", self.path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e).context("read high-water mark"),
+        };
+        // write the new mark aside and rename it over the old one, so a
+        // crash leaves one or the other
+        let tmp = self.path.with_extension("tmp");
+        let mut out = File::create(&tmp).context("create high-water mark")?;
+        write!(out, "{}", current + n).context("write high-water mark")?;
+        out.sync_all().context("sync high-water mark")?;
+        fs::rename(&tmp, &self.path).context("replace high-water mark")?;
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            File::open(dir)
+                .and_then(|d| d.sync_all())
+                .context("sync high-water mark directory")?;
+        }
+        Ok(current + 1)
+    }
+}
+
+pub struct IdGenerator {
+    reserve: Box<dyn Reserve + Send + Sync>,
+    block_size: u64,
+    block: Mutex<Range<u64>>,
+}
+
+impl IdGenerator {
+    pub fn new(reserve: impl Reserve + Send + Sync + 'static, block_size: u64) -> Self {
+        Self {
+            reserve: Box::new(reserve),
+            block_size: block_size.max(1),
+            block: Mutex::new(0..0),
+        }
+    }
+
+    /// The next id, reserving a new block first when the current one is used
+    /// up. Callers wait while a block is being reserved.
+    pub fn next(&self) -> anyhow::Result<u64> {
+        let mut block = self.block.lock().unwrap();
+        if block.is_empty() {
+            let start = self.reserve.reserve(self.block_size)?;
+            *block = start..start + self.block_size;
+        }
+        Ok(block.next().expect("block is not empty"))
+    }
+}
+
+/// Snowflake timestamps count milliseconds from 2024-01-01T00:00:00Z, which
+/// leaves room for 69 years of ids in 41 bits.
+pub const SNOWFLAKE_EPOCH_MS: u64 = 1_704_067_200_000;
+const TIMESTAMP_BITS: u32 = 41;
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+pub const MAX_NODES: usize = 1 << NODE_BITS;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+/// How far behind the last id the clock may fall before a generator that
+/// has used up its sequence gives up instead of waiting for it.
+const MAX_REGRESSION_MS: u64 = 1000;
+
+/// 64-bit ids laid out as a zero sign bit, 41 bits of milliseconds since
+/// [`SNOWFLAKE_EPOCH_MS`], 10 bits of node index and 12 bits of sequence.
+///
+/// When the clock goes backwards the generator keeps counting in the last
+/// millisecond it saw rather than reusing earlier ones, so ids never
+/// decrease. Once 4096 ids have been handed out in one millisecond, callers
+/// wait for the clock to move past it. Uniqueness across restarts relies on
+/// the clock not being set back by more than the time a restart takes.
+pub struct Snowflake {
+    node: u64,
+    clock: Box<dyn Fn() -> u64 + Send + Sync>,
+    /// The millisecond of the last id and the sequence used in it.
+    last: Mutex<(u64, u64)>,
+}
+
+impl Snowflake {
+    /// `node` is the index of this node in the cluster.
+    pub fn new(node: usize) -> anyhow::Result<Self> {
+        Self::with_clock(node, || {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64)
+        })
+    }
+
+    /// Like [`Snowflake::new`] with a clock reading milliseconds since the
+    /// Unix epoch.
+    pub fn with_clock(
+        node: usize,
+        clock: impl Fn() -> u64 + Send + Sync + 'static,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            node < MAX_NODES,
+            "node index {} does not fit in {} bits",
+            node,
+            NODE_BITS
+        );
+        Ok(Self {
+            node: node as u64,
+            clock: Box::new(clock),
+            last: Mutex::new((0, 0)),
+        })
+    }
+
+    pub fn next(&self) -> anyhow::Result<u64> {
+        let mut last = self.last.lock().unwrap();
+        let (ms, seq) = loop {
+            let now = (self.clock)().saturating_sub(SNOWFLAKE_EPOCH_MS);
+            let (ms, seq) = *last;
+            if now > ms {
+                break (now, 0);
+            }
+            if seq < MAX_SEQUENCE {
+                break (ms, seq + 1);
+            }
+            anyhow::ensure!(
+                ms - now <= MAX_REGRESSION_MS,
+                "clock is {}ms behind the last id",
+                ms - now
+            );
+            thread::sleep(Duration::from_millis((ms - now).max(1)));
+        };
+        anyhow::ensure!(
+            ms < 1 << TIMESTAMP_BITS,
+            "clock is past the end of the snowflake epoch"
+        );
+        *last = (ms, seq);
+        Ok(ms << (NODE_BITS + SEQUENCE_BITS) | self.node << SEQUENCE_BITS | seq)
+    }
+
+    /// Splits an id into its milliseconds since the Unix epoch, node index
+    /// and sequence.
+    pub fn split(id: u64) -> (u64, usize, u64) {
+        (
+            (id >> (NODE_BITS + SEQUENCE_BITS)) + SNOWFLAKE_EPOCH_MS,
+            ((id >> SEQUENCE_BITS) & (MAX_NODES as u64 - 1)) as usize,
+            id & MAX_SEQUENCE,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::HashSet,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+    };
+
+    /// Takes `per_thread` ids on each of `threads` threads at once and checks
+    /// them against every id seen before.
+    fn check_unique(
+        gen: &Arc<IdGenerator>,
+        threads: usize,
+        per_thread: usize,
+        seen: &mut HashSet<u64>,
+    ) {
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let gen = gen.clone();
+                thread::spawn(move || {
+                    (0..per_thread)
+                        .map(|_| gen.next().unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        for h in handles {
+            for id in h.join().unwrap() {
+                assert!(seen.insert(id), "id {} handed out twice", id);
+            }
+        }
+    }
+
+    #[test]
+    fn ids_are_unique_across_threads_and_restarts() {
+        let dir = std::env::temp_dir().join(format!("nazgul-ids-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("n1");
+        let mut seen = HashSet::new();
+        for _ in 0..3 {
+            // every round stops partway through a block, like a crash would
+            let gen = Arc::new(IdGenerator::new(FileHighWater::new(&path), 64));
+            check_unique(&gen, 8, 101, &mut seen);
+        }
+        assert_eq!(seen.len(), 3 * 8 * 101);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reservations_are_never_reused() {
+        struct Counter(Mutex<u64>);
+        impl Reserve for Counter {
+            fn reserve(&self, n: u64) -> anyhow::Result<u64> {
+                let mut hw = self.0.lock().unwrap();
+                *hw += n;
+                Ok(*hw - n + 1)
+            }
+        }
+        let gen = Arc::new(IdGenerator::new(Counter(Mutex::new(0)), 7));
+        let mut seen = HashSet::new();
+        check_unique(&gen, 16, 50, &mut seen);
+        // blocks are handed out whole and in order
+        assert_eq!(seen.iter().max(), Some(&(16 * 50)));
+    }
+
+    /// A clock that only moves when told to.
+    fn manual_clock(start: u64) -> (Arc<AtomicU64>, impl Fn() -> u64 + Send + Sync) {
+        let now = Arc::new(AtomicU64::new(start));
+        let clock = now.clone();
+        (now, move || clock.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn snowflakes_pack_time_node_and_sequence() {
+        let t = SNOWFLAKE_EPOCH_MS + 5_000;
+        let (now, clock) = manual_clock(t);
+        let ids = Snowflake::with_clock(3, clock).unwrap();
+        let a = ids.next().unwrap();
+        let b = ids.next().unwrap();
+        now.store(t + 1, Ordering::SeqCst);
+        let c = ids.next().unwrap();
+        assert_eq!(Snowflake::split(a), (t, 3, 0));
+        assert_eq!(Snowflake::split(b), (t, 3, 1));
+        assert_eq!(Snowflake::split(c), (t + 1, 3, 0));
+        assert!(Snowflake::with_clock(MAX_NODES, || 0).is_err());
+    }
+
+    #[test]
+    fn snowflakes_keep_increasing_when_the_clock_goes_back() {
+        let t = SNOWFLAKE_EPOCH_MS + 5_000;
+        let (now, clock) = manual_clock(t);
+        let ids = Snowflake::with_clock(1, clock).unwrap();
+        let before = ids.next().unwrap();
+        now.store(t - 500, Ordering::SeqCst);
+        let after = ids.next().unwrap();
+        assert!(after > before);
+        assert_eq!(Snowflake::split(after), (t, 1, 1));
+        // with the sequence used up it would have to wait out the regression,
+        // which is too long
+        now.store(t - MAX_REGRESSION_MS - 1, Ordering::SeqCst);
+        for _ in 1..MAX_SEQUENCE {
+            ids.next().unwrap();
+        }
+        assert!(ids.next().is_err());
+        now.store(t + 1, Ordering::SeqCst);
+        assert_eq!(Snowflake::split(ids.next().unwrap()), (t + 1, 1, 0));
+    }
+
+    #[test]
+    fn snowflakes_wait_out_a_full_millisecond() {
+        // the clock ticks once every 5000 readings, so a sequence overflows
+        // before it moves
+        let readings = Arc::new(AtomicU64::new(0));
+        let clock = readings.clone();
+        let t = SNOWFLAKE_EPOCH_MS + 5_000;
+        let ids = Arc::new(
+            Snowflake::with_clock(2, move || t + clock.fetch_add(1, Ordering::SeqCst) / 5000)
+                .unwrap(),
+        );
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let ids = ids.clone();
+                thread::spawn(move || (0..3000).map(|_| ids.next().unwrap()).collect::<Vec<_>>())
+            })
+            .collect();
+        let mut seen = HashSet::new();
+        for h in handles {
+            let ids = h.join().unwrap();
+            assert!(ids.windows(2).all(|w| w[0] < w[1]));
+            for id in ids {
+                assert!(seen.insert(id), "id {} handed out twice", id);
+            }
+        }
+        let max = seen.iter().max().unwrap();
+        assert!(Snowflake::split(*max).0 > t);
+    }
+}
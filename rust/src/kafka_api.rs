@@ -0,0 +1,771 @@
+//! The Kafka requests and responses kafka-gateway serves, at the versions it
+//! supports.
+//!
+//! Requests are decoded after their header, up to the fields the gateway
+//! uses; fields at the end it has no use for are left unread. Responses are
+//! encoded without their header. Fields only some versions have are read and
+//! written by version, as listed in Kafka's message definitions; the ones the
+//! gateway has no value for are written as Kafka does when it has none.
+//! [`crate::wire`] has the types they are built from.
+
+use crate::wire::{self, Reader, Record, Writer};
+use std::time::Duration;
+
+pub const PRODUCE: i16 = 0;
+pub const FETCH: i16 = 1;
+pub const LIST_OFFSETS: i16 = 2;
+pub const METADATA: i16 = 3;
+pub const OFFSET_COMMIT: i16 = 8;
+pub const OFFSET_FETCH: i16 = 9;
+pub const FIND_COORDINATOR: i16 = 10;
+pub const API_VERSIONS: i16 = 18;
+
+/// Supported versions of each api, all of them older than the flexible
+/// encoding. Clients look up offsets and group coordinators before
+/// fetching and committing, so those two come along.
+pub const APIS: [(i16, i16, i16); 8] = [
+    (PRODUCE, 3, 8),
+    (FETCH, 4, 11),
+    (LIST_OFFSETS, 1, 5),
+    (METADATA, 0, 4),
+    (OFFSET_COMMIT, 2, 7),
+    (OFFSET_FETCH, 1, 5),
+    (FIND_COORDINATOR, 0, 2),
+    (API_VERSIONS, 0, 2),
+];
+
+pub const NONE: i16 = 0;
+pub const UNKNOWN_SERVER_ERROR: i16 = -1;
+pub const OFFSET_OUT_OF_RANGE: i16 = 1;
+pub const CORRUPT_MESSAGE: i16 = 2;
+pub const NOT_LEADER_OR_FOLLOWER: i16 = 6;
+pub const REQUEST_TIMED_OUT: i16 = 7;
+pub const UNSUPPORTED_VERSION: i16 = 35;
+
+/// Per topic, in request order.
+pub type Topics<T> = Vec<(String, Vec<T>)>;
+
+/// Whether `version` of `api_key` is in [`APIS`].
+pub fn supported(api_key: i16, version: i16) -> bool {
+    APIS.iter()
+        .any(|(key, min, max)| *key == api_key && (*min..=*max).contains(&version))
+}
+
+/// Lists [`APIS`]. Sent with `UNSUPPORTED_VERSION` at version 0 to clients
+/// asking with a version this does not know.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiVersionsResponse {
+    pub error: i16,
+}
+
+impl ApiVersionsResponse {
+    pub fn encode(&self, version: i16) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.i16(self.error).array(APIS.len());
+        for (key, min, max) in APIS {
+            w.i16(key).i16(min).i16(max);
+        }
+        if version >= 1 {
+            w.i32(0);
+        }
+        w.into_inner()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataRequest {
+    /// `None` asks for every topic.
+    pub topics: Option<Vec<String>>,
+}
+
+impl MetadataRequest {
+    pub fn decode(version: i16, r: &mut Reader) -> anyhow::Result<Self> {
+        let topics = r.nullable_array_of(Reader::string)?;
+        if version >= 4 {
+            let _allow_auto_topic_creation = r.bool()?;
+        }
+        // an empty list asks for every topic before version 1
+        let topics = topics.filter(|t| version >= 1 || !t.is_empty());
+        Ok(Self { topics })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Broker {
+    pub node_id: i32,
+    pub host: String,
+    pub port: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionMetadata {
+    pub index: i32,
+    pub leader: i32,
+    pub replicas: Vec<i32>,
+    pub isr: Vec<i32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataResponse {
+    pub brokers: Vec<Broker>,
+    pub cluster_id: Option<String>,
+    pub controller_id: i32,
+    pub topics: Topics<PartitionMetadata>,
+}
+
+impl MetadataResponse {
+    pub fn encode(&self, version: i16) -> Vec<u8> {
+        let mut w = Writer::new();
+        if version >= 3 {
+            w.i32(0);
+        }
+        w.array(self.brokers.len());
+        for broker in &self.brokers {
+            w.i32(broker.node_id).string(&broker.host).i32(broker.port);
+            if version >= 1 {
+                w.nullable_string(None);
+            }
+        }
+        if version >= 2 {
+            w.nullable_string(self.cluster_id.as_deref());
+        }
+        if version >= 1 {
+            w.i32(self.controller_id);
+        }
+        w.array(self.topics.len());
+        for (topic, partitions) in &self.topics {
+            w.i16(NONE).string(topic);
+            if version >= 1 {
+                w.bool(false);
+            }
+            w.array(partitions.len());
+            for p in partitions {
+                w.i16(NONE).i32(p.index).i32(p.leader);
+                w.array(p.replicas.len());
+                for r in &p.replicas {
+                    w.i32(*r);
+                }
+                w.array(p.isr.len());
+                for r in &p.isr {
+                    w.i32(*r);
+                }
+            }
+        }
+        w.into_inner()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProduceRequest<'a> {
+    /// 0 asks for no response.
+    pub acks: i16,
+    /// The record batches of each partition.
+    pub topics: Topics<(i32, Option<&'a [u8]>)>,
+}
+
+impl<'a> ProduceRequest<'a> {
+    pub fn decode(_version: i16, r: &mut Reader<'a>) -> anyhow::Result<Self> {
+        let _transactional_id = r.nullable_string()?;
+        let acks = r.i16()?;
+        let _timeout_ms = r.i32()?;
+        let topics = r.array_of(|r| {
+            let topic = r.string()?;
+            let partitions = r.array_of(|r| Ok((r.i32()?, r.nullable_bytes()?)))?;
+            Ok((topic, partitions))
+        })?;
+        Ok(Self { acks, topics })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Produced {
+    pub index: i32,
+    pub error: i16,
+    /// The offset of the first record, -1 on errors.
+    pub base_offset: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProduceResponse {
+    pub topics: Topics<Produced>,
+}
+
+impl ProduceResponse {
+    pub fn encode(&self, version: i16) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.array(self.topics.len());
+        for (topic, partitions) in &self.topics {
+            w.string(topic).array(partitions.len());
+            for p in partitions {
+                w.i32(p.index).i16(p.error).i64(p.base_offset).i64(-1);
+                if version >= 5 {
+                    w.i64(-1);
+                }
+                if version >= 8 {
+                    w.array(0).nullable_string(None);
+                }
+            }
+        }
+        w.i32(0);
+        w.into_inner()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchRequest {
+    pub max_wait: Duration,
+    pub read_committed: bool,
+    /// The offset to fetch from in each partition.
+    pub topics: Topics<(i32, i64)>,
+}
+
+impl FetchRequest {
+    pub fn decode(version: i16, r: &mut Reader) -> anyhow::Result<Self> {
+        let _replica_id = r.i32()?;
+        let max_wait = Duration::from_millis(r.i32()?.max(0) as u64);
+        let _min_bytes = r.i32()?;
+        let _max_bytes = r.i32()?;
+        let read_committed = r.i8()? != 0;
+        if version >= 7 {
+            let _session_id = r.i32()?;
+            let _session_epoch = r.i32()?;
+        }
+        let topics = r.array_of(|r| {
+            let topic = r.string()?;
+            let partitions = r.array_of(|r| {
+                let partition = r.i32()?;
+                if version >= 9 {
+                    let _current_leader_epoch = r.i32()?;
+                }
+                let offset = r.i64()?;
+                if version >= 5 {
+                    let _log_start_offset = r.i64()?;
+                }
+                let _partition_max_bytes = r.i32()?;
+                Ok((partition, offset))
+            })?;
+            Ok((topic, partitions))
+        })?;
+        Ok(Self {
+            max_wait,
+            read_committed,
+            topics,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fetched {
+    pub index: i32,
+    pub error: i16,
+    /// Doubles as the last stable offset.
+    pub high_watermark: i64,
+    /// Sent as one record batch.
+    pub records: Vec<Record>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchResponse {
+    pub topics: Topics<Fetched>,
+}
+
+impl FetchResponse {
+    pub fn encode(&self, version: i16) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.i32(0);
+        if version >= 7 {
+            w.i16(NONE).i32(0);
+        }
+        w.array(self.topics.len());
+        for (topic, partitions) in &self.topics {
+            w.string(topic).array(partitions.len());
+            for p in partitions {
+                w.i32(p.index)
+                    .i16(p.error)
+                    .i64(p.high_watermark)
+                    .i64(p.high_watermark);
+                if version >= 5 {
+                    w.i64(-1);
+                }
+                w.null_array();
+                if version >= 11 {
+                    w.i32(-1);
+                }
+                w.nullable_bytes(Some(&wire::encode_batch(&p.records)));
+            }
+        }
+        w.into_inner()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListOffsetsRequest {
+    /// The timestamp looked up in each partition: -1 for the latest offset,
+    /// -2 for the earliest.
+    pub topics: Topics<(i32, i64)>,
+}
+
+impl ListOffsetsRequest {
+    pub fn decode(version: i16, r: &mut Reader) -> anyhow::Result<Self> {
+        let _replica_id = r.i32()?;
+        if version >= 2 {
+            let _isolation_level = r.i8()?;
+        }
+        let topics = r.array_of(|r| {
+            let topic = r.string()?;
+            let partitions = r.array_of(|r| {
+                let partition = r.i32()?;
+                if version >= 4 {
+                    let _current_leader_epoch = r.i32()?;
+                }
+                Ok((partition, r.i64()?))
+            })?;
+            Ok((topic, partitions))
+        })?;
+        Ok(Self { topics })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedOffset {
+    pub index: i32,
+    pub error: i16,
+    pub offset: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListOffsetsResponse {
+    pub topics: Topics<ListedOffset>,
+}
+
+impl ListOffsetsResponse {
+    pub fn encode(&self, version: i16) -> Vec<u8> {
+        let mut w = Writer::new();
+        if version >= 2 {
+            w.i32(0);
+        }
+        w.array(self.topics.len());
+        for (topic, partitions) in &self.topics {
+            w.string(topic).array(partitions.len());
+            for p in partitions {
+                w.i32(p.index).i16(p.error).i64(-1).i64(p.offset);
+                if version >= 4 {
+                    w.i32(-1);
+                }
+            }
+        }
+        w.into_inner()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OffsetCommitRequest {
+    pub group: String,
+    pub topics: Topics<(i32, i64)>,
+}
+
+impl OffsetCommitRequest {
+    pub fn decode(version: i16, r: &mut Reader) -> anyhow::Result<Self> {
+        let group = r.string()?;
+        let _generation_id = r.i32()?;
+        let _member_id = r.string()?;
+        if version >= 7 {
+            let _group_instance_id = r.nullable_string()?;
+        }
+        if version <= 4 {
+            let _retention_time_ms = r.i64()?;
+        }
+        let topics = r.array_of(|r| {
+            let topic = r.string()?;
+            let partitions = r.array_of(|r| {
+                let partition = r.i32()?;
+                let offset = r.i64()?;
+                if version >= 6 {
+                    let _committed_leader_epoch = r.i32()?;
+                }
+                let _metadata = r.nullable_string()?;
+                Ok((partition, offset))
+            })?;
+            Ok((topic, partitions))
+        })?;
+        Ok(Self { group, topics })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OffsetCommitResponse {
+    /// The error of each partition.
+    pub topics: Topics<(i32, i16)>,
+}
+
+impl OffsetCommitResponse {
+    pub fn encode(&self, version: i16) -> Vec<u8> {
+        let mut w = Writer::new();
+        if version >= 3 {
+            w.i32(0);
+        }
+        w.array(self.topics.len());
+        for (topic, partitions) in &self.topics {
+            w.string(topic).array(partitions.len());
+            for (partition, error) in partitions {
+                w.i32(*partition).i16(*error);
+            }
+        }
+        w.into_inner()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OffsetFetchRequest {
+    pub group: String,
+    /// `None` asks for every partition with a committed offset.
+    pub topics: Option<Topics<i32>>,
+}
+
+impl OffsetFetchRequest {
+    pub fn decode(_version: i16, r: &mut Reader) -> anyhow::Result<Self> {
+        let group = r.string()?;
+        let topics = r.nullable_array_of(|r| Ok((r.string()?, r.array_of(Reader::i32)?)))?;
+        Ok(Self { group, topics })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OffsetFetchResponse {
+    /// The committed offset of each partition, -1 for none.
+    pub topics: Topics<(i32, i64)>,
+    /// Sent for every partition, and for the whole group from version 2.
+    pub error: i16,
+}
+
+impl OffsetFetchResponse {
+    pub fn encode(&self, version: i16) -> Vec<u8> {
+        let mut w = Writer::new();
+        if version >= 3 {
+            w.i32(0);
+        }
+        w.array(self.topics.len());
+        for (topic, partitions) in &self.topics {
+            w.string(topic).array(partitions.len());
+            for (partition, offset) in partitions {
+                w.i32(*partition).i64(*offset);
+                if version >= 5 {
+                    w.i32(-1);
+                }
+                w.nullable_string(Some("")).i16(self.error);
+            }
+        }
+        if version >= 2 {
+            w.i16(self.error);
+        }
+        w.into_inner()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FindCoordinatorRequest {
+    pub key: String,
+}
+
+impl FindCoordinatorRequest {
+    pub fn decode(version: i16, r: &mut Reader) -> anyhow::Result<Self> {
+        let key = r.string()?;
+        if version >= 1 {
+            let _key_type = r.i8()?;
+        }
+        Ok(Self { key })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FindCoordinatorResponse {
+    pub coordinator: Broker,
+}
+
+impl FindCoordinatorResponse {
+    pub fn encode(&self, version: i16) -> Vec<u8> {
+        let mut w = Writer::new();
+        if version >= 1 {
+            w.i32(0);
+        }
+        w.i16(NONE);
+        if version >= 1 {
+            w.nullable_string(None);
+        }
+        let c = &self.coordinator;
+        w.i32(c.node_id).string(&c.host).i32(c.port);
+        w.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The fields of a message, each as Kafka encodes it.
+    fn bytes(fields: &[&[u8]]) -> Vec<u8> {
+        fields.concat()
+    }
+
+    const ZERO32: &[u8] = &[0, 0, 0, 0];
+    const NULL32: &[u8] = &[0xff; 4];
+    const NULL64: &[u8] = &[0xff; 8];
+    const ONE: &[u8] = &[0, 0, 0, 1];
+    const NO_ERROR: &[u8] = &[0, 0];
+    const NULL_STRING: &[u8] = &[0xff, 0xff];
+    const TOPIC: &[u8] = &[0, 1, b't'];
+
+    fn one_topic<T>(partitions: Vec<T>) -> Topics<T> {
+        vec![("t".to_string(), partitions)]
+    }
+
+    #[test]
+    fn encodes_api_versions() {
+        let apis: &[u8] = &[
+            0, 0, 0, 8, //
+            0, 0, 0, 3, 0, 8, // produce
+            0, 1, 0, 4, 0, 11, // fetch
+            0, 2, 0, 1, 0, 5, // list offsets
+            0, 3, 0, 0, 0, 4, // metadata
+            0, 8, 0, 2, 0, 7, // offset commit
+            0, 9, 0, 1, 0, 5, // offset fetch
+            0, 10, 0, 0, 0, 2, // find coordinator
+            0, 18, 0, 0, 0, 2, // api versions
+        ];
+        let ok = ApiVersionsResponse { error: NONE };
+        assert_eq!(ok.encode(0), bytes(&[NO_ERROR, apis]));
+        assert_eq!(ok.encode(2), bytes(&[NO_ERROR, apis, ZERO32]));
+        let unsupported = ApiVersionsResponse {
+            error: UNSUPPORTED_VERSION,
+        };
+        assert_eq!(unsupported.encode(0), bytes(&[&[0, 35], apis]));
+    }
+
+    #[test]
+    fn decodes_metadata_requests() {
+        let decode = |version, buf: &[u8]| MetadataRequest::decode(version, &mut Reader::new(buf));
+        assert_eq!(decode(0, ZERO32).unwrap().topics, None);
+        assert_eq!(decode(1, ZERO32).unwrap().topics, Some(vec![]));
+        let some = bytes(&[ONE, TOPIC, &[1]]);
+        assert_eq!(
+            decode(4, &some).unwrap().topics,
+            Some(vec!["t".to_string()])
+        );
+        assert_eq!(decode(4, &bytes(&[NULL32, &[0]])).unwrap().topics, None);
+    }
+
+    #[test]
+    fn encodes_metadata() {
+        let response = MetadataResponse {
+            brokers: vec![Broker {
+                node_id: 0,
+                host: "h".to_string(),
+                port: 9092,
+            }],
+            cluster_id: Some("nazgul".to_string()),
+            controller_id: 0,
+            topics: one_topic(vec![PartitionMetadata {
+                index: 0,
+                leader: 0,
+                replicas: vec![0],
+                isr: vec![0],
+            }]),
+        };
+        let broker: &[u8] = &[0, 0, 0, 0, 0, 1, b'h', 0, 0, 0x23, 0x84];
+        let partition = bytes(&[NO_ERROR, ZERO32, ZERO32, ONE, ZERO32, ONE, ZERO32]);
+        assert_eq!(
+            response.encode(0),
+            bytes(&[ONE, broker, ONE, NO_ERROR, TOPIC, ONE, &partition])
+        );
+        let cluster_id: &[u8] = &[0, 6, b'n', b'a', b'z', b'g', b'u', b'l'];
+        assert_eq!(
+            response.encode(4),
+            bytes(&[
+                ZERO32,
+                ONE,
+                broker,
+                NULL_STRING,
+                cluster_id,
+                ZERO32,
+                ONE,
+                NO_ERROR,
+                TOPIC,
+                &[0],
+                ONE,
+                &partition,
+            ])
+        );
+    }
+
+    #[test]
+    fn decodes_produce_requests() {
+        let buf = bytes(&[
+            NULL_STRING,
+            &[0, 1],
+            &[0, 0, 0x75, 0x30],
+            ONE,
+            TOPIC,
+            ONE,
+            &[0, 0, 0, 2],
+            &[0, 0, 0, 3, b'a', b'b', b'c'],
+        ]);
+        let request = ProduceRequest::decode(3, &mut Reader::new(&buf)).unwrap();
+        assert_eq!(request.acks, 1);
+        assert_eq!(request.topics, one_topic(vec![(2, Some(&b"abc"[..]))]));
+    }
+
+    #[test]
+    fn encodes_produce() {
+        let response = ProduceResponse {
+            topics: one_topic(vec![Produced {
+                index: 0,
+                error: NONE,
+                base_offset: 5,
+            }]),
+        };
+        let base_offset: &[u8] = &[0, 0, 0, 0, 0, 0, 0, 5];
+        let partition = bytes(&[ZERO32, NO_ERROR, base_offset, NULL64]);
+        assert_eq!(
+            response.encode(3),
+            bytes(&[ONE, TOPIC, ONE, &partition, ZERO32])
+        );
+        assert_eq!(
+            response.encode(8),
+            bytes(&[
+                ONE,
+                TOPIC,
+                ONE,
+                &partition,
+                NULL64,
+                ZERO32,
+                NULL_STRING,
+                ZERO32
+            ])
+        );
+    }
+
+    #[test]
+    fn decodes_fetch_requests() {
+        let buf = bytes(&[
+            NULL32,
+            &[0, 0, 0x01, 0xf4],
+            ONE,
+            &[0, 0x10, 0, 0],
+            &[1],
+            ZERO32,
+            NULL32,
+            ONE,
+            TOPIC,
+            ONE,
+            ZERO32,
+            NULL32,
+            &[0, 0, 0, 0, 0, 0, 0, 7],
+            NULL64,
+            &[0, 0x10, 0, 0],
+            ZERO32,
+            &[0, 0],
+        ]);
+        let request = FetchRequest::decode(11, &mut Reader::new(&buf)).unwrap();
+        assert_eq!(request.max_wait, Duration::from_millis(500));
+        assert!(request.read_committed);
+        assert_eq!(request.topics, one_topic(vec![(0, 7)]));
+    }
+
+    #[test]
+    fn encodes_fetch() {
+        let response = FetchResponse {
+            topics: one_topic(vec![Fetched {
+                index: 0,
+                error: OFFSET_OUT_OF_RANGE,
+                high_watermark: 8,
+                records: Vec::new(),
+            }]),
+        };
+        let hw: &[u8] = &[0, 0, 0, 0, 0, 0, 0, 8];
+        let error: &[u8] = &[0, 1];
+        assert_eq!(
+            response.encode(4),
+            bytes(&[ZERO32, ONE, TOPIC, ONE, ZERO32, error, hw, hw, NULL32, ZERO32])
+        );
+        assert_eq!(
+            response.encode(11),
+            bytes(&[
+                ZERO32, NO_ERROR, ZERO32, ONE, TOPIC, ONE, ZERO32, error, hw, hw, NULL64, NULL32,
+                NULL32, ZERO32,
+            ])
+        );
+    }
+
+    #[test]
+    fn decodes_offset_commit_requests() {
+        let offset: &[u8] = &[0, 0, 0, 0, 0, 0, 0, 4];
+        let group: &[u8] = &[0, 1, b'g'];
+        let v2 = bytes(&[
+            group,
+            NULL32,
+            &[0, 0],
+            NULL64,
+            ONE,
+            TOPIC,
+            ONE,
+            ZERO32,
+            offset,
+            NULL_STRING,
+        ]);
+        let v7 = bytes(&[
+            group,
+            NULL32,
+            &[0, 0],
+            NULL_STRING,
+            ONE,
+            TOPIC,
+            ONE,
+            ZERO32,
+            offset,
+            NULL32,
+            NULL_STRING,
+        ]);
+        for (version, buf) in [(2, v2), (7, v7)] {
+            let request = OffsetCommitRequest::decode(version, &mut Reader::new(&buf)).unwrap();
+            assert_eq!(request.group, "g");
+            assert_eq!(request.topics, one_topic(vec![(0, 4)]), "v{}", version);
+        }
+    }
+
+    #[test]
+    fn encodes_offset_commit() {
+        let response = OffsetCommitResponse {
+            topics: one_topic(vec![(0, NONE)]),
+        };
+        let topics = bytes(&[ONE, TOPIC, ONE, ZERO32, NO_ERROR]);
+        assert_eq!(response.encode(2), topics);
+        assert_eq!(response.encode(7), bytes(&[ZERO32, &topics]));
+    }
+
+    #[test]
+    fn decodes_offset_fetch_requests() {
+        let buf = bytes(&[&[0, 1, b'g'], NULL32]);
+        let request = OffsetFetchRequest::decode(2, &mut Reader::new(&buf)).unwrap();
+        assert_eq!((request.group.as_str(), request.topics), ("g", None));
+    }
+
+    #[test]
+    fn encodes_offset_fetch() {
+        let response = OffsetFetchResponse {
+            topics: one_topic(vec![(0, 4)]),
+            error: NONE,
+        };
+        let offset: &[u8] = &[0, 0, 0, 0, 0, 0, 0, 4];
+        let metadata: &[u8] = &[0, 0];
+        assert_eq!(
+            response.encode(1),
+            bytes(&[ONE, TOPIC, ONE, ZERO32, offset, metadata, NO_ERROR])
+        );
+        assert_eq!(
+            response.encode(5),
+            bytes(&[
+                ZERO32, ONE, TOPIC, ONE, ZERO32, offset, NULL32, metadata, NO_ERROR, NO_ERROR,
+            ])
+        );
+    }
+}
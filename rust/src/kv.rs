@@ -0,0 +1,203 @@
+//! Key/value state machine with Maelstrom's `lin-kv` semantics.
+//!
+//! Keys and values are arbitrary JSON. Reads go through the log like writes,
+//! so a replicated `KvStore` is linearizable.
+
+use crate::rsm::StateMachine;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::BTreeMap, fmt::Display};
+
+/// Maelstrom error codes.
+pub const TIMEOUT: usize = 0;
+pub const NOT_SUPPORTED: usize = 10;
+pub const TEMPORARILY_UNAVAILABLE: usize = 11;
+pub const MALFORMED_REQUEST: usize = 12;
+pub const ABORT: usize = 14;
+pub const KEY_DOES_NOT_EXIST: usize = 20;
+pub const PRECONDITION_FAILED: usize = 22;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op")]
+#[serde(rename_all = "snake_case")]
+pub enum KvCommand {
+    Read {
+        key: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        put: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvError {
+    KeyDoesNotExist(Value),
+    PreconditionFailed { expected: Value, actual: Value },
+}
+
+impl KvError {
+    pub fn code(&self) -> usize {
+        match self {
+            KvError::KeyDoesNotExist(_) => KEY_DOES_NOT_EXIST,
+            KvError::PreconditionFailed { .. } => PRECONDITION_FAILED,
+        }
+    }
+}
+
+impl Display for KvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KvError::KeyDoesNotExist(key) => write!(f, "key {} does not exist", key),
+            KvError::PreconditionFailed { expected, actual } => {
+                write!(f, "expected {}, but had {}", expected, actual)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+/// An `error` reply from a remote key/value service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpcError {
+    pub code: usize,
+    pub text: String,
+}
+
+impl RpcError {
+    /// The Maelstrom error code carried by `e`, whether it came from a remote
+    /// service or a local [`KvStore`].
+    pub fn code_of(e: &anyhow::Error) -> Option<usize> {
+        e.chain().find_map(|e| {
+            e.downcast_ref::<RpcError>()
+                .map(|e| e.code)
+                .or_else(|| e.downcast_ref::<KvError>().map(KvError::code))
+        })
+    }
+
+    pub fn is_missing(e: &anyhow::Error) -> bool {
+        Self::code_of(e) == Some(KEY_DOES_NOT_EXIST)
+    }
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "error {}: {}", self.code, self.text)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+#[derive(Debug, Default)]
+pub struct KvStore {
+    // JSON keys are not hashable, so they are stored by their serialization
+    data: BTreeMap<String, Value>,
+}
+
+impl KvStore {
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        self.data.get(&key.to_string())
+    }
+}
+
+impl StateMachine for KvStore {
+    type Command = KvCommand;
+    /// The value read for `Read`, `None` for successful writes.
+    type Output = Result<Option<Value>, KvError>;
+
+    fn apply(&mut self, command: &KvCommand) -> Self::Output {
+        match command {
+            KvCommand::Read { key } => match self.get(key) {
+                Some(value) => Ok(Some(value.clone())),
+                None => Err(KvError::KeyDoesNotExist(key.clone())),
+            },
+            KvCommand::Write { key, value } => {
+                self.data.insert(key.to_string(), value.clone());
+                Ok(None)
+            }
+            KvCommand::Cas { key, from, to, put } => {
+                match self.data.get_mut(&key.to_string()) {
+                    Some(current) if current == from => *current = to.clone(),
+                    Some(current) => {
+                        return Err(KvError::PreconditionFailed {
+                            expected: from.clone(),
+                            actual: current.clone(),
+                        })
+                    }
+                    None if *put => {
+                        self.data.insert(key.to_string(), to.clone());
+                    }
+                    None => return Err(KvError::KeyDoesNotExist(key.clone())),
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Value {
+        serde_json::to_value(&self.data).expect("json values always serialize")
+    }
+
+    fn restore(&mut self, snapshot: Value) {
+        self.data = serde_json::from_value(snapshot).unwrap_or_default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn read_write_and_cas() {
+        let mut kv = KvStore::default();
+        let read = KvCommand::Read { key: json!(1) };
+        assert_eq!(kv.apply(&read), Err(KvError::KeyDoesNotExist(json!(1))));
+        kv.apply(&KvCommand::Write {
+            key: json!(1),
+            value: json!(3),
+        })
+        .unwrap();
+        assert_eq!(kv.apply(&read), Ok(Some(json!(3))));
+
+        let cas = |from, to| KvCommand::Cas {
+            key: json!(1),
+            from: json!(from),
+            to: json!(to),
+            put: false,
+        };
+        assert_eq!(kv.apply(&cas(3, 4)), Ok(None));
+        assert_eq!(
+            kv.apply(&cas(3, 5)).unwrap_err().code(),
+            PRECONDITION_FAILED
+        );
+        assert_eq!(kv.apply(&read), Ok(Some(json!(4))));
+    }
+
+    #[test]
+    fn cas_creates_missing_keys_only_when_asked() {
+        let mut kv = KvStore::default();
+        let cas = |put| KvCommand::Cas {
+            key: json!("k"),
+            from: json!(0),
+            to: json!(1),
+            put,
+        };
+        assert_eq!(
+            kv.apply(&cas(false)).unwrap_err().code(),
+            KEY_DOES_NOT_EXIST
+        );
+        assert_eq!(kv.apply(&cas(true)), Ok(None));
+
+        let mut restored = KvStore::default();
+        restored.restore(kv.snapshot());
+        assert_eq!(restored.get(&json!("k")), Some(&json!(1)));
+    }
+}
@@ -2,6 +2,26 @@
 #![allow(unused_imports)]
 #![allow(unused_variables)]
 
+pub mod anti_entropy;
+pub mod broker;
+pub mod crdt;
+pub mod failure_detector;
+pub mod gossip;
+pub mod group;
+pub mod ids;
+pub mod kafka_api;
+pub mod kv;
+pub mod log;
+pub mod membership;
+pub mod paxos;
+pub mod raft;
+pub mod replication;
+pub mod ring;
+pub mod rsm;
+pub mod storage;
+pub mod subscription;
+pub mod wire;
+
 use anyhow::{Context, Ok};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
@@ -9,6 +29,7 @@ use std::{
     io::{BufRead, Write},
     sync::{atomic::AtomicUsize, mpsc::Sender, Arc, Mutex},
     thread::{self, JoinHandle},
+    time::Duration,
     vec,
 };
 
@@ -93,6 +114,7 @@ pub trait Node<S, Payload> {
     fn step(&self, input: Message<Payload>) -> anyhow::Result<()>;
 }
 
+#[allow(clippy::ptr_arg)]
 pub trait KV<T>: Send + Sync {
     /// Read returns the value for a given key in the key/value store.
     /// Returns an RPCError error with a KeyDoesNotExist code if the key does not exist.
@@ -122,6 +144,29 @@ pub trait KV<T>: Send + Sync {
         T: Serialize + Deserialize<'static> + Send;
 }
 
+/// Feeds `payload` back into the node's own inbox every `interval`, so timers
+/// are handled by `Node::step` like any other message.
+pub fn spawn_ticker<P>(node: String, interval: Duration, payload: P, tx: Sender<Message<P>>)
+where
+    P: Clone + Send + 'static,
+{
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let msg = Message {
+            src: node.clone(),
+            dst: node.clone(),
+            body: Body {
+                id: None,
+                in_reply_to: None,
+                payload: payload.clone(),
+            },
+        };
+        if tx.send(msg).is_err() {
+            break;
+        }
+    });
+}
+
 pub fn main_loop<S, N, P>(init_state: S) -> anyhow::Result<()>
 where
     N: Node<S, P> + 'static + Send + Sync,
@@ -0,0 +1,729 @@
+//! Per-key logs as kafka-log keeps them, in memory or in segment files.
+//!
+//! Entries carry the producer sequence numbers and transaction markers their
+//! writers attached. The store remembers the latest sequence numbers of each
+//! producer and the progress of each transaction per key, and rebuilds both
+//! from the entries when it is opened, so followers and restarted nodes can
+//! deduplicate sends and end transactions like the node that appended them.
+
+use crate::{
+    kv::{RpcError, PRECONDITION_FAILED},
+    storage::{Retention, Storage},
+};
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Sequence numbers remembered per producer and key, as many as a producer
+/// may have in flight.
+pub const PRODUCER_WINDOW: usize = 5;
+/// Entries read at a time when scanning a log.
+const READ_BATCH: usize = 100;
+/// Entries between two marks of a log's time index.
+const TIME_INDEX_INTERVAL: usize = 100;
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// A log entry. Messages are JSON values in kafka-log, but the log itself
+/// does not care what an entry holds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Log<V = Value> {
+    pub offset: usize,
+    pub value: V,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, Value>,
+    /// Compaction keeps only the latest entry per sub-key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub_key: Option<String>,
+    /// Milliseconds since the epoch when the owner appended the entry.
+    #[serde(default)]
+    pub timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub producer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub txn: Option<TxnRef>,
+}
+
+impl<V> Log<V> {
+    pub fn new(value: V) -> Self {
+        Self {
+            offset: 0,
+            value,
+            headers: BTreeMap::new(),
+            sub_key: None,
+            timestamp: 0,
+            producer: None,
+            seq: None,
+            txn: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Commit,
+    Abort,
+}
+
+/// Ties an entry to a transaction. Markers, the entries ending one, carry
+/// its outcome and no message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TxnRef {
+    pub id: String,
+    /// The key whose marker decides the outcome for every key.
+    pub home: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<Outcome>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Isolation {
+    #[default]
+    ReadUncommitted,
+    /// Hides transactional messages until their transaction commits.
+    ReadCommitted,
+}
+
+/// A key's log in memory. `end` outlives the entries, so offsets are not
+/// reused once retention has emptied the log.
+#[derive(Debug)]
+struct MemoryLog<V> {
+    entries: VecDeque<Log<V>>,
+    end: usize,
+}
+
+impl<V> Default for MemoryLog<V> {
+    fn default() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            end: 0,
+        }
+    }
+}
+
+impl<V: Serialize> MemoryLog<V> {
+    fn clean(&mut self, retention: &Retention, compact: bool) {
+        if compact {
+            let mut latest = HashMap::new();
+            for log in &self.entries {
+                if let Some(sub_key) = &log.sub_key {
+                    latest.insert(sub_key.clone(), log.offset);
+                }
+            }
+            self.entries
+                .retain(|l| l.sub_key.as_ref().is_none_or(|k| latest[k] == l.offset));
+        }
+        if let Some(max) = retention.max_records {
+            while self.entries.len() as u64 > max {
+                self.entries.pop_front();
+            }
+        }
+        if let Some(age) = retention.max_age {
+            let oldest = now_ms().saturating_sub(age.as_millis() as u64);
+            while self.entries.front().is_some_and(|l| l.timestamp < oldest) {
+                self.entries.pop_front();
+            }
+        }
+        if let Some(max) = retention.max_bytes {
+            // sized as they would be stored on disk
+            let size = |l: &Log<V>| serde_json::to_vec(l).map_or(0, |v| v.len() as u64);
+            let mut total: u64 = self.entries.iter().map(size).sum();
+            while total > max {
+                let Some(log) = self.entries.pop_front() else {
+                    break;
+                };
+                total -= size(&log);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Backend<V> {
+    Memory(HashMap<String, MemoryLog<V>>),
+    Disk(Storage),
+}
+
+/// A transaction's progress on one key.
+#[derive(Debug)]
+struct TxnState {
+    /// The first entry of the transaction on this key.
+    first: usize,
+    /// When that entry was appended, in milliseconds since the epoch.
+    started: u64,
+    txn: TxnRef,
+    /// The outcome and the offset of the marker recording it.
+    ended: Option<(Outcome, usize)>,
+}
+
+/// Marks every [`TIME_INDEX_INTERVAL`]th offset of a log with the latest
+/// timestamp up to it, so a lookup by time scans one interval of entries
+/// instead of the whole log. Rebuilt from the entries like the producers.
+#[derive(Debug, Default)]
+struct TimeIndex {
+    /// The latest timestamp at or before each marked offset. Both rise, even
+    /// where the timestamps of the entries themselves do not.
+    marks: Vec<(u64, usize)>,
+    latest: u64,
+    since_mark: usize,
+}
+
+impl TimeIndex {
+    fn track(&mut self, offset: usize, timestamp: u64) {
+        self.latest = self.latest.max(timestamp);
+        self.since_mark += 1;
+        if self.since_mark == TIME_INDEX_INTERVAL {
+            self.marks.push((self.latest, offset));
+            self.since_mark = 0;
+        }
+    }
+
+    /// An offset no entry before which was appended at or after `timestamp`.
+    fn scan_from(&self, timestamp: u64) -> usize {
+        match self.marks.partition_point(|(t, _)| *t < timestamp) {
+            0 => 0,
+            i => self.marks[i - 1].1 + 1,
+        }
+    }
+}
+
+/// Where an owner keeps the logs of its keys, whatever their entries hold.
+#[derive(Debug)]
+pub struct LogStore<V = Value> {
+    backend: Backend<V>,
+    /// The latest sequence numbers per key and producer, with the offsets
+    /// they were appended at. Rebuilt from the entries, so followers and
+    /// restarted nodes know them too.
+    producers: HashMap<String, HashMap<String, VecDeque<(u64, usize)>>>,
+    /// Transactions per key and id, rebuilt from the entries like
+    /// `producers`.
+    txns: HashMap<String, HashMap<String, TxnState>>,
+    times: HashMap<String, TimeIndex>,
+}
+
+impl<V: Clone + Serialize + DeserializeOwned> LogStore<V> {
+    pub fn in_memory() -> Self {
+        Self {
+            backend: Backend::Memory(HashMap::new()),
+            producers: HashMap::new(),
+            txns: HashMap::new(),
+            times: HashMap::new(),
+        }
+    }
+
+    /// Opens the logs kept in `storage`, reading every entry once to rebuild
+    /// the producer and transaction state.
+    pub fn on_disk(storage: Storage) -> anyhow::Result<Self> {
+        Self::new(Backend::Disk(storage))
+    }
+
+    fn new(backend: Backend<V>) -> anyhow::Result<Self> {
+        let mut store = Self {
+            backend,
+            producers: HashMap::new(),
+            txns: HashMap::new(),
+            times: HashMap::new(),
+        };
+        let keys: Vec<String> = match &store.backend {
+            Backend::Memory(logs) => logs.keys().cloned().collect(),
+            Backend::Disk(storage) => storage.keys().cloned().collect(),
+        };
+        for key in keys {
+            for log in store.read(&key, 0, usize::MAX)? {
+                store.track(&key, &log);
+            }
+        }
+        Ok(store)
+    }
+
+    /// The last offset in `key`'s log, 0 if it is empty.
+    pub fn end(&self, key: &str) -> usize {
+        match &self.backend {
+            Backend::Memory(logs) => logs.get(key).map_or(0, |l| l.end),
+            Backend::Disk(storage) => storage.last_offset(key).unwrap_or(0) as usize,
+        }
+    }
+
+    /// Appends `log` at the next offset and returns it. Timestamps never go
+    /// back within a log, even under a leader whose clock is behind the last
+    /// one's.
+    pub fn append(&mut self, key: String, mut log: Log<V>) -> anyhow::Result<usize> {
+        log.offset = self.end(&key) + 1;
+        log.timestamp = now_ms().max(self.times.get(&key).map_or(0, |t| t.latest));
+        let offset = log.offset;
+        self.append_at(key, log)?;
+        Ok(offset)
+    }
+
+    /// Appends `log` at its own offset, which must be past the end.
+    pub fn append_at(&mut self, key: String, log: Log<V>) -> anyhow::Result<()> {
+        match &mut self.backend {
+            Backend::Memory(logs) => {
+                let l = logs.entry(key.clone()).or_default();
+                l.end = log.offset;
+                l.entries.push_back(log.clone());
+            }
+            Backend::Disk(storage) => {
+                let data = serde_json::to_vec(&log).context("serialize log entry")?;
+                storage
+                    .append(&key, log.offset as u64, &data)
+                    .context("append to segment")?;
+            }
+        }
+        self.track(&key, &log);
+        Ok(())
+    }
+
+    /// Records the producer sequence, transaction progress and timestamp of
+    /// `log`.
+    fn track(&mut self, key: &str, log: &Log<V>) {
+        self.times
+            .entry(key.to_string())
+            .or_default()
+            .track(log.offset, log.timestamp);
+        if let Some(txn) = &log.txn {
+            let state = self
+                .txns
+                .entry(key.to_string())
+                .or_default()
+                .entry(txn.id.clone())
+                .or_insert_with(|| TxnState {
+                    first: log.offset,
+                    started: log.timestamp,
+                    txn: TxnRef {
+                        outcome: None,
+                        ..txn.clone()
+                    },
+                    ended: None,
+                });
+            if let (Some(outcome), None) = (txn.outcome, state.ended) {
+                state.ended = Some((outcome, log.offset));
+            }
+        }
+        let (Some(producer), Some(seq)) = (&log.producer, log.seq) else {
+            return;
+        };
+        let window = self
+            .producers
+            .entry(key.to_string())
+            .or_default()
+            .entry(producer.clone())
+            .or_default();
+        window.push_back((seq, log.offset));
+        if window.len() > PRODUCER_WINDOW {
+            window.pop_front();
+        }
+    }
+
+    /// The offset `producer` already appended `seq` at, if it did. Sequence
+    /// numbers older than the remembered window cannot be checked and fail.
+    pub fn duplicate(&self, key: &str, producer: &str, seq: u64) -> anyhow::Result<Option<usize>> {
+        let Some(window) = self.producers.get(key).and_then(|p| p.get(producer)) else {
+            return Ok(None);
+        };
+        if let Some((_, offset)) = window.iter().find(|(s, _)| *s == seq) {
+            return Ok(Some(*offset));
+        }
+        match window.front() {
+            Some((oldest, _)) if seq < *oldest => Err(RpcError {
+                code: PRECONDITION_FAILED,
+                text: format!("sequence {} of {} is too old to check", seq, producer),
+            }
+            .into()),
+            _ => Ok(None),
+        }
+    }
+
+    /// Drops every entry after `offset`.
+    pub fn truncate_after(&mut self, key: &str, offset: usize) -> anyhow::Result<()> {
+        match &mut self.backend {
+            Backend::Memory(logs) => {
+                if let Some(l) = logs.get_mut(key) {
+                    l.entries.retain(|e| e.offset <= offset);
+                    l.end = l.end.min(offset);
+                }
+            }
+            Backend::Disk(storage) => storage
+                .truncate_after(key, offset as u64)
+                .context("truncate segments")?,
+        }
+        for window in self
+            .producers
+            .get_mut(key)
+            .into_iter()
+            .flat_map(|p| p.values_mut())
+        {
+            window.retain(|(_, o)| *o <= offset);
+        }
+        if let Some(txns) = self.txns.get_mut(key) {
+            txns.retain(|_, t| t.first <= offset);
+            for t in txns.values_mut() {
+                if t.ended.is_some_and(|(_, marker)| marker > offset) {
+                    t.ended = None;
+                }
+            }
+        }
+        // the latest timestamp stays, as later marks must not go below the
+        // entries kept since the last one
+        if let Some(times) = self.times.get_mut(key) {
+            times.marks.retain(|(_, o)| *o <= offset);
+        }
+        Ok(())
+    }
+
+    /// How `txn` ended on `key` and the offset of its marker, if it did.
+    pub fn ended(&self, key: &str, txn: &str) -> Option<(Outcome, usize)> {
+        self.txns.get(key)?.get(txn)?.ended
+    }
+
+    /// The last offset before the first entry of a pending transaction,
+    /// which read-committed polls do not read past.
+    pub fn stable_end(&self, key: &str) -> usize {
+        self.txns
+            .get(key)
+            .into_iter()
+            .flat_map(|t| t.values())
+            .filter(|t| t.ended.is_none())
+            .map(|t| t.first - 1)
+            .min()
+            .unwrap_or(usize::MAX)
+    }
+
+    /// Whether a poll at `isolation` returns `log`. Markers are never
+    /// returned; read-committed polls also skip aborted transactions.
+    pub fn visible(&self, key: &str, log: &Log<V>, isolation: Isolation) -> bool {
+        match &log.txn {
+            None => true,
+            Some(txn) if txn.outcome.is_some() => false,
+            Some(_) if isolation == Isolation::ReadUncommitted => true,
+            Some(txn) => matches!(self.ended(key, &txn.id), Some((Outcome::Commit, _))),
+        }
+    }
+
+    /// Pending transactions started before `before` (milliseconds since the
+    /// epoch), with the keys they are pending on.
+    pub fn pending_since(&self, before: u64) -> Vec<(String, TxnRef)> {
+        let mut pending = Vec::new();
+        for (key, txns) in &self.txns {
+            for t in txns.values() {
+                if t.ended.is_none() && t.started < before {
+                    pending.push((key.clone(), t.txn.clone()));
+                }
+            }
+        }
+        pending
+    }
+
+    /// Up to `max` entries from `from` on. Reading below the start of a log
+    /// that retention has cut begins at its earliest entry instead. Offsets
+    /// are only skipped where compaction removed entries.
+    pub fn read(&self, key: &str, from: usize, max: usize) -> anyhow::Result<Vec<Log<V>>> {
+        match &self.backend {
+            Backend::Memory(logs) => Ok(logs
+                .get(key)
+                .into_iter()
+                .flat_map(|l| &l.entries)
+                .skip_while(|l| l.offset < from)
+                .take(max)
+                .cloned()
+                .collect()),
+            Backend::Disk(storage) => storage
+                .read(key, from as u64, max)
+                .context("read segments")?
+                .into_iter()
+                .map(|(_, data)| serde_json::from_slice(&data).context("corrupt log entry"))
+                .collect(),
+        }
+    }
+
+    /// The entries from `from` up to `end` that a poll at `isolation`
+    /// returns, at most `max` of them. Markers and aborted messages take up
+    /// offsets too, so reading goes on past them until the batch is full.
+    pub fn read_visible(
+        &self,
+        key: &str,
+        mut from: usize,
+        end: usize,
+        isolation: Isolation,
+        max: usize,
+    ) -> anyhow::Result<Vec<Log<V>>> {
+        let mut visible = Vec::new();
+        while visible.len() < max && from <= end {
+            let batch = self.read(key, from, max)?;
+            let Some(last) = batch.last() else {
+                break;
+            };
+            from = last.offset + 1;
+            for l in batch.into_iter().take_while(|l| l.offset <= end) {
+                if visible.len() < max && self.visible(key, &l, isolation) {
+                    visible.push(l);
+                }
+            }
+        }
+        Ok(visible)
+    }
+
+    /// The offset of the first entry appended at or after `timestamp`
+    /// (milliseconds since the epoch) and no later than `end`. The time
+    /// index narrows the scan down to the entries after its last mark
+    /// before `timestamp`.
+    pub fn offset_for_time(
+        &self,
+        key: &str,
+        timestamp: u64,
+        end: usize,
+    ) -> anyhow::Result<Option<usize>> {
+        let mut from = self.times.get(key).map_or(0, |t| t.scan_from(timestamp));
+        loop {
+            let batch = self.read(key, from, READ_BATCH)?;
+            let Some(last) = batch.last() else {
+                return Ok(None);
+            };
+            let mut entries = batch.iter().take_while(|l| l.offset <= end);
+            if let Some(l) = entries.find(|l| l.timestamp >= timestamp) {
+                return Ok(Some(l.offset));
+            }
+            if last.offset >= end {
+                return Ok(None);
+            }
+            from = last.offset + 1;
+        }
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        match &self.backend {
+            Backend::Memory(logs) => logs.keys().cloned().collect(),
+            Backend::Disk(storage) => storage.keys().cloned().collect(),
+        }
+    }
+
+    /// The offset of the first entry left in `key`'s log, or the one the
+    /// next entry will get if none is.
+    pub fn start(&self, key: &str) -> anyhow::Result<usize> {
+        let first = self.read(key, 0, 1)?;
+        Ok(first.first().map_or(self.end(key) + 1, |l| l.offset))
+    }
+
+    /// Drops the entries before `offset`. Offsets are not reused.
+    pub fn truncate_before(&mut self, key: &str, offset: usize) -> anyhow::Result<()> {
+        match &mut self.backend {
+            Backend::Memory(logs) => {
+                if let Some(log) = logs.get_mut(key) {
+                    while log.entries.front().is_some_and(|l| l.offset < offset) {
+                        log.entries.pop_front();
+                    }
+                }
+            }
+            Backend::Disk(storage) => storage
+                .truncate_before(key, offset as u64)
+                .context("truncate segments")?,
+        }
+        Ok(())
+    }
+
+    /// Drops every entry of `key` and forgets its producers and
+    /// transactions. The end stays, so later appends do not reuse offsets
+    /// consumers may have read already.
+    pub fn delete(&mut self, key: &str) -> anyhow::Result<()> {
+        let end = self.end(key);
+        self.truncate_before(key, end + 1)?;
+        self.producers.remove(key);
+        self.txns.remove(key);
+        self.times.remove(key);
+        Ok(())
+    }
+
+    /// Applies retention to every log, then compacts it if asked to.
+    pub fn clean(&mut self, retention: &Retention, compact: bool) -> anyhow::Result<()> {
+        match &mut self.backend {
+            Backend::Memory(logs) => {
+                for log in logs.values_mut() {
+                    log.clean(retention, compact);
+                }
+            }
+            Backend::Disk(storage) => {
+                for (key, log) in storage.logs_mut() {
+                    if compact {
+                        log.compact(|data| {
+                            let log: Log<V> = serde_json::from_slice(data).ok()?;
+                            log.sub_key.map(String::into_bytes)
+                        })
+                        .with_context(|| format!("compact {}", key))?;
+                    }
+                    log.apply_retention(retention)
+                        .with_context(|| format!("apply retention to {}", key))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const KEY: &str = "k";
+
+    fn sent(producer: &str, seq: u64, value: Value) -> Log {
+        Log {
+            producer: Some(producer.to_string()),
+            seq: Some(seq),
+            ..Log::new(value)
+        }
+    }
+
+    fn in_txn(id: &str, value: Value, outcome: Option<Outcome>) -> Log {
+        Log {
+            txn: Some(TxnRef {
+                id: id.to_string(),
+                home: KEY.to_string(),
+                outcome,
+            }),
+            ..Log::new(value)
+        }
+    }
+
+    fn values(logs: Vec<Log>) -> Vec<Value> {
+        logs.into_iter().map(|l| l.value).collect()
+    }
+
+    #[test]
+    fn remembers_a_window_of_producer_sequences() {
+        let mut store = LogStore::in_memory();
+        for seq in 0..=PRODUCER_WINDOW as u64 {
+            store
+                .append(KEY.into(), sent("p", seq, json!(seq)))
+                .unwrap();
+        }
+        assert_eq!(store.duplicate(KEY, "p", 3).unwrap(), Some(4));
+        assert_eq!(store.duplicate(KEY, "p", 9).unwrap(), None);
+        assert_eq!(store.duplicate(KEY, "q", 0).unwrap(), None);
+        let err = store.duplicate(KEY, "p", 0).unwrap_err();
+        assert_eq!(RpcError::code_of(&err), Some(PRECONDITION_FAILED));
+    }
+
+    #[test]
+    fn read_committed_stops_before_pending_transactions() {
+        let mut store = LogStore::in_memory();
+        store.append(KEY.into(), Log::new(json!(1))).unwrap();
+        store
+            .append(KEY.into(), in_txn("a", json!(2), None))
+            .unwrap();
+        store
+            .append(KEY.into(), in_txn("b", json!(3), None))
+            .unwrap();
+        store.append(KEY.into(), Log::new(json!(4))).unwrap();
+        assert_eq!(store.stable_end(KEY), 1);
+
+        store
+            .append(KEY.into(), in_txn("b", json!(null), Some(Outcome::Abort)))
+            .unwrap();
+        store
+            .append(KEY.into(), in_txn("a", json!(null), Some(Outcome::Commit)))
+            .unwrap();
+        assert_eq!(store.stable_end(KEY), usize::MAX);
+        assert_eq!(store.ended(KEY, "a"), Some((Outcome::Commit, 6)));
+
+        let committed = store.read_visible(KEY, 1, 6, Isolation::ReadCommitted, 10);
+        assert_eq!(values(committed.unwrap()), [json!(1), json!(2), json!(4)]);
+        let all = store.read_visible(KEY, 1, 6, Isolation::ReadUncommitted, 10);
+        assert_eq!(
+            values(all.unwrap()),
+            [json!(1), json!(2), json!(3), json!(4)]
+        );
+        let first = store.read_visible(KEY, 2, 6, Isolation::ReadCommitted, 2);
+        assert_eq!(values(first.unwrap()), [json!(2), json!(4)]);
+    }
+
+    #[test]
+    fn truncating_forgets_sequences_and_markers_past_the_cut() {
+        let mut store = LogStore::in_memory();
+        store.append(KEY.into(), sent("p", 0, json!(1))).unwrap();
+        store
+            .append(KEY.into(), in_txn("a", json!(2), None))
+            .unwrap();
+        store.append(KEY.into(), sent("p", 1, json!(3))).unwrap();
+        store
+            .append(KEY.into(), in_txn("a", json!(null), Some(Outcome::Commit)))
+            .unwrap();
+
+        store.truncate_after(KEY, 2).unwrap();
+        assert_eq!(store.end(KEY), 2);
+        assert_eq!(store.duplicate(KEY, "p", 1).unwrap(), None);
+        assert_eq!(store.ended(KEY, "a"), None);
+        assert_eq!(store.stable_end(KEY), 1);
+        assert_eq!(store.append(KEY.into(), Log::new(json!(5))).unwrap(), 3);
+    }
+
+    #[test]
+    fn finds_offsets_by_time_across_index_marks() {
+        let mut store = LogStore::in_memory();
+        // timestamps of replicated entries may go back a little
+        let timestamps: Vec<u64> = (1..=350).map(|o| 100 + o * 10 - (o % 3) * 15).collect();
+        for (i, timestamp) in timestamps.iter().enumerate() {
+            let log = Log {
+                offset: i + 1,
+                timestamp: *timestamp,
+                ..Log::new(json!(i))
+            };
+            store.append_at(KEY.into(), log).unwrap();
+        }
+        for t in [0, 105, 1095, 1100, 1105, 2600, 3700] {
+            let expected = timestamps.iter().position(|ts| *ts >= t).map(|i| i + 1);
+            assert_eq!(
+                store.offset_for_time(KEY, t, 350).unwrap(),
+                expected,
+                "{}",
+                t
+            );
+        }
+        assert_eq!(store.offset_for_time(KEY, 3100, 200).unwrap(), None);
+
+        // appends carry on from the latest timestamp
+        let offset = store.append(KEY.into(), Log::new(json!(0))).unwrap();
+        let appended = store.read(KEY, offset, 1).unwrap();
+        assert!(appended[0].timestamp >= timestamps[349]);
+    }
+
+    #[test]
+    fn retention_and_compaction_keep_offsets() {
+        let mut store = LogStore::in_memory();
+        for (i, sub_key) in ["a", "b", "a", "c", "a"].into_iter().enumerate() {
+            let log = Log {
+                sub_key: Some(sub_key.to_string()),
+                ..Log::new(json!(i + 1))
+            };
+            store.append(KEY.into(), log).unwrap();
+        }
+        store.clean(&Retention::default(), true).unwrap();
+        let offsets: Vec<usize> = store
+            .read(KEY, 0, 10)
+            .unwrap()
+            .iter()
+            .map(|l| l.offset)
+            .collect();
+        assert_eq!(offsets, [2, 4, 5]);
+
+        let retention = Retention {
+            max_records: Some(1),
+            ..Retention::default()
+        };
+        store.clean(&retention, false).unwrap();
+        assert_eq!(store.start(KEY).unwrap(), 5);
+        assert_eq!(store.append(KEY.into(), Log::new(json!(6))).unwrap(), 6);
+    }
+}
@@ -0,0 +1,620 @@
+//! SWIM-style membership (Das, Gupta, Motivala 2002).
+//!
+//! Every protocol period a node pings one member. If no ack arrives within the
+//! ping timeout it asks a few other members to ping the target on its behalf,
+//! and if those fail too the target becomes suspect. Suspects that do not
+//! refute the suspicion (by bumping their incarnation) within the suspicion
+//! timeout are confirmed dead. Membership updates are piggybacked on pings and
+//! acks, each one retransmitted `O(log n)` times.
+//!
+//! The protocol is a plain state machine: the node feeds it messages and
+//! ticks, and sends the returned envelopes through its own output.
+
+use crate::failure_detector::MembershipEvent;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+const MAX_PIGGYBACK: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Update {
+    pub node: String,
+    pub state: State,
+    pub incarnation: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+#[serde(rename_all = "snake_case")]
+pub enum SwimMsg {
+    Ping {
+        seq: u64,
+        updates: Vec<Update>,
+    },
+    Ack {
+        seq: u64,
+        updates: Vec<Update>,
+    },
+    PingReq {
+        seq: u64,
+        target: String,
+        updates: Vec<Update>,
+    },
+    Join {
+        updates: Vec<Update>,
+    },
+    JoinOk {
+        updates: Vec<Update>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub to: String,
+    pub msg: SwimMsg,
+}
+
+#[derive(Debug, Clone)]
+pub struct SwimConfig {
+    pub period: Duration,
+    pub ping_timeout: Duration,
+    pub suspect_timeout: Duration,
+    pub indirect_probes: usize,
+}
+
+impl Default for SwimConfig {
+    fn default() -> Self {
+        Self {
+            period: Duration::from_millis(1000),
+            ping_timeout: Duration::from_millis(300),
+            suspect_timeout: Duration::from_millis(5000),
+            indirect_probes: 3,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Member {
+    state: State,
+    incarnation: u64,
+    suspect_since: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Probe {
+    target: String,
+    sent_at: Instant,
+    indirect: bool,
+    /// Set when we ping on behalf of another member's `PingReq`.
+    relay_for: Option<(String, u64)>,
+}
+
+#[derive(Debug)]
+pub struct Swim {
+    me: String,
+    incarnation: u64,
+    config: SwimConfig,
+    members: BTreeMap<String, Member>,
+    probes: HashMap<u64, Probe>,
+    seq: u64,
+    next_probe: usize,
+    last_probe: Option<Instant>,
+    broadcasts: Vec<(Update, usize)>,
+    events: Vec<MembershipEvent>,
+    rng: u64,
+}
+
+impl Swim {
+    pub fn new(me: impl Into<String>, config: SwimConfig) -> Self {
+        let me = me.into();
+        let rng = me.bytes().fold(0x9e37_79b9_7f4a_7c15, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x100_0000_01b3)
+        });
+        Self {
+            me,
+            incarnation: 0,
+            config,
+            members: BTreeMap::new(),
+            probes: HashMap::new(),
+            seq: 0,
+            next_probe: 0,
+            last_probe: None,
+            broadcasts: Vec::new(),
+            events: Vec::new(),
+            rng,
+        }
+    }
+
+    /// Members currently believed alive (suspects included), excluding us.
+    pub fn members(&self) -> Vec<String> {
+        self.members
+            .iter()
+            .filter(|(_, m)| m.state != State::Dead)
+            .map(|(n, _)| n.clone())
+            .collect()
+    }
+
+    pub fn state_of(&self, node: &str) -> Option<State> {
+        self.members.get(node).map(|m| m.state)
+    }
+
+    /// Membership changes since the last call.
+    pub fn drain_events(&mut self) -> Vec<MembershipEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Announces us to `seeds`. They answer with their full membership view.
+    pub fn join(&mut self, seeds: impl IntoIterator<Item = String>) -> Vec<Envelope> {
+        seeds
+            .into_iter()
+            .filter(|s| *s != self.me)
+            .map(|to| Envelope {
+                to,
+                msg: SwimMsg::Join {
+                    updates: vec![self.alive_update()],
+                },
+            })
+            .collect()
+    }
+
+    pub fn tick(&mut self, now: Instant) -> Vec<Envelope> {
+        let mut out = Vec::new();
+
+        let mut expired = Vec::new();
+        let mut escalate = Vec::new();
+        for (seq, probe) in &self.probes {
+            let waited = now.duration_since(probe.sent_at);
+            if probe.relay_for.is_some() || probe.indirect {
+                if waited >= self.config.period {
+                    expired.push(*seq);
+                }
+            } else if waited >= self.config.ping_timeout {
+                escalate.push(*seq);
+            }
+        }
+        for seq in expired {
+            let probe = self.probes.remove(&seq).unwrap();
+            if probe.relay_for.is_none() {
+                self.suspect(&probe.target, now);
+            }
+        }
+        for seq in escalate {
+            let target = self.probes[&seq].target.clone();
+            self.probes.get_mut(&seq).unwrap().indirect = true;
+            for helper in self.pick_helpers(&target) {
+                let updates = self.piggyback();
+                out.push(Envelope {
+                    to: helper,
+                    msg: SwimMsg::PingReq {
+                        seq,
+                        target: target.clone(),
+                        updates,
+                    },
+                });
+            }
+        }
+
+        let timed_out: Vec<_> = self
+            .members
+            .iter()
+            .filter(|(_, m)| {
+                m.state == State::Suspect
+                    && m.suspect_since
+                        .is_some_and(|s| now.duration_since(s) >= self.config.suspect_timeout)
+            })
+            .map(|(n, m)| (n.clone(), m.incarnation))
+            .collect();
+        for (node, incarnation) in timed_out {
+            self.apply(
+                Update {
+                    node,
+                    state: State::Dead,
+                    incarnation,
+                },
+                now,
+            );
+        }
+
+        if self
+            .last_probe
+            .is_none_or(|t| now.duration_since(t) >= self.config.period)
+        {
+            self.last_probe = Some(now);
+            if let Some(target) = self.next_target() {
+                let seq = self.next_seq();
+                self.probes.insert(
+                    seq,
+                    Probe {
+                        target: target.clone(),
+                        sent_at: now,
+                        indirect: false,
+                        relay_for: None,
+                    },
+                );
+                let updates = self.piggyback();
+                out.push(Envelope {
+                    to: target,
+                    msg: SwimMsg::Ping { seq, updates },
+                });
+            }
+        }
+
+        out
+    }
+
+    pub fn handle(&mut self, from: &str, msg: SwimMsg, now: Instant) -> Vec<Envelope> {
+        let mut out = Vec::new();
+        match msg {
+            SwimMsg::Ping { seq, updates } => {
+                self.apply_all(updates, now);
+                let updates = self.piggyback();
+                out.push(Envelope {
+                    to: from.to_string(),
+                    msg: SwimMsg::Ack { seq, updates },
+                });
+            }
+            SwimMsg::Ack { seq, updates } => {
+                self.apply_all(updates, now);
+                if let Some(Probe {
+                    relay_for: Some((requester, their_seq)),
+                    ..
+                }) = self.probes.remove(&seq)
+                {
+                    let updates = self.piggyback();
+                    out.push(Envelope {
+                        to: requester,
+                        msg: SwimMsg::Ack {
+                            seq: their_seq,
+                            updates,
+                        },
+                    });
+                }
+            }
+            SwimMsg::PingReq {
+                seq,
+                target,
+                updates,
+            } => {
+                self.apply_all(updates, now);
+                let our_seq = self.next_seq();
+                self.probes.insert(
+                    our_seq,
+                    Probe {
+                        target: target.clone(),
+                        sent_at: now,
+                        indirect: false,
+                        relay_for: Some((from.to_string(), seq)),
+                    },
+                );
+                let updates = self.piggyback();
+                out.push(Envelope {
+                    to: target,
+                    msg: SwimMsg::Ping {
+                        seq: our_seq,
+                        updates,
+                    },
+                });
+            }
+            SwimMsg::Join { updates } => {
+                for mut u in updates {
+                    // a member we buried may have restarted and lost its
+                    // incarnation; its join outranks the old record so the
+                    // rest of the cluster takes it back too
+                    if u.node == from && u.state == State::Alive {
+                        if let Some(m) = self.members.get(from).filter(|m| m.state == State::Dead) {
+                            u.incarnation = u.incarnation.max(m.incarnation + 1);
+                        }
+                    }
+                    self.apply(u, now);
+                }
+                let mut updates: Vec<_> = self
+                    .members
+                    .iter()
+                    .map(|(node, m)| Update {
+                        node: node.clone(),
+                        state: m.state,
+                        incarnation: m.incarnation,
+                    })
+                    .collect();
+                updates.push(self.alive_update());
+                out.push(Envelope {
+                    to: from.to_string(),
+                    msg: SwimMsg::JoinOk { updates },
+                });
+            }
+            SwimMsg::JoinOk { updates } => self.apply_all(updates, now),
+        }
+        out
+    }
+
+    fn apply_all(&mut self, updates: Vec<Update>, now: Instant) {
+        for u in updates {
+            self.apply(u, now);
+        }
+    }
+
+    /// Applies an update if it supersedes what we know, and queues it for
+    /// further dissemination.
+    fn apply(&mut self, u: Update, now: Instant) {
+        if u.node == self.me {
+            // a seed may know us by a later incarnation than we remember
+            self.incarnation = self.incarnation.max(u.incarnation);
+            if u.state != State::Alive && u.incarnation >= self.incarnation {
+                // refute by outliving the suspicion
+                self.incarnation = u.incarnation + 1;
+                let alive = self.alive_update();
+                self.disseminate(alive);
+            }
+            return;
+        }
+
+        let accept = match self.members.get(&u.node) {
+            None => true,
+            Some(m) => match u.state {
+                State::Alive => u.incarnation > m.incarnation,
+                State::Suspect => {
+                    (m.state == State::Alive && u.incarnation >= m.incarnation)
+                        || u.incarnation > m.incarnation
+                }
+                State::Dead => m.state != State::Dead && u.incarnation >= m.incarnation,
+            },
+        };
+        if !accept {
+            return;
+        }
+
+        let was_up = self
+            .members
+            .get(&u.node)
+            .is_some_and(|m| m.state != State::Dead);
+        let is_up = u.state != State::Dead;
+        if was_up != is_up {
+            self.events.push(if is_up {
+                MembershipEvent::Up(u.node.clone())
+            } else {
+                MembershipEvent::Down(u.node.clone())
+            });
+        }
+        self.members.insert(
+            u.node.clone(),
+            Member {
+                state: u.state,
+                incarnation: u.incarnation,
+                suspect_since: (u.state == State::Suspect).then_some(now),
+            },
+        );
+        self.disseminate(u);
+    }
+
+    fn suspect(&mut self, node: &str, now: Instant) {
+        let Some(m) = self.members.get(node) else {
+            return;
+        };
+        if m.state == State::Alive {
+            let incarnation = m.incarnation;
+            self.apply(
+                Update {
+                    node: node.to_string(),
+                    state: State::Suspect,
+                    incarnation,
+                },
+                now,
+            );
+        }
+    }
+
+    fn disseminate(&mut self, u: Update) {
+        let n = self.members.len() + 1;
+        let transmissions = 3 * (usize::BITS - n.leading_zeros()) as usize;
+        self.broadcasts.retain(|(b, _)| b.node != u.node);
+        self.broadcasts.push((u, transmissions));
+    }
+
+    fn piggyback(&mut self) -> Vec<Update> {
+        // least transmitted first, so fresh news spreads fastest
+        self.broadcasts
+            .sort_by_key(|(_, left)| std::cmp::Reverse(*left));
+        let updates = self
+            .broadcasts
+            .iter_mut()
+            .take(MAX_PIGGYBACK)
+            .map(|(u, left)| {
+                *left -= 1;
+                u.clone()
+            })
+            .collect();
+        self.broadcasts.retain(|(_, left)| *left > 0);
+        updates
+    }
+
+    fn alive_update(&self) -> Update {
+        Update {
+            node: self.me.clone(),
+            state: State::Alive,
+            incarnation: self.incarnation,
+        }
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    fn next_target(&mut self) -> Option<String> {
+        let candidates = self.members();
+        if candidates.is_empty() {
+            return None;
+        }
+        self.next_probe = (self.next_probe + 1) % candidates.len();
+        Some(candidates[self.next_probe].clone())
+    }
+
+    fn pick_helpers(&mut self, target: &str) -> Vec<String> {
+        let mut candidates: Vec<_> = self
+            .members
+            .iter()
+            .filter(|(n, m)| m.state == State::Alive && *n != target)
+            .map(|(n, _)| n.clone())
+            .collect();
+        let mut helpers = Vec::new();
+        while helpers.len() < self.config.indirect_probes && !candidates.is_empty() {
+            // xorshift; quality is irrelevant, we only want to spread the load
+            self.rng ^= self.rng << 13;
+            self.rng ^= self.rng >> 7;
+            self.rng ^= self.rng << 17;
+            let i = (self.rng % candidates.len() as u64) as usize;
+            helpers.push(candidates.swap_remove(i));
+        }
+        helpers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Cluster {
+        nodes: BTreeMap<String, Swim>,
+        down: Vec<String>,
+        now: Instant,
+    }
+
+    impl Cluster {
+        fn new(names: &[&str]) -> Self {
+            let nodes = names
+                .iter()
+                .map(|n| (n.to_string(), Swim::new(*n, SwimConfig::default())))
+                .collect();
+            Self {
+                nodes,
+                down: Vec::new(),
+                now: Instant::now(),
+            }
+        }
+
+        fn deliver(&mut self, from: String, mut queue: Vec<Envelope>) {
+            let mut pending: Vec<_> = queue.drain(..).map(|e| (from.clone(), e)).collect();
+            while let Some((from, e)) = pending.pop() {
+                if self.down.contains(&e.to) || self.down.contains(&from) {
+                    continue;
+                }
+                let Some(node) = self.nodes.get_mut(&e.to) else {
+                    continue;
+                };
+                let to = e.to.clone();
+                for out in node.handle(&from, e.msg, self.now) {
+                    pending.push((to.clone(), out));
+                }
+            }
+        }
+
+        fn run(&mut self, duration: Duration) {
+            let step = Duration::from_millis(100);
+            let mut elapsed = Duration::ZERO;
+            while elapsed < duration {
+                self.now += step;
+                elapsed += step;
+                let names: Vec<_> = self.nodes.keys().cloned().collect();
+                for name in names {
+                    if self.down.contains(&name) {
+                        continue;
+                    }
+                    let out = self.nodes.get_mut(&name).unwrap().tick(self.now);
+                    self.deliver(name, out);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nodes_join_through_a_seed() {
+        let mut c = Cluster::new(&["n0", "n1", "n2", "n3"]);
+        for n in ["n1", "n2", "n3"] {
+            let out = c.nodes.get_mut(n).unwrap().join(["n0".to_string()]);
+            c.deliver(n.to_string(), out);
+        }
+        c.run(Duration::from_secs(10));
+        for (name, node) in &c.nodes {
+            assert_eq!(node.members().len(), 3, "{name} sees {:?}", node.members());
+        }
+    }
+
+    #[test]
+    fn crashed_node_is_confirmed_dead() {
+        let mut c = Cluster::new(&["n0", "n1", "n2"]);
+        for n in ["n1", "n2"] {
+            let out = c.nodes.get_mut(n).unwrap().join(["n0".to_string()]);
+            c.deliver(n.to_string(), out);
+        }
+        c.run(Duration::from_secs(3));
+        c.down.push("n2".to_string());
+        c.run(Duration::from_secs(15));
+        for n in ["n0", "n1"] {
+            assert_eq!(c.nodes[n].state_of("n2"), Some(State::Dead));
+            assert!(c
+                .nodes
+                .get_mut(n)
+                .unwrap()
+                .drain_events()
+                .contains(&MembershipEvent::Down("n2".to_string())));
+        }
+    }
+
+    #[test]
+    fn restarted_node_rejoins() {
+        let mut c = Cluster::new(&["n0", "n1", "n2"]);
+        for n in ["n1", "n2"] {
+            let out = c.nodes.get_mut(n).unwrap().join(["n0".to_string()]);
+            c.deliver(n.to_string(), out);
+        }
+        c.run(Duration::from_secs(3));
+        c.down.push("n2".to_string());
+        c.run(Duration::from_secs(15));
+        assert_eq!(c.nodes["n0"].state_of("n2"), Some(State::Dead));
+
+        // comes back with no memory of its old incarnation
+        c.down.clear();
+        c.nodes
+            .insert("n2".to_string(), Swim::new("n2", SwimConfig::default()));
+        let out = c.nodes.get_mut("n2").unwrap().join(["n0".to_string()]);
+        c.deliver("n2".to_string(), out);
+        c.run(Duration::from_secs(10));
+        for n in ["n0", "n1"] {
+            assert_eq!(c.nodes[n].state_of("n2"), Some(State::Alive), "{n}");
+            assert!(c
+                .nodes
+                .get_mut(n)
+                .unwrap()
+                .drain_events()
+                .contains(&MembershipEvent::Up("n2".to_string())));
+        }
+        assert_eq!(c.nodes["n2"].members().len(), 2);
+    }
+
+    #[test]
+    fn suspected_node_refutes() {
+        let mut s = Swim::new("n0", SwimConfig::default());
+        let now = Instant::now();
+        s.apply(
+            Update {
+                node: "n0".to_string(),
+                state: State::Suspect,
+                incarnation: 0,
+            },
+            now,
+        );
+        assert_eq!(s.incarnation, 1);
+        assert_eq!(s.piggyback()[0].state, State::Alive);
+    }
+}
@@ -0,0 +1,872 @@
+//! Multi-Paxos replicated log.
+//!
+//! Every node is proposer, acceptor and learner. A node that has not heard
+//! from a leader within its lease runs phase 1 (prepare/promise) once for all
+//! slots above what it has applied, re-proposes whatever the promises report
+//! as possibly chosen, and from then on only runs phase 2 (accept/accepted)
+//! for new commands: the stable-leader optimization. Ballots are
+//! `(round, node)` pairs so they are totally ordered and unique per node.
+//!
+//! Accept messages double as leader heartbeats and carry the leader's commit
+//! point. Followers learn chosen values from accepts at the leader's ballot
+//! and catch up on anything else, including compacted history as a snapshot,
+//! by asking the leader.
+
+use crate::{
+    rsm::{Proposal, RsmError, StateMachine},
+    Body, Message,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+const MAX_ENTRIES_PER_MESSAGE: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct PaxosConfig {
+    pub lease_timeout_min: Duration,
+    pub lease_timeout_max: Duration,
+    pub heartbeat_interval: Duration,
+    /// Number of applied slots after which history is compacted.
+    pub snapshot_threshold: u64,
+}
+
+impl Default for PaxosConfig {
+    fn default() -> Self {
+        Self {
+            lease_timeout_min: Duration::from_millis(1000),
+            lease_timeout_max: Duration::from_millis(2000),
+            heartbeat_interval: Duration::from_millis(200),
+            snapshot_threshold: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Ballot {
+    pub round: u64,
+    pub node: String,
+}
+
+/// A proposed value. `origin`/`seq` identify the proposal so the proposer can
+/// tell its own command from someone else's in the same slot; `command` is
+/// `None` for the no-ops a new leader uses to fill gaps.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Value<C> {
+    pub origin: String,
+    pub seq: u64,
+    pub command: Option<C>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SlotValue<C> {
+    pub slot: u64,
+    pub value: Value<C>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AcceptedValue<C> {
+    pub slot: u64,
+    pub ballot: Ballot,
+    pub value: Value<C>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "rpc")]
+#[serde(rename_all = "snake_case")]
+pub enum PaxosMsg<C> {
+    Prepare {
+        ballot: Ballot,
+        from: u64,
+    },
+    /// `applied` tells the new leader which slots are already chosen, even
+    /// if this acceptor has compacted them away.
+    Promise {
+        ballot: Ballot,
+        applied: u64,
+        accepted: Vec<AcceptedValue<C>>,
+    },
+    Accept {
+        ballot: Ballot,
+        entries: Vec<SlotValue<C>>,
+        commit: u64,
+    },
+    Accepted {
+        ballot: Ballot,
+        slots: Vec<u64>,
+    },
+    Nack {
+        ballot: Ballot,
+        promised: Ballot,
+    },
+    Catchup {
+        from: u64,
+    },
+    Learn {
+        entries: Vec<SlotValue<C>>,
+    },
+    Snapshot {
+        applied: u64,
+        data: serde_json::Value,
+    },
+}
+
+pub type Outbox<C> = Vec<Message<PaxosMsg<C>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+struct InFlight<C> {
+    value: Value<C>,
+    acks: HashSet<String>,
+}
+
+type Waiter<O> = oneshot::Sender<Result<O, RsmError>>;
+
+/// An acceptor's applied slot and everything it accepted above it.
+type PromiseOf<C> = (u64, Vec<AcceptedValue<C>>);
+
+pub struct MultiPaxos<S: StateMachine> {
+    me: String,
+    peers: Vec<String>,
+    config: PaxosConfig,
+    sm: S,
+    role: Role,
+    leader: Option<String>,
+
+    // acceptor
+    promised: Ballot,
+    accepted: BTreeMap<u64, (Ballot, Value<S::Command>)>,
+
+    // proposer
+    ballot: Ballot,
+    promises: HashMap<String, PromiseOf<S::Command>>,
+    next_slot: u64,
+    in_flight: BTreeMap<u64, InFlight<S::Command>>,
+    unsent: Vec<u64>,
+    seq: u64,
+    /// The acceptor we learn chosen slots from after an election, and the
+    /// slot it had applied, while we are still behind it.
+    catchup: Option<(String, u64)>,
+
+    // learner
+    chosen: BTreeMap<u64, Value<S::Command>>,
+    history: BTreeMap<u64, Value<S::Command>>,
+    applied: u64,
+    snapshot_slot: u64,
+    snapshot: serde_json::Value,
+
+    deadline: Instant,
+    last_heartbeat: Instant,
+    waiters: HashMap<u64, (u64, Waiter<S::Output>)>,
+    rng: u64,
+}
+
+impl<S: StateMachine> MultiPaxos<S> {
+    /// `nodes` is the whole cluster, this node included.
+    pub fn new(me: impl Into<String>, nodes: &[String], sm: S, config: PaxosConfig) -> Self {
+        let me = me.into();
+        let rng = me.bytes().fold(0x84222325cbf29ce4, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x100_0000_01b3)
+        });
+        let now = Instant::now();
+        let mut paxos = Self {
+            peers: nodes.iter().filter(|n| **n != me).cloned().collect(),
+            me,
+            config,
+            sm,
+            role: Role::Follower,
+            leader: None,
+            promised: Ballot::default(),
+            accepted: BTreeMap::new(),
+            ballot: Ballot::default(),
+            promises: HashMap::new(),
+            next_slot: 1,
+            in_flight: BTreeMap::new(),
+            unsent: Vec::new(),
+            seq: 0,
+            catchup: None,
+            chosen: BTreeMap::new(),
+            history: BTreeMap::new(),
+            applied: 0,
+            snapshot_slot: 0,
+            snapshot: serde_json::Value::Null,
+            deadline: now,
+            last_heartbeat: now,
+            waiters: HashMap::new(),
+            rng,
+        };
+        paxos.reset_deadline(now);
+        paxos
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn leader(&self) -> Option<&String> {
+        self.leader.as_ref()
+    }
+
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+
+    pub fn state_machine(&self) -> &S {
+        &self.sm
+    }
+
+    /// Assigns `command` the next slot if we are the stable leader. It is sent
+    /// to the acceptors on the next tick, batched with other proposals.
+    pub fn propose(&mut self, command: S::Command) -> Result<Proposal<S::Output>, RsmError> {
+        if self.role != Role::Leader {
+            return Err(RsmError::NotLeader(self.leader.clone()));
+        }
+        self.seq += 1;
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        let (tx, rx) = oneshot::channel();
+        self.waiters.insert(slot, (self.seq, tx));
+        let value = Value {
+            origin: self.me.clone(),
+            seq: self.seq,
+            command: Some(command),
+        };
+        self.start_accept(slot, value);
+        Ok(rx)
+    }
+
+    pub fn tick(&mut self, now: Instant) -> Outbox<S::Command> {
+        match self.role {
+            Role::Leader => {
+                let heartbeat =
+                    now.duration_since(self.last_heartbeat) >= self.config.heartbeat_interval;
+                if !heartbeat && self.unsent.is_empty() {
+                    return Vec::new();
+                }
+                // heartbeats retransmit everything still waiting for a quorum
+                let slots: Vec<u64> = if heartbeat {
+                    self.last_heartbeat = now;
+                    self.unsent.clear();
+                    self.in_flight.keys().copied().collect()
+                } else {
+                    std::mem::take(&mut self.unsent)
+                };
+                let mut out = Vec::new();
+                // an empty heartbeat still goes out, carrying the commit point
+                let batches = slots.chunks(MAX_ENTRIES_PER_MESSAGE);
+                for batch in batches.chain(slots.is_empty().then_some(&[][..])) {
+                    let entries = batch
+                        .iter()
+                        .map(|slot| SlotValue {
+                            slot: *slot,
+                            value: self.in_flight[slot].value.clone(),
+                        })
+                        .collect();
+                    let msg = PaxosMsg::Accept {
+                        ballot: self.ballot.clone(),
+                        entries,
+                        commit: self.applied,
+                    };
+                    out.extend(self.broadcast(msg));
+                }
+                if heartbeat {
+                    out.extend(self.retry_catchup());
+                }
+                out
+            }
+            Role::Follower | Role::Candidate => {
+                if now >= self.deadline {
+                    return self.start_prepare(now);
+                }
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        from: &str,
+        msg: PaxosMsg<S::Command>,
+        now: Instant,
+    ) -> Outbox<S::Command> {
+        match msg {
+            PaxosMsg::Prepare { ballot, from: slot } => {
+                if ballot < self.promised {
+                    return vec![self.message(
+                        from,
+                        PaxosMsg::Nack {
+                            ballot,
+                            promised: self.promised.clone(),
+                        },
+                    )];
+                }
+                self.observe(&ballot, now);
+                let accepted = self
+                    .accepted
+                    .range(slot.max(self.applied + 1)..)
+                    .map(|(slot, (ballot, value))| AcceptedValue {
+                        slot: *slot,
+                        ballot: ballot.clone(),
+                        value: value.clone(),
+                    })
+                    .collect();
+                vec![self.message(
+                    from,
+                    PaxosMsg::Promise {
+                        ballot,
+                        applied: self.applied,
+                        accepted,
+                    },
+                )]
+            }
+            PaxosMsg::Promise {
+                ballot,
+                applied,
+                accepted,
+            } => {
+                if self.role != Role::Candidate || ballot != self.ballot {
+                    return Vec::new();
+                }
+                self.promises.insert(from.to_string(), (applied, accepted));
+                if self.promises.len() >= self.quorum() {
+                    return self.become_leader(now);
+                }
+                Vec::new()
+            }
+            PaxosMsg::Accept {
+                ballot,
+                entries,
+                commit,
+            } => {
+                if ballot < self.promised {
+                    return vec![self.message(
+                        from,
+                        PaxosMsg::Nack {
+                            ballot,
+                            promised: self.promised.clone(),
+                        },
+                    )];
+                }
+                self.observe(&ballot, now);
+                let slots = entries.iter().map(|e| e.slot).collect();
+                for e in entries {
+                    if e.slot > self.applied {
+                        self.accepted.insert(e.slot, (ballot.clone(), e.value));
+                    }
+                }
+
+                // anything up to `commit` accepted at this ballot is chosen
+                for slot in self.applied + 1..=commit {
+                    if let Some((b, value)) = self.accepted.get(&slot) {
+                        if *b == ballot {
+                            self.chosen.insert(slot, value.clone());
+                        }
+                    }
+                }
+                self.apply_chosen();
+
+                let mut out = vec![self.message(from, PaxosMsg::Accepted { ballot, slots })];
+                if self.applied < commit {
+                    out.push(self.message(
+                        from,
+                        PaxosMsg::Catchup {
+                            from: self.applied + 1,
+                        },
+                    ));
+                }
+                out
+            }
+            PaxosMsg::Accepted { ballot, slots } => {
+                if self.role != Role::Leader || ballot != self.ballot {
+                    return Vec::new();
+                }
+                let quorum = self.quorum();
+                for slot in slots {
+                    let Some(f) = self.in_flight.get_mut(&slot) else {
+                        continue;
+                    };
+                    f.acks.insert(from.to_string());
+                    if f.acks.len() >= quorum {
+                        let f = self.in_flight.remove(&slot).unwrap();
+                        self.chosen.insert(slot, f.value);
+                    }
+                }
+                self.apply_chosen();
+                Vec::new()
+            }
+            PaxosMsg::Nack { ballot, promised } => {
+                if ballot == self.ballot && promised > self.ballot {
+                    self.observe(&promised, now);
+                }
+                Vec::new()
+            }
+            PaxosMsg::Catchup { from: slot } => self.catchup_for(from, slot),
+            PaxosMsg::Learn { entries } => {
+                for e in entries {
+                    if e.slot > self.applied {
+                        self.chosen.insert(e.slot, e.value);
+                    }
+                }
+                self.apply_chosen();
+                Vec::new()
+            }
+            PaxosMsg::Snapshot { applied, data } => {
+                if applied > self.applied {
+                    self.sm.restore(data.clone());
+                    self.applied = applied;
+                    self.snapshot_slot = applied;
+                    self.snapshot = data;
+                    self.history.clear();
+                    self.chosen = self.chosen.split_off(&(applied + 1));
+                    self.accepted = self.accepted.split_off(&(applied + 1));
+                    self.next_slot = self.next_slot.max(applied + 1);
+                    self.apply_chosen();
+                }
+                Vec::new()
+            }
+        }
+    }
+
+    /// Adopts a ballot at least as high as our promise: a new leader we must
+    /// follow, or our own.
+    fn observe(&mut self, ballot: &Ballot, now: Instant) {
+        if *ballot > self.promised {
+            self.promised = ballot.clone();
+        }
+        if ballot.node != self.me {
+            self.role = Role::Follower;
+            self.leader = Some(ballot.node.clone());
+            self.in_flight.clear();
+            self.unsent.clear();
+            self.catchup = None;
+            self.reset_deadline(now);
+        }
+    }
+
+    fn start_prepare(&mut self, now: Instant) -> Outbox<S::Command> {
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.ballot = Ballot {
+            round: self.promised.round + 1,
+            node: self.me.clone(),
+        };
+        self.promised = self.ballot.clone();
+        self.reset_deadline(now);
+
+        let own = self
+            .accepted
+            .range(self.applied + 1..)
+            .map(|(slot, (ballot, value))| AcceptedValue {
+                slot: *slot,
+                ballot: ballot.clone(),
+                value: value.clone(),
+            })
+            .collect();
+        self.promises = HashMap::from([(self.me.clone(), (self.applied, own))]);
+        if self.promises.len() >= self.quorum() {
+            return self.become_leader(now);
+        }
+        self.broadcast(PaxosMsg::Prepare {
+            ballot: self.ballot.clone(),
+            from: self.applied + 1,
+        })
+    }
+
+    fn become_leader(&mut self, now: Instant) -> Outbox<S::Command> {
+        self.role = Role::Leader;
+        self.leader = Some(self.me.clone());
+        self.last_heartbeat = now;
+
+        let promises = std::mem::take(&mut self.promises);
+        let mut out = Vec::new();
+        // slots an acceptor has applied are chosen; learn them instead of
+        // re-proposing, since their values may already be compacted away
+        let (base_node, base) = promises
+            .iter()
+            .map(|(node, (applied, _))| (node.clone(), *applied))
+            .max_by_key(|(_, applied)| *applied)
+            .unwrap();
+        self.catchup = Some((base_node, base));
+        out.extend(self.retry_catchup());
+
+        let mut highest: BTreeMap<u64, (Ballot, Value<S::Command>)> = BTreeMap::new();
+        for (_, accepted) in promises.into_values() {
+            for a in accepted {
+                if a.slot <= base {
+                    continue;
+                }
+                let keep = highest.get(&a.slot).is_some_and(|(b, _)| *b >= a.ballot);
+                if !keep {
+                    highest.insert(a.slot, (a.ballot, a.value));
+                }
+            }
+        }
+        let last = highest.keys().last().copied().unwrap_or(0).max(base);
+        for slot in base.max(self.applied) + 1..=last {
+            if self.chosen.contains_key(&slot) {
+                continue;
+            }
+            let value = highest.remove(&slot).map(|(_, v)| v).unwrap_or(Value {
+                origin: self.me.clone(),
+                seq: 0,
+                command: None,
+            });
+            self.start_accept(slot, value);
+        }
+        self.next_slot = self.next_slot.max(last + 1).max(self.applied + 1);
+        self.apply_chosen();
+        out
+    }
+
+    /// Asks for the chosen slots we are missing since the election. Sent
+    /// again every heartbeat until we have applied them, since a lost
+    /// `Catchup` or a partial answer would otherwise stall us for good.
+    fn retry_catchup(&mut self) -> Outbox<S::Command> {
+        match &self.catchup {
+            Some((node, base)) if self.applied < *base => vec![self.message(
+                node,
+                PaxosMsg::Catchup {
+                    from: self.applied + 1,
+                },
+            )],
+            _ => {
+                self.catchup = None;
+                Vec::new()
+            }
+        }
+    }
+
+    fn start_accept(&mut self, slot: u64, value: Value<S::Command>) {
+        self.accepted
+            .insert(slot, (self.ballot.clone(), value.clone()));
+        let mut f = InFlight {
+            value,
+            acks: HashSet::from([self.me.clone()]),
+        };
+        if f.acks.len() >= self.quorum() {
+            self.chosen.insert(slot, std::mem::take(&mut f.value));
+            self.apply_chosen();
+            return;
+        }
+        self.in_flight.insert(slot, f);
+        self.unsent.push(slot);
+    }
+
+    fn catchup_for(&self, to: &str, from: u64) -> Outbox<S::Command> {
+        let mut out = Vec::new();
+        let mut from = from;
+        if from <= self.snapshot_slot {
+            out.push(self.message(
+                to,
+                PaxosMsg::Snapshot {
+                    applied: self.snapshot_slot,
+                    data: self.snapshot.clone(),
+                },
+            ));
+            from = self.snapshot_slot + 1;
+        }
+        let entries: Vec<_> = self
+            .history
+            .range(from..)
+            .take(MAX_ENTRIES_PER_MESSAGE)
+            .map(|(slot, value)| SlotValue {
+                slot: *slot,
+                value: value.clone(),
+            })
+            .collect();
+        if !entries.is_empty() {
+            out.push(self.message(to, PaxosMsg::Learn { entries }));
+        }
+        out
+    }
+
+    fn apply_chosen(&mut self) {
+        while let Some(value) = self.chosen.remove(&(self.applied + 1)) {
+            self.applied += 1;
+            self.accepted.remove(&self.applied);
+            let output = value.command.as_ref().map(|c| self.sm.apply(c));
+            if let Some((seq, tx)) = self.waiters.remove(&self.applied) {
+                let res = match output {
+                    Some(output) if value.origin == self.me && value.seq == seq => Ok(output),
+                    _ => Err(RsmError::Dropped),
+                };
+                let _ = tx.send(res);
+            }
+            self.history.insert(self.applied, value);
+        }
+        if self.applied - self.snapshot_slot >= self.config.snapshot_threshold {
+            self.snapshot = self.sm.snapshot();
+            self.snapshot_slot = self.applied;
+            self.history.clear();
+        }
+    }
+
+    fn broadcast(&self, msg: PaxosMsg<S::Command>) -> Outbox<S::Command> {
+        self.peers
+            .iter()
+            .map(|p| self.message(p, msg.clone()))
+            .collect()
+    }
+
+    fn message(&self, to: &str, msg: PaxosMsg<S::Command>) -> Message<PaxosMsg<S::Command>> {
+        Message::new(
+            self.me.clone(),
+            to.to_string(),
+            Body {
+                id: None,
+                in_reply_to: None,
+                payload: msg,
+            },
+        )
+    }
+
+    fn quorum(&self) -> usize {
+        let n = self.peers.len() + 1;
+        n / 2 + 1
+    }
+
+    fn reset_deadline(&mut self, now: Instant) {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let spread = self.config.lease_timeout_max - self.config.lease_timeout_min;
+        let jitter = spread.mul_f64((self.rng % 1000) as f64 / 1000.0);
+        self.deadline = now + self.config.lease_timeout_min + jitter;
+    }
+}
+
+impl<C> Default for Value<C> {
+    fn default() -> Self {
+        Self {
+            origin: String::new(),
+            seq: 0,
+            command: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Sum(u64);
+
+    impl StateMachine for Sum {
+        type Command = u64;
+        type Output = u64;
+
+        fn apply(&mut self, command: &u64) -> u64 {
+            self.0 += command;
+            self.0
+        }
+
+        fn snapshot(&self) -> serde_json::Value {
+            serde_json::json!(self.0)
+        }
+
+        fn restore(&mut self, snapshot: serde_json::Value) {
+            self.0 = snapshot.as_u64().unwrap();
+        }
+    }
+
+    struct Cluster {
+        nodes: BTreeMap<String, MultiPaxos<Sum>>,
+        down: Vec<String>,
+        /// How many of the next `Catchup` messages to lose.
+        drop_catchups: usize,
+        now: Instant,
+    }
+
+    impl Cluster {
+        fn new(n: usize, snapshot_threshold: u64) -> Self {
+            let names: Vec<_> = (0..n).map(|i| format!("n{i}")).collect();
+            let config = PaxosConfig {
+                snapshot_threshold,
+                ..PaxosConfig::default()
+            };
+            let nodes = names
+                .iter()
+                .map(|n| {
+                    (
+                        n.clone(),
+                        MultiPaxos::new(n.clone(), &names, Sum::default(), config.clone()),
+                    )
+                })
+                .collect();
+            Self {
+                nodes,
+                down: Vec::new(),
+                drop_catchups: 0,
+                now: Instant::now(),
+            }
+        }
+
+        fn deliver(&mut self, mut queue: Outbox<u64>) {
+            while let Some(m) = queue.pop() {
+                if self.down.contains(&m.src) || self.down.contains(&m.dst) {
+                    continue;
+                }
+                if matches!(m.body.payload, PaxosMsg::Catchup { .. }) && self.drop_catchups > 0 {
+                    self.drop_catchups -= 1;
+                    continue;
+                }
+                let node = self.nodes.get_mut(&m.dst).unwrap();
+                queue.extend(node.handle(&m.src, m.body.payload, self.now));
+            }
+        }
+
+        fn run(&mut self, duration: Duration) {
+            let step = Duration::from_millis(50);
+            let mut elapsed = Duration::ZERO;
+            while elapsed < duration {
+                self.now += step;
+                elapsed += step;
+                let names: Vec<_> = self.nodes.keys().cloned().collect();
+                for name in names {
+                    if self.down.contains(&name) {
+                        continue;
+                    }
+                    let out = self.nodes.get_mut(&name).unwrap().tick(self.now);
+                    self.deliver(out);
+                }
+            }
+        }
+
+        fn leader(&self) -> String {
+            let leaders: Vec<_> = self
+                .nodes
+                .iter()
+                .filter(|(n, p)| p.role() == Role::Leader && !self.down.contains(n))
+                .map(|(n, _)| n.clone())
+                .collect();
+            assert_eq!(leaders.len(), 1, "leaders: {leaders:?}");
+            leaders[0].clone()
+        }
+
+        fn propose(&mut self, command: u64) -> Proposal<u64> {
+            let leader = self.leader();
+            let rx = self
+                .nodes
+                .get_mut(&leader)
+                .unwrap()
+                .propose(command)
+                .unwrap();
+            self.run(Duration::from_millis(50));
+            rx
+        }
+    }
+
+    #[test]
+    fn elects_a_stable_leader() {
+        let mut c = Cluster::new(3, 1000);
+        c.run(Duration::from_secs(5));
+        let leader = c.leader();
+        c.run(Duration::from_secs(5));
+        assert_eq!(c.leader(), leader);
+        for p in c.nodes.values() {
+            assert_eq!(p.leader(), Some(&leader));
+        }
+    }
+
+    #[test]
+    fn replicates_and_applies_commands() {
+        let mut c = Cluster::new(3, 1000);
+        c.run(Duration::from_secs(5));
+        for i in 1..=10 {
+            let rx = c.propose(i);
+            assert_eq!(rx.try_recv().unwrap(), Ok((1..=i).sum()));
+        }
+        c.run(Duration::from_secs(1));
+        for p in c.nodes.values() {
+            assert_eq!(p.state_machine().0, 55);
+        }
+    }
+
+    #[test]
+    fn sends_a_burst_of_proposals_on_one_tick() {
+        let mut c = Cluster::new(3, 1000);
+        c.run(Duration::from_secs(5));
+        let leader = c.leader();
+        let n = 2 * MAX_ENTRIES_PER_MESSAGE as u64 + 1;
+        let rxs: Vec<_> = (1..=n)
+            .map(|i| c.nodes.get_mut(&leader).unwrap().propose(i).unwrap())
+            .collect();
+        c.run(Duration::from_millis(50));
+        for (i, rx) in (1..=n).zip(rxs) {
+            assert_eq!(rx.try_recv().unwrap(), Ok((1..=i).sum()));
+        }
+    }
+
+    #[test]
+    fn followers_reject_proposals() {
+        let mut c = Cluster::new(3, 1000);
+        c.run(Duration::from_secs(5));
+        let leader = c.leader();
+        let follower = c.nodes.keys().find(|n| **n != leader).unwrap().clone();
+        let err = c.nodes.get_mut(&follower).unwrap().propose(1).err();
+        assert_eq!(err, Some(RsmError::NotLeader(Some(leader))));
+    }
+
+    #[test]
+    fn fails_over_and_keeps_chosen_values() {
+        let mut c = Cluster::new(5, 1000);
+        c.run(Duration::from_secs(5));
+        c.propose(1);
+        let old = c.leader();
+        c.down.push(old.clone());
+        c.run(Duration::from_secs(5));
+        assert_ne!(c.leader(), old);
+        let rx = c.propose(2);
+        assert_eq!(rx.try_recv().unwrap(), Ok(3));
+    }
+
+    #[test]
+    fn new_leader_retries_a_lost_catchup() {
+        let mut c = Cluster::new(3, 1000);
+        c.run(Duration::from_secs(5));
+        let old = c.leader();
+        let lagging = c.nodes.keys().find(|n| **n != old).unwrap().clone();
+        c.down.push(lagging.clone());
+        for i in 1..=20 {
+            c.propose(i);
+        }
+        c.run(Duration::from_secs(1));
+
+        // the lagging node wins the next election and must learn 1..=20
+        // before anything new can apply
+        c.down = vec![old];
+        c.drop_catchups = 1;
+        c.now += Duration::from_secs(10);
+        let out = c.nodes.get_mut(&lagging).unwrap().tick(c.now);
+        c.deliver(out);
+        assert_eq!(c.leader(), lagging);
+        assert_eq!(c.drop_catchups, 0);
+        c.run(Duration::from_secs(2));
+        let rx = c.propose(21);
+        assert_eq!(rx.try_recv().unwrap(), Ok((1..=21).sum()));
+    }
+
+    #[test]
+    fn lagging_learner_catches_up_from_snapshot() {
+        let mut c = Cluster::new(3, 5);
+        c.run(Duration::from_secs(5));
+        let leader = c.leader();
+        let lagging = c.nodes.keys().find(|n| **n != leader).unwrap().clone();
+        c.down.push(lagging.clone());
+        for i in 0..20 {
+            c.propose(i);
+        }
+        assert!(c.nodes[&leader].snapshot_slot > 0);
+        c.down.clear();
+        c.run(Duration::from_secs(2));
+        assert_eq!(c.nodes[&lagging].state_machine().0, (0..20).sum::<u64>());
+    }
+}
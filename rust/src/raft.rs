@@ -0,0 +1,847 @@
+//! Raft consensus (Ongaro & Ousterhout 2014).
+//!
+//! Leader election, log replication, commit tracking and snapshot-based log
+//! compaction, driving a pluggable [`StateMachine`]. RPCs are plain Maelstrom
+//! messages: every call returns the `Message`s the node should send, with a
+//! [`RaftMsg`] payload the node wraps into its own payload type.
+
+use crate::{
+    rsm::{Proposal, RsmError, StateMachine},
+    Body, Message,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+const MAX_ENTRIES_PER_APPEND: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    pub election_timeout_min: Duration,
+    pub election_timeout_max: Duration,
+    pub heartbeat_interval: Duration,
+    /// Number of applied entries after which the log is compacted.
+    pub snapshot_threshold: u64,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            election_timeout_min: Duration::from_millis(1000),
+            election_timeout_max: Duration::from_millis(2000),
+            heartbeat_interval: Duration::from_millis(200),
+            snapshot_threshold: 1000,
+        }
+    }
+}
+
+/// A log entry. `command` is `None` for the no-op a new leader appends to
+/// commit entries from earlier terms.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry<C> {
+    pub term: u64,
+    pub command: Option<C>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "rpc")]
+#[serde(rename_all = "snake_case")]
+pub enum RaftMsg<C> {
+    RequestVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    RequestVoteOk {
+        term: u64,
+        granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry<C>>,
+        leader_commit: u64,
+    },
+    /// On failure `match_index` is the follower's hint of where to resume.
+    AppendEntriesOk {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+    InstallSnapshot {
+        term: u64,
+        last_included_index: u64,
+        last_included_term: u64,
+        data: serde_json::Value,
+    },
+    InstallSnapshotOk {
+        term: u64,
+        match_index: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// Messages the node should send on behalf of the consensus module.
+pub type Outbox<C> = Vec<Message<RaftMsg<C>>>;
+
+type Waiter<O> = oneshot::Sender<Result<O, RsmError>>;
+
+pub struct Raft<S: StateMachine> {
+    me: String,
+    peers: Vec<String>,
+    config: RaftConfig,
+    sm: S,
+
+    term: u64,
+    voted_for: Option<String>,
+    role: Role,
+    leader: Option<String>,
+    votes: HashSet<String>,
+
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot: serde_json::Value,
+    log: Vec<Entry<S::Command>>,
+    commit_index: u64,
+    last_applied: u64,
+
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    /// Last index already sent to each peer, so ticks only send new entries.
+    sent_index: HashMap<String, u64>,
+
+    election_deadline: Instant,
+    last_heartbeat: Instant,
+    waiters: HashMap<u64, (u64, Waiter<S::Output>)>,
+    rng: u64,
+}
+
+impl<S: StateMachine> Raft<S> {
+    /// `nodes` is the whole cluster, this node included.
+    pub fn new(me: impl Into<String>, nodes: &[String], sm: S, config: RaftConfig) -> Self {
+        let me = me.into();
+        let rng = me.bytes().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+            (h ^ b as u64).wrapping_mul(0x100_0000_01b3)
+        });
+        let now = Instant::now();
+        let mut raft = Self {
+            peers: nodes.iter().filter(|n| **n != me).cloned().collect(),
+            me,
+            config,
+            sm,
+            term: 0,
+            voted_for: None,
+            role: Role::Follower,
+            leader: None,
+            votes: HashSet::new(),
+            snapshot_index: 0,
+            snapshot_term: 0,
+            snapshot: serde_json::Value::Null,
+            log: Vec::new(),
+            commit_index: 0,
+            last_applied: 0,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            sent_index: HashMap::new(),
+            election_deadline: now,
+            last_heartbeat: now,
+            waiters: HashMap::new(),
+            rng,
+        };
+        raft.reset_election_deadline(now);
+        raft
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn leader(&self) -> Option<&String> {
+        self.leader.as_ref()
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    pub fn state_machine(&self) -> &S {
+        &self.sm
+    }
+
+    /// Appends `command` if we are the leader. It is replicated on the next
+    /// tick, batched with whatever else was proposed in between. The proposal
+    /// resolves once the command is applied, or fails if another leader
+    /// overwrites it.
+    pub fn propose(&mut self, command: S::Command) -> Result<Proposal<S::Output>, RsmError> {
+        if self.role != Role::Leader {
+            return Err(RsmError::NotLeader(self.leader.clone()));
+        }
+        self.log.push(Entry {
+            term: self.term,
+            command: Some(command),
+        });
+        let (tx, rx) = oneshot::channel();
+        self.waiters.insert(self.last_index(), (self.term, tx));
+        self.advance_commit();
+        Ok(rx)
+    }
+
+    pub fn tick(&mut self, now: Instant) -> Outbox<S::Command> {
+        match self.role {
+            Role::Leader => {
+                let heartbeat =
+                    now.duration_since(self.last_heartbeat) >= self.config.heartbeat_interval;
+                if heartbeat {
+                    self.last_heartbeat = now;
+                }
+                let last = self.last_index();
+                let peers: Vec<_> = self
+                    .peers
+                    .iter()
+                    .filter(|p| heartbeat || self.sent_index.get(*p).is_none_or(|s| *s < last))
+                    .cloned()
+                    .collect();
+                peers.iter().map(|p| self.append_for(p)).collect()
+            }
+            Role::Follower | Role::Candidate => {
+                if now >= self.election_deadline {
+                    return self.start_election(now);
+                }
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        from: &str,
+        msg: RaftMsg<S::Command>,
+        now: Instant,
+    ) -> Outbox<S::Command> {
+        let term = match &msg {
+            RaftMsg::RequestVote { term, .. }
+            | RaftMsg::RequestVoteOk { term, .. }
+            | RaftMsg::AppendEntries { term, .. }
+            | RaftMsg::AppendEntriesOk { term, .. }
+            | RaftMsg::InstallSnapshot { term, .. }
+            | RaftMsg::InstallSnapshotOk { term, .. } => *term,
+        };
+        if term > self.term {
+            self.step_down(term, None, now);
+        }
+
+        match msg {
+            RaftMsg::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let granted = term == self.term
+                    && self.voted_for.as_deref().is_none_or(|v| v == from)
+                    && up_to_date;
+                if granted {
+                    self.voted_for = Some(from.to_string());
+                    self.reset_election_deadline(now);
+                }
+                vec![self.message(
+                    from,
+                    RaftMsg::RequestVoteOk {
+                        term: self.term,
+                        granted,
+                    },
+                )]
+            }
+            RaftMsg::RequestVoteOk { term, granted } => {
+                if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes.insert(from.to_string());
+                    if self.votes.len() >= self.quorum() {
+                        return self.become_leader(now);
+                    }
+                }
+                Vec::new()
+            }
+            RaftMsg::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                if term < self.term {
+                    return vec![self.message(
+                        from,
+                        RaftMsg::AppendEntriesOk {
+                            term: self.term,
+                            success: false,
+                            match_index: 0,
+                        },
+                    )];
+                }
+                self.step_down(term, Some(from.to_string()), now);
+                let reply =
+                    self.append_entries(prev_log_index, prev_log_term, entries, leader_commit);
+                vec![self.message(from, reply)]
+            }
+            RaftMsg::AppendEntriesOk {
+                term,
+                success,
+                match_index,
+            } => {
+                if self.role != Role::Leader || term != self.term {
+                    return Vec::new();
+                }
+                if success {
+                    self.record_match(from, match_index);
+                    Vec::new()
+                } else {
+                    self.next_index.insert(from.to_string(), match_index + 1);
+                    vec![self.append_for(from)]
+                }
+            }
+            RaftMsg::InstallSnapshot {
+                term,
+                last_included_index,
+                last_included_term,
+                data,
+            } => {
+                if term < self.term {
+                    return vec![self.message(
+                        from,
+                        RaftMsg::InstallSnapshotOk {
+                            term: self.term,
+                            match_index: 0,
+                        },
+                    )];
+                }
+                self.step_down(term, Some(from.to_string()), now);
+                self.install_snapshot(last_included_index, last_included_term, data);
+                vec![self.message(
+                    from,
+                    RaftMsg::InstallSnapshotOk {
+                        term: self.term,
+                        match_index: last_included_index,
+                    },
+                )]
+            }
+            RaftMsg::InstallSnapshotOk { term, match_index } => {
+                if self.role == Role::Leader && term == self.term {
+                    self.record_match(from, match_index);
+                }
+                Vec::new()
+            }
+        }
+    }
+
+    fn start_election(&mut self, now: Instant) -> Outbox<S::Command> {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.me.clone());
+        self.votes = HashSet::from([self.me.clone()]);
+        self.reset_election_deadline(now);
+        if self.votes.len() >= self.quorum() {
+            return self.become_leader(now);
+        }
+        let msg = RaftMsg::RequestVote {
+            term: self.term,
+            last_log_index: self.last_index(),
+            last_log_term: self.last_term(),
+        };
+        self.peers
+            .iter()
+            .map(|p| self.message(p, msg.clone()))
+            .collect()
+    }
+
+    fn become_leader(&mut self, now: Instant) -> Outbox<S::Command> {
+        self.role = Role::Leader;
+        self.leader = Some(self.me.clone());
+        self.last_heartbeat = now;
+        self.sent_index.clear();
+        for p in &self.peers {
+            self.next_index.insert(p.clone(), self.last_index() + 1);
+            self.match_index.insert(p.clone(), 0);
+        }
+        self.log.push(Entry {
+            term: self.term,
+            command: None,
+        });
+        self.advance_commit();
+        self.broadcast_append()
+    }
+
+    fn step_down(&mut self, term: u64, leader: Option<String>, now: Instant) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        if self.role == Role::Leader {
+            // we will not hear whether our pending entries commit
+            self.drop_waiters(u64::MAX);
+        }
+        self.role = Role::Follower;
+        if leader.is_some() {
+            self.leader = leader;
+            self.reset_election_deadline(now);
+        }
+    }
+
+    fn append_entries(
+        &mut self,
+        mut prev_log_index: u64,
+        mut prev_log_term: u64,
+        mut entries: Vec<Entry<S::Command>>,
+        leader_commit: u64,
+    ) -> RaftMsg<S::Command> {
+        // entries covered by our snapshot are committed, so they must match
+        if prev_log_index < self.snapshot_index {
+            let skip = (self.snapshot_index - prev_log_index) as usize;
+            if skip > entries.len() {
+                return RaftMsg::AppendEntriesOk {
+                    term: self.term,
+                    success: true,
+                    match_index: self.snapshot_index,
+                };
+            }
+            entries.drain(..skip);
+            prev_log_index = self.snapshot_index;
+            prev_log_term = self.snapshot_term;
+        }
+
+        if self.term_at(prev_log_index) != Some(prev_log_term) {
+            let hint = if prev_log_index > self.last_index() {
+                self.last_index()
+            } else {
+                // skip back over the whole conflicting term at once
+                let conflict = self.term_at(prev_log_index);
+                let mut i = prev_log_index;
+                while i > self.snapshot_index + 1 && self.term_at(i - 1) == conflict {
+                    i -= 1;
+                }
+                i - 1
+            };
+            return RaftMsg::AppendEntriesOk {
+                term: self.term,
+                success: false,
+                match_index: hint,
+            };
+        }
+
+        let mut index = prev_log_index;
+        for entry in entries {
+            index += 1;
+            match self.term_at(index) {
+                Some(t) if t == entry.term => continue,
+                Some(_) => self
+                    .log
+                    .truncate((index - self.snapshot_index - 1) as usize),
+                None => {}
+            }
+            self.log.push(entry);
+        }
+
+        if leader_commit > self.commit_index {
+            self.commit_index = leader_commit.min(index);
+            self.apply_committed();
+        }
+        RaftMsg::AppendEntriesOk {
+            term: self.term,
+            success: true,
+            match_index: index,
+        }
+    }
+
+    fn install_snapshot(&mut self, index: u64, term: u64, data: serde_json::Value) {
+        if index <= self.snapshot_index {
+            return;
+        }
+        if self.term_at(index) == Some(term) {
+            self.log.drain(..(index - self.snapshot_index) as usize);
+        } else {
+            self.log.clear();
+        }
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.commit_index = self.commit_index.max(index);
+        if index > self.last_applied {
+            self.sm.restore(data.clone());
+            self.last_applied = index;
+            // the snapshot applied these without giving us their outputs
+            self.drop_waiters(index);
+        }
+        self.snapshot = data;
+    }
+
+    /// Fails the proposals waiting on entries up to `index`.
+    fn drop_waiters(&mut self, index: u64) {
+        let dropped: Vec<_> = self
+            .waiters
+            .keys()
+            .filter(|i| **i <= index)
+            .copied()
+            .collect();
+        for i in dropped {
+            let (_, tx) = self.waiters.remove(&i).unwrap();
+            let _ = tx.send(Err(RsmError::Dropped));
+        }
+    }
+
+    fn record_match(&mut self, peer: &str, index: u64) {
+        let m = self.match_index.entry(peer.to_string()).or_insert(0);
+        *m = (*m).max(index);
+        self.next_index.insert(peer.to_string(), *m + 1);
+        self.advance_commit();
+    }
+
+    fn advance_commit(&mut self) {
+        if self.role != Role::Leader {
+            return;
+        }
+        for n in (self.commit_index + 1..=self.last_index()).rev() {
+            // only entries from our own term are committed by counting replicas
+            if self.term_at(n) != Some(self.term) {
+                break;
+            }
+            let replicas = 1 + self.match_index.values().filter(|m| **m >= n).count();
+            if replicas >= self.quorum() {
+                self.commit_index = n;
+                break;
+            }
+        }
+        self.apply_committed();
+    }
+
+    fn apply_committed(&mut self) {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = &self.log[(self.last_applied - self.snapshot_index - 1) as usize];
+            let output = entry.command.as_ref().map(|c| self.sm.apply(c));
+            if let Some((term, tx)) = self.waiters.remove(&self.last_applied) {
+                let res = match output {
+                    Some(output) if term == entry.term => Ok(output),
+                    _ => Err(RsmError::Dropped),
+                };
+                let _ = tx.send(res);
+            }
+        }
+        if self.last_applied - self.snapshot_index >= self.config.snapshot_threshold {
+            self.compact();
+        }
+    }
+
+    fn compact(&mut self) {
+        let term = self.term_at(self.last_applied).unwrap();
+        self.log
+            .drain(..(self.last_applied - self.snapshot_index) as usize);
+        self.snapshot_index = self.last_applied;
+        self.snapshot_term = term;
+        self.snapshot = self.sm.snapshot();
+    }
+
+    fn broadcast_append(&mut self) -> Outbox<S::Command> {
+        let peers = self.peers.clone();
+        peers.iter().map(|p| self.append_for(p)).collect()
+    }
+
+    fn append_for(&mut self, peer: &str) -> Message<RaftMsg<S::Command>> {
+        let next = self
+            .next_index
+            .get(peer)
+            .copied()
+            .unwrap_or(self.last_index() + 1);
+        if next <= self.snapshot_index {
+            self.sent_index
+                .insert(peer.to_string(), self.snapshot_index);
+            return self.message(
+                peer,
+                RaftMsg::InstallSnapshot {
+                    term: self.term,
+                    last_included_index: self.snapshot_index,
+                    last_included_term: self.snapshot_term,
+                    data: self.snapshot.clone(),
+                },
+            );
+        }
+        let prev_log_index = next - 1;
+        let start = (next - self.snapshot_index - 1) as usize;
+        let end = self.log.len().min(start + MAX_ENTRIES_PER_APPEND);
+        self.sent_index
+            .insert(peer.to_string(), self.snapshot_index + end as u64);
+        self.message(
+            peer,
+            RaftMsg::AppendEntries {
+                term: self.term,
+                prev_log_index,
+                prev_log_term: self.term_at(prev_log_index).unwrap_or(0),
+                entries: self.log[start..end].to_vec(),
+                leader_commit: self.commit_index,
+            },
+        )
+    }
+
+    fn message(&self, to: &str, msg: RaftMsg<S::Command>) -> Message<RaftMsg<S::Command>> {
+        Message::new(
+            self.me.clone(),
+            to.to_string(),
+            Body {
+                id: None,
+                in_reply_to: None,
+                payload: msg,
+            },
+        )
+    }
+
+    fn quorum(&self) -> usize {
+        let n = self.peers.len() + 1;
+        n / 2 + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(self.snapshot_term, |e| e.term)
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        if index < self.snapshot_index || index > self.last_index() {
+            return None;
+        }
+        Some(self.log[(index - self.snapshot_index - 1) as usize].term)
+    }
+
+    fn reset_election_deadline(&mut self, now: Instant) {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let spread = self.config.election_timeout_max - self.config.election_timeout_min;
+        let jitter = spread.mul_f64((self.rng % 1000) as f64 / 1000.0);
+        self.election_deadline = now + self.config.election_timeout_min + jitter;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[derive(Default)]
+    struct Sum(u64);
+
+    impl StateMachine for Sum {
+        type Command = u64;
+        type Output = u64;
+
+        fn apply(&mut self, command: &u64) -> u64 {
+            self.0 += command;
+            self.0
+        }
+
+        fn snapshot(&self) -> serde_json::Value {
+            serde_json::json!(self.0)
+        }
+
+        fn restore(&mut self, snapshot: serde_json::Value) {
+            self.0 = snapshot.as_u64().unwrap();
+        }
+    }
+
+    struct Cluster {
+        nodes: BTreeMap<String, Raft<Sum>>,
+        down: Vec<String>,
+        now: Instant,
+    }
+
+    impl Cluster {
+        fn new(n: usize, snapshot_threshold: u64) -> Self {
+            let names: Vec<_> = (0..n).map(|i| format!("n{i}")).collect();
+            let config = RaftConfig {
+                snapshot_threshold,
+                ..RaftConfig::default()
+            };
+            let nodes = names
+                .iter()
+                .map(|n| {
+                    (
+                        n.clone(),
+                        Raft::new(n.clone(), &names, Sum::default(), config.clone()),
+                    )
+                })
+                .collect();
+            Self {
+                nodes,
+                down: Vec::new(),
+                now: Instant::now(),
+            }
+        }
+
+        fn deliver(&mut self, mut queue: Vec<Message<RaftMsg<u64>>>) {
+            while let Some(m) = queue.pop() {
+                if self.down.contains(&m.src) || self.down.contains(&m.dst) {
+                    continue;
+                }
+                let node = self.nodes.get_mut(&m.dst).unwrap();
+                queue.extend(node.handle(&m.src, m.body.payload, self.now));
+            }
+        }
+
+        fn run(&mut self, duration: Duration) {
+            let step = Duration::from_millis(50);
+            let mut elapsed = Duration::ZERO;
+            while elapsed < duration {
+                self.now += step;
+                elapsed += step;
+                let names: Vec<_> = self.nodes.keys().cloned().collect();
+                for name in names {
+                    if self.down.contains(&name) {
+                        continue;
+                    }
+                    let out = self.nodes.get_mut(&name).unwrap().tick(self.now);
+                    self.deliver(out);
+                }
+            }
+        }
+
+        fn leader(&self) -> String {
+            let leaders: Vec<_> = self
+                .nodes
+                .iter()
+                .filter(|(n, r)| r.role() == Role::Leader && !self.down.contains(n))
+                .map(|(n, _)| n.clone())
+                .collect();
+            assert_eq!(leaders.len(), 1, "leaders: {leaders:?}");
+            leaders[0].clone()
+        }
+
+        fn propose(&mut self, command: u64) -> Proposal<u64> {
+            let leader = self.leader();
+            let rx = self
+                .nodes
+                .get_mut(&leader)
+                .unwrap()
+                .propose(command)
+                .unwrap();
+            self.run(Duration::from_millis(50));
+            rx
+        }
+    }
+
+    #[test]
+    fn elects_a_single_leader() {
+        let mut c = Cluster::new(3, 1000);
+        c.run(Duration::from_secs(5));
+        let leader = c.leader();
+        let term = c.nodes[&leader].term();
+        for r in c.nodes.values() {
+            assert_eq!(r.term(), term);
+            assert_eq!(r.leader(), Some(&leader));
+        }
+    }
+
+    #[test]
+    fn replicates_and_applies_commands() {
+        let mut c = Cluster::new(3, 1000);
+        c.run(Duration::from_secs(5));
+        for i in 1..=10 {
+            let rx = c.propose(i);
+            assert_eq!(rx.try_recv().unwrap(), Ok((1..=i).sum()));
+        }
+        c.run(Duration::from_secs(1));
+        for r in c.nodes.values() {
+            assert_eq!(r.state_machine().0, 55);
+        }
+    }
+
+    #[test]
+    fn followers_reject_proposals() {
+        let mut c = Cluster::new(3, 1000);
+        c.run(Duration::from_secs(5));
+        let leader = c.leader();
+        let follower = c.nodes.keys().find(|n| **n != leader).unwrap().clone();
+        let err = c.nodes.get_mut(&follower).unwrap().propose(1).err();
+        assert_eq!(err, Some(RsmError::NotLeader(Some(leader))));
+    }
+
+    #[test]
+    fn fails_over_when_leader_crashes() {
+        let mut c = Cluster::new(5, 1000);
+        c.run(Duration::from_secs(5));
+        c.propose(1);
+        let old = c.leader();
+        c.down.push(old.clone());
+        c.run(Duration::from_secs(5));
+        assert_ne!(c.leader(), old);
+        let rx = c.propose(2);
+        assert_eq!(rx.try_recv().unwrap(), Ok(3));
+    }
+
+    #[test]
+    fn deposed_leader_fails_pending_proposals() {
+        let mut c = Cluster::new(3, 1000);
+        c.run(Duration::from_secs(5));
+        let leader = c.leader();
+        let peer = c.nodes.keys().find(|n| **n != leader).unwrap().clone();
+        let node = c.nodes.get_mut(&leader).unwrap();
+        let rx = node.propose(1).unwrap();
+        let term = node.term();
+        let (last_log_index, last_log_term) = (node.last_index() + 1, term + 1);
+        node.handle(
+            &peer,
+            RaftMsg::RequestVote {
+                term: term + 1,
+                last_log_index,
+                last_log_term,
+            },
+            c.now,
+        );
+        assert_eq!(rx.try_recv().unwrap(), Err(RsmError::Dropped));
+    }
+
+    #[test]
+    fn snapshot_over_pending_proposals_fails_them() {
+        let mut c = Cluster::new(3, 1000);
+        c.run(Duration::from_secs(5));
+        let leader = c.leader();
+        let node = c.nodes.get_mut(&leader).unwrap();
+        let rx = node.propose(1).unwrap();
+        let index = node.last_index();
+        node.install_snapshot(index + 5, node.term(), serde_json::json!(42));
+        assert_eq!(rx.try_recv().unwrap(), Err(RsmError::Dropped));
+        assert_eq!(node.state_machine().0, 42);
+    }
+
+    #[test]
+    fn lagging_follower_catches_up_from_snapshot() {
+        let mut c = Cluster::new(3, 5);
+        c.run(Duration::from_secs(5));
+        let leader = c.leader();
+        let lagging = c.nodes.keys().find(|n| **n != leader).unwrap().clone();
+        c.down.push(lagging.clone());
+        for i in 0..20 {
+            c.propose(i);
+        }
+        assert!(c.nodes[&leader].snapshot_index > 0);
+        c.down.clear();
+        c.run(Duration::from_secs(2));
+        assert_eq!(c.nodes[&lagging].state_machine().0, (0..20).sum::<u64>());
+    }
+}
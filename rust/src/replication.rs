@@ -0,0 +1,425 @@
+//! Leader/follower replication of a partitioned log with an in-sync replica
+//! set, in the style of Kafka.
+//!
+//! Every key has a fixed list of replicas. One of them leads an epoch and
+//! appends; the others copy its log. The in-sync replica set (ISR) holds the
+//! replicas that have kept up with the leader within the lag timeout, and the
+//! high watermark is the highest offset every ISR member has. Only offsets up
+//! to the high watermark are acknowledged or visible to readers.
+//!
+//! When the leader is suspected dead, the first live ISR member in replica
+//! order starts the next epoch. Epochs are ordered by number, then by leader,
+//! so two replicas that take over at once still agree on one of them. A
+//! replica that learns of a newer epoch drops whatever it has above its high
+//! watermark, since only the new leader's log counts from then on.
+//!
+//! Writes need at least `min_insync` ISR members, the leader included: below
+//! that the leader refuses new entries and holds the high watermark, and no
+//! replica takes over with fewer live ISR members. A write is acknowledged
+//! only once that many replicas have it, so it survives a failover as long as
+//! one of them does.
+//!
+//! ISR changes reach followers along with the log. A follower cut off before
+//! it learned it was dropped from the ISR can still take over, losing the
+//! writes acknowledged without it; with `min_insync` above 1 those writes
+//! also reached another replica still in its ISR.
+//!
+//! This module only tracks positions; the node keeps the log itself and moves
+//! entries between replicas.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_LAG_TIMEOUT: Duration = Duration::from_millis(1000);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Epoch {
+    pub number: u64,
+    pub leader: String,
+}
+
+/// What a follower should do with entries from a leader.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Follow {
+    /// The sender leads an older epoch; the reply tells it about ours.
+    Stale,
+    /// The entries start after our end; the reply asks for the missing ones.
+    /// A newer epoch may still cut the log after the given offset first.
+    Behind { truncate: Option<usize> },
+    /// Truncate the log after the given offset if set, then append the
+    /// entries past our end.
+    Append { truncate: Option<usize> },
+}
+
+#[derive(Debug)]
+struct Follower {
+    /// Unknown until it answers in the current epoch.
+    end: Option<usize>,
+    /// When it last had everything the leader had.
+    caught_up: Instant,
+}
+
+/// One replica's view of a key's partition.
+#[derive(Debug)]
+pub struct Partition {
+    me: String,
+    replicas: Vec<String>,
+    epoch: Epoch,
+    isr: BTreeSet<String>,
+    end: usize,
+    hw: usize,
+    /// Leader only.
+    followers: HashMap<String, Follower>,
+    lag_timeout: Duration,
+    min_insync: usize,
+}
+
+impl Partition {
+    /// A partition led by `leader` in epoch 0, with every replica in sync.
+    /// `end` is the last offset in the local log. `min_insync` is capped at
+    /// the number of replicas.
+    pub fn new(
+        me: impl Into<String>,
+        replicas: Vec<String>,
+        leader: impl Into<String>,
+        end: usize,
+        lag_timeout: Duration,
+        min_insync: usize,
+        now: Instant,
+    ) -> Self {
+        let mut p = Self {
+            min_insync: min_insync.clamp(1, replicas.len().max(1)),
+            me: me.into(),
+            isr: replicas.iter().cloned().collect(),
+            replicas,
+            epoch: Epoch {
+                number: 0,
+                leader: leader.into(),
+            },
+            end,
+            hw: 0,
+            followers: HashMap::new(),
+            lag_timeout,
+        };
+        p.lead(now);
+        p
+    }
+
+    pub fn epoch(&self) -> &Epoch {
+        &self.epoch
+    }
+
+    pub fn leader(&self) -> &str {
+        &self.epoch.leader
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.epoch.leader == self.me
+    }
+
+    pub fn replicas(&self) -> &[String] {
+        &self.replicas
+    }
+
+    pub fn isr(&self) -> &BTreeSet<String> {
+        &self.isr
+    }
+
+    /// Whether this replica leads and has enough of the ISR left to take
+    /// writes.
+    pub fn writable(&self) -> bool {
+        self.is_leader() && self.isr.len() >= self.min_insync
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn hw(&self) -> usize {
+        self.hw
+    }
+
+    /// Followers and the offset after which each should receive entries.
+    pub fn followers(&self) -> Vec<(String, usize)> {
+        self.followers
+            .iter()
+            .map(|(f, state)| (f.clone(), state.end.unwrap_or(self.hw)))
+            .collect()
+    }
+
+    /// The leader appended up to `end`.
+    pub fn appended(&mut self, end: usize) {
+        self.end = end;
+        self.advance();
+    }
+
+    /// A follower reported its end in the current epoch.
+    pub fn acked(&mut self, follower: &str, end: usize, now: Instant) {
+        let (hw, leader_end) = (self.hw, self.end);
+        let Some(state) = self.followers.get_mut(follower) else {
+            return;
+        };
+        state.end = Some(end);
+        if end >= leader_end {
+            state.caught_up = now;
+        }
+        if end >= hw {
+            self.isr.insert(follower.to_string());
+        } else {
+            // it lost entries it had, e.g. by restarting without its log
+            self.isr.remove(follower);
+        }
+        self.advance();
+    }
+
+    /// Drops followers that have not caught up within the lag timeout from
+    /// the ISR.
+    pub fn tick(&mut self, now: Instant) {
+        if !self.is_leader() {
+            return;
+        }
+        for (follower, state) in &self.followers {
+            if now.duration_since(state.caught_up) > self.lag_timeout {
+                self.isr.remove(follower);
+            }
+        }
+        self.advance();
+    }
+
+    /// Moves to `epoch` if it is newer than ours. Returns the offset to
+    /// truncate the log after when entries above the high watermark must go.
+    pub fn observe(&mut self, epoch: &Epoch, now: Instant) -> Option<usize> {
+        if *epoch <= self.epoch {
+            return None;
+        }
+        self.epoch = epoch.clone();
+        self.lead(now);
+        if self.is_leader() || self.end <= self.hw {
+            return None;
+        }
+        self.end = self.hw;
+        Some(self.hw)
+    }
+
+    /// Entries following `prev` arrived from `epoch`'s leader, together with
+    /// its ISR and high watermark.
+    pub fn follow(
+        &mut self,
+        epoch: &Epoch,
+        isr: BTreeSet<String>,
+        hw: usize,
+        prev: usize,
+        now: Instant,
+    ) -> Follow {
+        if *epoch < self.epoch {
+            return Follow::Stale;
+        }
+        let truncate = self.observe(epoch, now);
+        self.isr = isr;
+        if prev > self.end {
+            return Follow::Behind { truncate };
+        }
+        self.hw = self.hw.max(hw.min(self.end));
+        Follow::Append { truncate }
+    }
+
+    /// A follower appended entries from the leader up to `end`.
+    pub fn replicated(&mut self, end: usize, leader_hw: usize) {
+        self.end = end;
+        self.hw = self.hw.max(leader_hw.min(end));
+    }
+
+    /// Starts the next epoch if the leader is not `alive` and this replica is
+    /// the first live member of the ISR. Returns whether it took over.
+    pub fn take_over(&mut self, alive: impl Fn(&str) -> bool, now: Instant) -> bool {
+        if self.is_leader() || alive(&self.epoch.leader) {
+            return false;
+        }
+        let successor = self
+            .replicas
+            .iter()
+            .find(|r| self.isr.contains(*r) && (**r == self.me || alive(r)));
+        if successor != Some(&self.me) {
+            return false;
+        }
+        let live = self.isr.iter().filter(|r| **r == self.me || alive(r));
+        if live.count() < self.min_insync {
+            return false;
+        }
+        self.epoch = Epoch {
+            number: self.epoch.number + 1,
+            leader: self.me.clone(),
+        };
+        self.isr.retain(|r| *r == self.me || alive(r));
+        self.lead(now);
+        true
+    }
+
+    /// Resets the follower state for the current epoch; every follower gets
+    /// one lag timeout to answer before leaving the ISR.
+    fn lead(&mut self, now: Instant) {
+        self.followers.clear();
+        if !self.is_leader() {
+            return;
+        }
+        for r in &self.replicas {
+            if *r != self.me {
+                let state = Follower {
+                    end: None,
+                    caught_up: now,
+                };
+                self.followers.insert(r.clone(), state);
+            }
+        }
+        self.advance();
+    }
+
+    fn advance(&mut self) {
+        if !self.writable() {
+            return;
+        }
+        let in_sync = self.isr.iter().map(|r| {
+            if *r == self.me {
+                self.end
+            } else {
+                self.followers.get(r).and_then(|f| f.end).unwrap_or(self.hw)
+            }
+        });
+        let hw = in_sync.min().unwrap_or(self.end).min(self.end);
+        self.hw = self.hw.max(hw);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replicas() -> Vec<String> {
+        ["n1", "n2", "n3"].iter().map(|n| n.to_string()).collect()
+    }
+
+    fn partition(me: &str, now: Instant) -> Partition {
+        Partition::new(me, replicas(), "n1", 0, DEFAULT_LAG_TIMEOUT, 1, now)
+    }
+
+    #[test]
+    fn high_watermark_waits_for_the_isr() {
+        let now = Instant::now();
+        let mut leader = partition("n1", now);
+        leader.appended(3);
+        assert_eq!(leader.hw(), 0);
+        leader.acked("n2", 3, now);
+        assert_eq!(leader.hw(), 0);
+        leader.acked("n3", 2, now);
+        assert_eq!(leader.hw(), 2);
+
+        // n3 stops answering and leaves the ISR
+        leader.acked("n2", 3, now + DEFAULT_LAG_TIMEOUT);
+        leader.tick(now + DEFAULT_LAG_TIMEOUT * 2);
+        assert_eq!(leader.isr().len(), 2);
+        assert_eq!(leader.hw(), 3);
+
+        // and rejoins once it has everything up to the high watermark
+        leader.acked("n3", 3, now + DEFAULT_LAG_TIMEOUT * 2);
+        assert!(leader.isr().contains("n3"));
+    }
+
+    #[test]
+    fn writes_wait_for_the_minimum_isr() {
+        let now = Instant::now();
+        let mut leader = Partition::new("n1", replicas(), "n1", 0, DEFAULT_LAG_TIMEOUT, 2, now);
+        leader.appended(1);
+        leader.acked("n2", 1, now);
+        leader.acked("n3", 1, now);
+        assert_eq!(leader.hw(), 1);
+
+        // both followers fall behind and leave the ISR
+        leader.appended(2);
+        leader.tick(now + DEFAULT_LAG_TIMEOUT * 2);
+        assert_eq!(leader.isr().len(), 1);
+        assert!(!leader.writable());
+        assert_eq!(leader.hw(), 1);
+
+        leader.acked("n2", 2, now + DEFAULT_LAG_TIMEOUT * 2);
+        assert!(leader.writable());
+        assert_eq!(leader.hw(), 2);
+    }
+
+    #[test]
+    fn takes_over_only_with_the_minimum_isr() {
+        let now = Instant::now();
+        let mut n2 = Partition::new("n2", replicas(), "n1", 0, DEFAULT_LAG_TIMEOUT, 2, now);
+        let epoch = n2.epoch().clone();
+        let isr: BTreeSet<String> = ["n1", "n2"].iter().map(|n| n.to_string()).collect();
+        n2.follow(&epoch, isr, 0, 0, now);
+
+        assert!(!n2.take_over(|n| n != "n1", now));
+        assert!(!n2.is_leader());
+
+        let isr: BTreeSet<String> = replicas().into_iter().collect();
+        n2.follow(&epoch, isr, 0, 0, now);
+        assert!(n2.take_over(|n| n != "n1", now));
+        assert_eq!(n2.isr().len(), 2);
+    }
+
+    #[test]
+    fn first_live_isr_member_takes_over() {
+        let now = Instant::now();
+        let mut n2 = partition("n2", now);
+        let mut n3 = partition("n3", now);
+        let epoch = n2.epoch().clone();
+        let isr: BTreeSet<String> = replicas().into_iter().collect();
+        assert_eq!(
+            n2.follow(&epoch, isr.clone(), 1, 0, now),
+            Follow::Append { truncate: None }
+        );
+        n2.replicated(2, 1);
+        assert_eq!(n2.hw(), 1);
+
+        let alive = |n: &str| n != "n1";
+        assert!(!n3.take_over(alive, now));
+        assert!(n2.take_over(alive, now));
+        assert_eq!(n2.epoch().number, 1);
+        assert!(n2.is_leader());
+
+        // n3 hears from the new leader and follows it
+        n3.replicated(1, 1);
+        let next = n2.epoch().clone();
+        assert_eq!(
+            n3.follow(&next, n2.isr().clone(), 1, 1, now),
+            Follow::Append { truncate: None }
+        );
+        assert_eq!(n3.leader(), "n2");
+    }
+
+    #[test]
+    fn newer_epochs_win_and_cut_unreplicated_entries() {
+        let now = Instant::now();
+        let mut old = partition("n1", now);
+        old.appended(5);
+        old.acked("n2", 2, now);
+        old.acked("n3", 2, now);
+        assert_eq!(old.hw(), 2);
+
+        let newer = Epoch {
+            number: 1,
+            leader: "n2".to_string(),
+        };
+        assert_eq!(old.observe(&newer, now), Some(2));
+        assert!(!old.is_leader());
+        assert_eq!(old.end(), 2);
+
+        let stale = Epoch::default();
+        assert_eq!(
+            old.follow(&stale, BTreeSet::new(), 0, 0, now),
+            Follow::Stale
+        );
+        assert_eq!(
+            old.follow(&newer, BTreeSet::new(), 4, 3, now),
+            Follow::Behind { truncate: None }
+        );
+    }
+}
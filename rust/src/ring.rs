@@ -0,0 +1,131 @@
+//! Consistent hashing.
+//!
+//! Each node is placed on a 64-bit ring at several virtual points; a key is
+//! owned by the first node clockwise from the key's hash. Adding or removing
+//! a node only moves the keys adjacent to its points.
+
+use std::collections::BTreeMap;
+
+const VIRTUAL_NODES: usize = 64;
+
+#[derive(Debug, Clone, Default)]
+pub struct HashRing {
+    ring: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    pub fn new<'a>(nodes: impl IntoIterator<Item = &'a String>) -> Self {
+        let mut ring = Self::default();
+        for node in nodes {
+            ring.add(node);
+        }
+        ring
+    }
+
+    pub fn add(&mut self, node: &str) {
+        for i in 0..VIRTUAL_NODES {
+            self.ring
+                .insert(hash(&format!("{node}#{i}")), node.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, node: &str) {
+        self.ring.retain(|_, n| n != node);
+    }
+
+    /// The node owning `key`, or `None` if the ring is empty.
+    pub fn owner(&self, key: &str) -> Option<&String> {
+        let h = hash(key);
+        self.ring
+            .range(h..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node)
+    }
+
+    /// Up to `n` distinct nodes for `key`, starting with its owner and going
+    /// clockwise.
+    pub fn replicas(&self, key: &str, n: usize) -> Vec<&String> {
+        let h = hash(key);
+        let mut replicas: Vec<&String> = Vec::new();
+        for node in self
+            .ring
+            .range(h..)
+            .chain(self.ring.range(..h))
+            .map(|(_, n)| n)
+        {
+            if replicas.len() == n {
+                break;
+            }
+            if !replicas.contains(&node) {
+                replicas.push(node);
+            }
+        }
+        replicas
+    }
+}
+
+/// FNV-1a, so every node computes the same ring regardless of std's hasher.
+fn hash(s: &str) -> u64 {
+    let h = s.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100_0000_01b3)
+    });
+    // FNV mixes the last bytes poorly; finish with a 64-bit avalanche
+    let h = (h ^ (h >> 33)).wrapping_mul(0xff51afd7ed558ccd);
+    let h = (h ^ (h >> 33)).wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn nodes(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("n{i}")).collect()
+    }
+
+    #[test]
+    fn spreads_keys_over_all_nodes() {
+        let ring = HashRing::new(&nodes(5));
+        let mut counts: HashMap<&String, usize> = HashMap::new();
+        for k in 0..5000 {
+            *counts
+                .entry(ring.owner(&k.to_string()).unwrap())
+                .or_default() += 1;
+        }
+        assert_eq!(counts.len(), 5);
+        assert!(counts.values().all(|c| *c > 500), "{counts:?}");
+    }
+
+    #[test]
+    fn removing_a_node_only_moves_its_keys() {
+        let all = nodes(5);
+        let before = HashRing::new(&all);
+        let mut after = before.clone();
+        after.remove("n2");
+        for k in 0..1000 {
+            let k = k.to_string();
+            let (b, a) = (before.owner(&k).unwrap(), after.owner(&k).unwrap());
+            if b != "n2" {
+                assert_eq!(a, b);
+            }
+            assert_ne!(a, "n2");
+        }
+        assert_eq!(HashRing::default().owner("k"), None);
+    }
+
+    #[test]
+    fn replicas_start_at_the_owner_and_are_distinct() {
+        let ring = HashRing::new(&nodes(5));
+        for k in 0..100 {
+            let k = k.to_string();
+            let replicas = ring.replicas(&k, 3);
+            assert_eq!(replicas.len(), 3);
+            assert_eq!(replicas[0], ring.owner(&k).unwrap());
+            assert!(replicas[1..].iter().all(|r| *r != replicas[0]));
+            assert_ne!(replicas[1], replicas[2]);
+        }
+        assert_eq!(ring.replicas("k", 10).len(), 5);
+    }
+}
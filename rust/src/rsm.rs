@@ -0,0 +1,233 @@
+//! Replicated state machines: what a consensus module drives.
+//!
+//! A node plugs its own `StateMachine` into a consensus module, proposes
+//! commands through it, and waits on the returned [`Proposal`] for the output
+//! of applying the command once it is committed.
+//!
+//! Raft and Multi-Paxos both implement [`Consensus`]; [`Replica`] picks one
+//! of them from a [`Protocol`] so nodes can switch backends by configuration.
+
+use crate::{
+    paxos::{MultiPaxos, PaxosConfig, PaxosMsg},
+    raft::{Raft, RaftConfig, RaftMsg},
+    Message,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fmt::{Debug, Display},
+    str::FromStr,
+    time::Instant,
+};
+
+pub trait StateMachine: Send + 'static {
+    type Command: Clone + Debug + Serialize + DeserializeOwned + Send;
+    type Output: Send;
+
+    fn apply(&mut self, command: &Self::Command) -> Self::Output;
+
+    /// Serializes the whole state, used to compact the log.
+    fn snapshot(&self) -> serde_json::Value;
+
+    fn restore(&mut self, snapshot: serde_json::Value);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RsmError {
+    /// This node cannot accept proposals. Carries the leader if it is known.
+    NotLeader(Option<String>),
+    /// The proposal's leader lost track of it before it applied: another
+    /// leader overwrote it, or took over before it committed. In the latter
+    /// case it may still apply.
+    Dropped,
+}
+
+impl Display for RsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RsmError::NotLeader(Some(leader)) => write!(f, "not the leader, try {}", leader),
+            RsmError::NotLeader(None) => write!(f, "not the leader, no leader known"),
+            RsmError::Dropped => write!(f, "proposal was dropped by a new leader"),
+        }
+    }
+}
+
+impl std::error::Error for RsmError {}
+
+pub type Proposal<O> = oneshot::Receiver<Result<O, RsmError>>;
+
+/// A consensus module driving a replicated log of `S::Command`s. Calls return
+/// the messages the node should send; the node wraps them into its own payload.
+pub trait Consensus<S: StateMachine>: Send {
+    type Msg: Clone + Debug + Serialize + DeserializeOwned + Send;
+
+    fn propose(&mut self, command: S::Command) -> Result<Proposal<S::Output>, RsmError>;
+
+    fn tick(&mut self, now: Instant) -> Vec<Message<Self::Msg>>;
+
+    fn handle(&mut self, from: &str, msg: Self::Msg, now: Instant) -> Vec<Message<Self::Msg>>;
+
+    fn leader(&self) -> Option<&String>;
+
+    fn state_machine(&self) -> &S;
+}
+
+impl<S: StateMachine> Consensus<S> for Raft<S> {
+    type Msg = RaftMsg<S::Command>;
+
+    fn propose(&mut self, command: S::Command) -> Result<Proposal<S::Output>, RsmError> {
+        Raft::propose(self, command)
+    }
+
+    fn tick(&mut self, now: Instant) -> Vec<Message<Self::Msg>> {
+        Raft::tick(self, now)
+    }
+
+    fn handle(&mut self, from: &str, msg: Self::Msg, now: Instant) -> Vec<Message<Self::Msg>> {
+        Raft::handle(self, from, msg, now)
+    }
+
+    fn leader(&self) -> Option<&String> {
+        Raft::leader(self)
+    }
+
+    fn state_machine(&self) -> &S {
+        Raft::state_machine(self)
+    }
+}
+
+impl<S: StateMachine> Consensus<S> for MultiPaxos<S> {
+    type Msg = PaxosMsg<S::Command>;
+
+    fn propose(&mut self, command: S::Command) -> Result<Proposal<S::Output>, RsmError> {
+        MultiPaxos::propose(self, command)
+    }
+
+    fn tick(&mut self, now: Instant) -> Vec<Message<Self::Msg>> {
+        MultiPaxos::tick(self, now)
+    }
+
+    fn handle(&mut self, from: &str, msg: Self::Msg, now: Instant) -> Vec<Message<Self::Msg>> {
+        MultiPaxos::handle(self, from, msg, now)
+    }
+
+    fn leader(&self) -> Option<&String> {
+        MultiPaxos::leader(self)
+    }
+
+    fn state_machine(&self) -> &S {
+        MultiPaxos::state_machine(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Raft,
+    Paxos,
+}
+
+impl Protocol {
+    /// Reads the backend from `NAZGUL_CONSENSUS`, defaulting to Raft.
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var("NAZGUL_CONSENSUS") {
+            Ok(s) => s.parse(),
+            Err(_) => Ok(Protocol::default()),
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raft" => Ok(Protocol::Raft),
+            "paxos" | "multi-paxos" => Ok(Protocol::Paxos),
+            _ => anyhow::bail!("unknown consensus protocol: {}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsensusMsg<C> {
+    Raft(RaftMsg<C>),
+    Paxos(PaxosMsg<C>),
+}
+
+/// Whichever consensus backend was configured.
+pub enum Replica<S: StateMachine> {
+    Raft(Raft<S>),
+    Paxos(MultiPaxos<S>),
+}
+
+impl<S: StateMachine> Replica<S> {
+    /// `nodes` is the whole cluster, this node included.
+    pub fn new(protocol: Protocol, me: impl Into<String>, nodes: &[String], sm: S) -> Self {
+        match protocol {
+            Protocol::Raft => Replica::Raft(Raft::new(me, nodes, sm, RaftConfig::default())),
+            Protocol::Paxos => {
+                Replica::Paxos(MultiPaxos::new(me, nodes, sm, PaxosConfig::default()))
+            }
+        }
+    }
+}
+
+fn wrap<M, N>(msgs: Vec<Message<M>>, f: impl Fn(M) -> N) -> Vec<Message<N>> {
+    msgs.into_iter()
+        .map(|m| Message {
+            src: m.src,
+            dst: m.dst,
+            body: crate::Body {
+                id: m.body.id,
+                in_reply_to: m.body.in_reply_to,
+                payload: f(m.body.payload),
+            },
+        })
+        .collect()
+}
+
+impl<S: StateMachine> Consensus<S> for Replica<S> {
+    type Msg = ConsensusMsg<S::Command>;
+
+    fn propose(&mut self, command: S::Command) -> Result<Proposal<S::Output>, RsmError> {
+        match self {
+            Replica::Raft(r) => r.propose(command),
+            Replica::Paxos(p) => p.propose(command),
+        }
+    }
+
+    fn tick(&mut self, now: Instant) -> Vec<Message<Self::Msg>> {
+        match self {
+            Replica::Raft(r) => wrap(r.tick(now), ConsensusMsg::Raft),
+            Replica::Paxos(p) => wrap(p.tick(now), ConsensusMsg::Paxos),
+        }
+    }
+
+    /// Messages for the other backend, from a misconfigured peer, are dropped.
+    fn handle(&mut self, from: &str, msg: Self::Msg, now: Instant) -> Vec<Message<Self::Msg>> {
+        match (self, msg) {
+            (Replica::Raft(r), ConsensusMsg::Raft(m)) => {
+                wrap(r.handle(from, m, now), ConsensusMsg::Raft)
+            }
+            (Replica::Paxos(p), ConsensusMsg::Paxos(m)) => {
+                wrap(p.handle(from, m, now), ConsensusMsg::Paxos)
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn leader(&self) -> Option<&String> {
+        match self {
+            Replica::Raft(r) => r.leader(),
+            Replica::Paxos(p) => p.leader(),
+        }
+    }
+
+    fn state_machine(&self) -> &S {
+        match self {
+            Replica::Raft(r) => r.state_machine(),
+            Replica::Paxos(p) => p.state_machine(),
+        }
+    }
+}
@@ -0,0 +1,846 @@
+//! Disk-backed, segmented append-only logs.
+//!
+//! Every key gets a directory holding a run of segment files named after the
+//! first offset they contain. Records are appended as
+//! `offset (u64) | length (u32) | checksum (u32) | payload`, all little-endian.
+//! Each segment keeps a sparse index of `(offset, file position)` entries,
+//! one every `index_interval_bytes`, so reads seek close to the requested
+//! offset instead of scanning the segment. Once a segment reaches
+//! `segment_bytes` it is sealed, its index is written next to it and a new
+//! segment is started.
+//!
+//! On open, sealed segments load their index files and the active segment is
+//! scanned; a torn record at its tail, left by a crash mid-write, is cut off.
+//!
+//! Retention and compaction only ever touch sealed segments: retention drops
+//! whole segments from the head of the log, and compaction rewrites them
+//! without the records a later record with the same sub-key supersedes.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+const HEADER_LEN: u64 = 16;
+const SEGMENT_SUFFIX: &str = "log";
+const INDEX_SUFFIX: &str = "index";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Sync after every append.
+    Always,
+    /// Sync on append once this long has passed since the last sync.
+    Interval(Duration),
+    /// Leave flushing to the OS.
+    Never,
+}
+
+#[derive(Debug, Clone)]
+pub struct StorageConfig {
+    pub dir: PathBuf,
+    pub segment_bytes: u64,
+    pub index_interval_bytes: u64,
+    pub fsync: FsyncPolicy,
+}
+
+impl StorageConfig {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            segment_bytes: 1 << 20,
+            index_interval_bytes: 4096,
+            fsync: FsyncPolicy::Interval(Duration::from_millis(100)),
+        }
+    }
+}
+
+/// Limits on how much of a log is kept. A record goes once any limit is
+/// exceeded; on disk this happens a whole sealed segment at a time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    pub max_records: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Segment {
+    base_offset: u64,
+    path: PathBuf,
+    size: u64,
+    index: Vec<(u64, u64)>,
+    /// Bytes written since the last index entry.
+    since_index: u64,
+}
+
+impl Segment {
+    fn index_path(&self) -> PathBuf {
+        self.path.with_extension(INDEX_SUFFIX)
+    }
+
+    fn write_index(&self) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(self.index_path())?);
+        for (offset, pos) in &self.index {
+            out.write_all(&offset.to_le_bytes())?;
+            out.write_all(&pos.to_le_bytes())?;
+        }
+        out.into_inner()?.sync_all()
+    }
+
+    fn load_index(&mut self) -> io::Result<bool> {
+        let Ok(data) = fs::read(self.index_path()) else {
+            return Ok(false);
+        };
+        if !data.len().is_multiple_of(16) {
+            return Ok(false);
+        }
+        self.index = data
+            .chunks_exact(16)
+            .map(|c| {
+                let offset = u64::from_le_bytes(c[..8].try_into().unwrap());
+                let pos = u64::from_le_bytes(c[8..].try_into().unwrap());
+                (offset, pos)
+            })
+            .collect();
+        self.size = fs::metadata(&self.path)?.len();
+        Ok(true)
+    }
+
+    /// Rebuilds the index by reading every record, truncating the file at the
+    /// first record that is incomplete or fails its checksum. Returns the
+    /// last offset found.
+    fn recover(&mut self, index_interval: u64) -> io::Result<Option<u64>> {
+        let file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        let mut reader = BufReader::new(&file);
+        let (mut pos, mut last) = (0, None);
+        self.index.clear();
+        self.since_index = 0;
+        while let Some((offset, payload)) = read_record(&mut reader)? {
+            if self.index.is_empty() || self.since_index >= index_interval {
+                self.index.push((offset, pos));
+                self.since_index = 0;
+            }
+            let len = HEADER_LEN + payload.len() as u64;
+            pos += len;
+            self.since_index += len;
+            last = Some(offset);
+        }
+        if file.metadata()?.len() != pos {
+            file.set_len(pos)?;
+            file.sync_all()?;
+        }
+        self.size = pos;
+        Ok(last)
+    }
+
+    fn records(&self) -> io::Result<Vec<(u64, Vec<u8>)>> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut records = Vec::new();
+        while let Some(record) = read_record(&mut reader)? {
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    fn remove(&self) -> io::Result<()> {
+        fs::remove_file(&self.path)?;
+        match fs::remove_file(self.index_path()) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// One key's log.
+#[derive(Debug)]
+pub struct SegmentedLog {
+    dir: PathBuf,
+    config: StorageConfig,
+    segments: Vec<Segment>,
+    writer: Option<BufWriter<File>>,
+    last_offset: Option<u64>,
+    last_sync: Instant,
+}
+
+impl SegmentedLog {
+    pub fn open(dir: impl Into<PathBuf>, config: StorageConfig) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let mut bases: Vec<u64> = fs::read_dir(&dir)?
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let path = e.path();
+                let is_segment = path.extension().is_some_and(|e| e == SEGMENT_SUFFIX);
+                is_segment
+                    .then(|| path.file_stem()?.to_str()?.parse().ok())
+                    .flatten()
+            })
+            .collect();
+        bases.sort();
+
+        let mut log = Self {
+            segments: Vec::new(),
+            writer: None,
+            last_offset: None,
+            last_sync: Instant::now(),
+            dir,
+            config,
+        };
+        let count = bases.len();
+        for (i, base) in bases.into_iter().enumerate() {
+            let mut segment = log.new_segment(base);
+            let active = i + 1 == count;
+            if active || !segment.load_index()? {
+                let last = segment.recover(log.config.index_interval_bytes)?;
+                log.last_offset = last.or(log.last_offset);
+            }
+            log.segments.push(segment);
+        }
+        if log.last_offset.is_none() && count > 1 {
+            // the active segment was empty; the sealed one before it is not
+            log.last_offset = log.segments[count - 2].recover(log.config.index_interval_bytes)?;
+        }
+        if log.last_offset.is_none() {
+            // a log truncated to nothing is an empty segment named after
+            // the offset that comes next
+            log.last_offset = log
+                .segments
+                .last()
+                .and_then(|s| s.base_offset.checked_sub(1));
+        }
+        Ok(log)
+    }
+
+    fn new_segment(&self, base_offset: u64) -> Segment {
+        Segment {
+            base_offset,
+            path: self
+                .dir
+                .join(format!("{:020}.{}", base_offset, SEGMENT_SUFFIX)),
+            size: 0,
+            index: Vec::new(),
+            since_index: 0,
+        }
+    }
+
+    /// The offset of the last record, if any.
+    pub fn last_offset(&self) -> Option<u64> {
+        self.last_offset
+    }
+
+    /// The offset of the first record still kept, if any.
+    pub fn start_offset(&self) -> Option<u64> {
+        // a segment's first index entry is its first record
+        self.segments
+            .iter()
+            .find_map(|s| s.index.first())
+            .map(|(offset, _)| *offset)
+    }
+
+    pub fn size_bytes(&self) -> u64 {
+        self.segments.iter().map(|s| s.size).sum()
+    }
+
+    /// Removes every record after `offset`. The segment that then ends the
+    /// log becomes the active one again.
+    pub fn truncate_after(&mut self, offset: u64) -> io::Result<()> {
+        if self.last_offset.is_none_or(|last| last <= offset) {
+            return Ok(());
+        }
+        self.sync()?;
+        self.writer = None;
+        while self.segments.last().is_some_and(|s| s.base_offset > offset) {
+            self.segments.pop().unwrap().remove()?;
+        }
+        self.last_offset = None;
+        let interval = self.config.index_interval_bytes;
+        if let Some(segment) = self.segments.last_mut() {
+            let keep: u64 = segment
+                .records()?
+                .iter()
+                .take_while(|(o, _)| *o <= offset)
+                .map(|(_, payload)| HEADER_LEN + payload.len() as u64)
+                .sum();
+            let file = OpenOptions::new().write(true).open(&segment.path)?;
+            file.set_len(keep)?;
+            file.sync_all()?;
+            match fs::remove_file(segment.index_path()) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            self.last_offset = segment.recover(interval)?;
+        }
+        // compaction may have emptied the segment that now ends the log
+        for segment in self.segments.iter().rev() {
+            if self.last_offset.is_some() {
+                break;
+            }
+            self.last_offset = segment.records()?.pop().map(|(o, _)| o);
+        }
+        Ok(())
+    }
+
+    /// Removes every record before `offset`. Whole segments below it are
+    /// deleted and the one it falls in is rewritten from it on; the end of
+    /// the log stays where it was even if no record is left.
+    pub fn truncate_before(&mut self, offset: u64) -> io::Result<()> {
+        if self.start_offset().is_none_or(|start| start >= offset) {
+            return Ok(());
+        }
+        self.sync()?;
+        self.writer = None;
+        let last = self.last_offset.expect("a log with a start has an end");
+        if last < offset {
+            for segment in self.segments.drain(..) {
+                segment.remove()?;
+            }
+            return self.roll(last + 1);
+        }
+        while self.segments.len() > 1 && self.segments[1].base_offset <= offset {
+            self.segments.remove(0).remove()?;
+        }
+        let sealed = self.segments.len() > 1;
+        let segment = &mut self.segments[0];
+        let records = segment.records()?;
+        let kept: Vec<_> = records.iter().filter(|(o, _)| *o >= offset).collect();
+        if kept.is_empty() {
+            // compaction left nothing at or after `offset` in this one
+            self.segments.remove(0).remove()?;
+            return Ok(());
+        }
+        let tmp = segment.path.with_extension("truncated");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        for (offset, payload) in kept {
+            write_record(&mut out, *offset, payload)?;
+        }
+        out.into_inner()?.sync_all()?;
+        fs::rename(&tmp, &segment.path)?;
+        segment.recover(self.config.index_interval_bytes)?;
+        if sealed {
+            segment.write_index()?;
+        }
+        Ok(())
+    }
+
+    /// Deletes sealed segments from the head of the log that fall entirely
+    /// outside `retention`. Returns how many were deleted.
+    pub fn apply_retention(&mut self, retention: &Retention) -> io::Result<usize> {
+        let mut total = self.size_bytes();
+        let mut removed = 0;
+        while self.segments.len() > 1 {
+            let (segment, next) = (&self.segments[0], &self.segments[1]);
+            let newest_kept = self
+                .last_offset
+                .zip(retention.max_records)
+                .map(|(last, max)| (last + 1).saturating_sub(max));
+            let too_many = newest_kept.is_some_and(|start| next.base_offset <= start);
+            let too_big = retention
+                .max_bytes
+                .is_some_and(|max| total - segment.size >= max);
+            let too_old = match retention.max_age {
+                Some(age) => {
+                    let modified = fs::metadata(&segment.path)?.modified()?;
+                    SystemTime::now()
+                        .duration_since(modified)
+                        .is_ok_and(|elapsed| elapsed > age)
+                }
+                None => false,
+            };
+            if !(too_many || too_big || too_old) {
+                break;
+            }
+            let segment = self.segments.remove(0);
+            segment.remove()?;
+            total -= segment.size;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Rewrites sealed segments without records superseded by a later record
+    /// with the same sub-key. Records `sub_key` returns `None` for are kept.
+    pub fn compact(&mut self, sub_key: impl Fn(&[u8]) -> Option<Vec<u8>>) -> io::Result<()> {
+        let mut latest: HashMap<Vec<u8>, u64> = HashMap::new();
+        for segment in &self.segments {
+            for (offset, payload) in segment.records()? {
+                if let Some(k) = sub_key(&payload) {
+                    latest.insert(k, offset);
+                }
+            }
+        }
+
+        let sealed = self.segments.len().saturating_sub(1);
+        let mut emptied = HashSet::new();
+        for (i, segment) in self.segments[..sealed].iter_mut().enumerate() {
+            let records = segment.records()?;
+            let kept: Vec<_> = records
+                .iter()
+                .filter(|(offset, payload)| sub_key(payload).is_none_or(|k| latest[&k] == *offset))
+                .collect();
+            if kept.len() == records.len() {
+                continue;
+            }
+            if kept.is_empty() {
+                segment.remove()?;
+                emptied.insert(i);
+                continue;
+            }
+            let tmp = segment.path.with_extension("cleaned");
+            let mut out = BufWriter::new(File::create(&tmp)?);
+            for (offset, payload) in kept {
+                write_record(&mut out, *offset, payload)?;
+            }
+            out.into_inner()?.sync_all()?;
+            fs::rename(&tmp, &segment.path)?;
+            segment.recover(self.config.index_interval_bytes)?;
+            segment.write_index()?;
+        }
+        let mut i = 0;
+        self.segments.retain(|_| {
+            i += 1;
+            !emptied.contains(&(i - 1))
+        });
+        Ok(())
+    }
+
+    /// Appends a record. Offsets must be strictly increasing but may skip.
+    pub fn append(&mut self, offset: u64, payload: &[u8]) -> io::Result<()> {
+        if self.last_offset.is_some_and(|last| offset <= last) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("offset {} is not past the end of the log", offset),
+            ));
+        }
+        let len = HEADER_LEN + payload.len() as u64;
+        let roll = self
+            .segments
+            .last()
+            .is_none_or(|s| s.size > 0 && s.size + len > self.config.segment_bytes);
+        if roll {
+            self.roll(offset)?;
+        }
+
+        let segment = self.segments.last_mut().unwrap();
+        if segment.index.is_empty() || segment.since_index >= self.config.index_interval_bytes {
+            segment.index.push((offset, segment.size));
+            segment.since_index = 0;
+        }
+        let writer = match &mut self.writer {
+            Some(w) => w,
+            None => self.writer.insert(BufWriter::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&segment.path)?,
+            )),
+        };
+        write_record(writer, offset, payload)?;
+        writer.flush()?;
+        segment.size += len;
+        segment.since_index += len;
+        self.last_offset = Some(offset);
+
+        let sync = match self.config.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            FsyncPolicy::Never => false,
+        };
+        if sync {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        if let Some(w) = &mut self.writer {
+            w.flush()?;
+            w.get_ref().sync_data()?;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    fn roll(&mut self, base_offset: u64) -> io::Result<()> {
+        if !self.segments.is_empty() {
+            self.sync()?;
+            self.segments.last().unwrap().write_index()?;
+        }
+        self.writer = None;
+        let segment = self.new_segment(base_offset);
+        File::create(&segment.path)?;
+        self.segments.push(segment);
+        Ok(())
+    }
+
+    /// Up to `max` records with offsets at or after `from`, in order.
+    pub fn read(&self, from: u64, max: usize) -> io::Result<Vec<(u64, Vec<u8>)>> {
+        let mut out = Vec::new();
+        // the last segment starting at or before `from`
+        let first = self
+            .segments
+            .partition_point(|s| s.base_offset <= from)
+            .saturating_sub(1);
+        for segment in &self.segments[first..] {
+            if out.len() >= max {
+                break;
+            }
+            let i = segment.index.partition_point(|(o, _)| *o <= from);
+            let pos = i.checked_sub(1).map_or(0, |i| segment.index[i].1);
+            let mut reader = BufReader::new(File::open(&segment.path)?);
+            reader.seek(SeekFrom::Start(pos))?;
+            let mut read = pos;
+            while out.len() < max && read < segment.size {
+                let Some((offset, payload)) = read_record(&mut reader)? else {
+                    break;
+                };
+                read += HEADER_LEN + payload.len() as u64;
+                if offset >= from {
+                    out.push((offset, payload));
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Logs for many keys under one directory.
+#[derive(Debug)]
+pub struct Storage {
+    config: StorageConfig,
+    logs: HashMap<String, SegmentedLog>,
+}
+
+impl Storage {
+    /// Opens every key's log found under `config.dir`.
+    pub fn open(config: StorageConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let mut logs = HashMap::new();
+        for entry in fs::read_dir(&config.dir)? {
+            let path = entry?.path();
+            let Some(key) = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(decode_key)
+            else {
+                continue;
+            };
+            logs.insert(key, SegmentedLog::open(&path, config.clone())?);
+        }
+        Ok(Self { config, logs })
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.logs.keys()
+    }
+
+    pub fn last_offset(&self, key: &str) -> Option<u64> {
+        self.logs.get(key).and_then(|l| l.last_offset())
+    }
+
+    pub fn truncate_after(&mut self, key: &str, offset: u64) -> io::Result<()> {
+        match self.logs.get_mut(key) {
+            Some(log) => log.truncate_after(offset),
+            None => Ok(()),
+        }
+    }
+
+    pub fn truncate_before(&mut self, key: &str, offset: u64) -> io::Result<()> {
+        match self.logs.get_mut(key) {
+            Some(log) => log.truncate_before(offset),
+            None => Ok(()),
+        }
+    }
+
+    /// Deletes `key`'s log and its directory.
+    pub fn delete(&mut self, key: &str) -> io::Result<()> {
+        if self.logs.remove(key).is_none() {
+            return Ok(());
+        }
+        fs::remove_dir_all(self.dir_of(key))
+    }
+
+    pub fn logs_mut(&mut self) -> impl Iterator<Item = (&String, &mut SegmentedLog)> {
+        self.logs.iter_mut()
+    }
+
+    pub fn append(&mut self, key: &str, offset: u64, payload: &[u8]) -> io::Result<()> {
+        let log = match self.logs.get_mut(key) {
+            Some(log) => log,
+            None => {
+                let dir = self.dir_of(key);
+                let log = SegmentedLog::open(dir, self.config.clone())?;
+                self.logs.entry(key.to_string()).or_insert(log)
+            }
+        };
+        log.append(offset, payload)
+    }
+
+    pub fn read(&self, key: &str, from: u64, max: usize) -> io::Result<Vec<(u64, Vec<u8>)>> {
+        match self.logs.get(key) {
+            Some(log) => log.read(from, max),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.logs.values_mut().try_for_each(|l| l.sync())
+    }
+
+    fn dir_of(&self, key: &str) -> PathBuf {
+        self.config.dir.join(encode_key(key))
+    }
+}
+
+fn write_record(out: &mut impl Write, offset: u64, payload: &[u8]) -> io::Result<()> {
+    out.write_all(&offset.to_le_bytes())?;
+    out.write_all(&(payload.len() as u32).to_le_bytes())?;
+    out.write_all(&checksum(payload).to_le_bytes())?;
+    out.write_all(payload)
+}
+
+fn read_record(reader: &mut impl Read) -> io::Result<Option<(u64, Vec<u8>)>> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let offset = u64::from_le_bytes(header[..8].try_into().unwrap());
+    let len = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    let sum = u32::from_le_bytes(header[12..].try_into().unwrap());
+    let mut payload = vec![0; len as usize];
+    match reader.read_exact(&mut payload) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    if checksum(&payload) != sum {
+        return Ok(None);
+    }
+    Ok(Some((offset, payload)))
+}
+
+/// FNV-1a, enough to catch torn writes.
+fn checksum(data: &[u8]) -> u32 {
+    data.iter()
+        .fold(0x811c9dc5, |h, b| (h ^ *b as u32).wrapping_mul(0x0100_0193))
+}
+
+/// Keys become directory names, so they are hex-encoded to be safe on any
+/// filesystem.
+fn encode_key(key: &str) -> String {
+    key.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_key(name: &str) -> Option<String> {
+    if !name.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes = (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&name[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+impl Drop for SegmentedLog {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(name: &str) -> StorageConfig {
+        let dir = std::env::temp_dir().join(format!("nazgul-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        StorageConfig {
+            dir,
+            segment_bytes: 256,
+            index_interval_bytes: 64,
+            fsync: FsyncPolicy::Never,
+        }
+    }
+
+    fn payload(i: u64) -> Vec<u8> {
+        format!("message {}", i).into_bytes()
+    }
+
+    #[test]
+    fn reads_across_rolled_segments() {
+        let config = config("rolled");
+        let mut storage = Storage::open(config.clone()).unwrap();
+        for i in 1..=50 {
+            storage.append("k", i, &payload(i)).unwrap();
+        }
+        assert!(storage.logs["k"].segments.len() > 3);
+
+        let read = storage.read("k", 17, 10).unwrap();
+        let offsets: Vec<_> = read.iter().map(|(o, _)| *o).collect();
+        assert_eq!(offsets, (17..27).collect::<Vec<_>>());
+        assert_eq!(read[0].1, payload(17));
+        assert!(storage.read("k", 51, 10).unwrap().is_empty());
+        assert!(storage.append("k", 50, b"again").is_err());
+        fs::remove_dir_all(config.dir).unwrap();
+    }
+
+    #[test]
+    fn rebuilds_state_on_reopen() {
+        let config = config("reopen");
+        let mut storage = Storage::open(config.clone()).unwrap();
+        for i in 1..=30 {
+            storage.append("a/b", i, &payload(i)).unwrap();
+        }
+        storage.append("other", 1, b"x").unwrap();
+        drop(storage);
+
+        let mut storage = Storage::open(config.clone()).unwrap();
+        let mut keys: Vec<_> = storage.keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["a/b".to_string(), "other".to_string()]);
+        assert_eq!(storage.last_offset("a/b"), Some(30));
+        storage.append("a/b", 31, &payload(31)).unwrap();
+        let read = storage.read("a/b", 1, 100).unwrap();
+        assert_eq!(read.len(), 31);
+        assert_eq!(read[30], (31, payload(31)));
+        fs::remove_dir_all(config.dir).unwrap();
+    }
+
+    #[test]
+    fn retention_drops_whole_segments_from_the_head() {
+        let config = config("retention");
+        let mut log = SegmentedLog::open(config.dir.join("k"), config.clone()).unwrap();
+        for i in 1..=50 {
+            log.append(i, &payload(i)).unwrap();
+        }
+        let segments = log.segments.len();
+        let by_count = Retention {
+            max_records: Some(20),
+            ..Retention::default()
+        };
+        assert!(log.apply_retention(&by_count).unwrap() > 0);
+        let start = log.start_offset().unwrap();
+        assert!(start > 1 && start <= 31, "start {start}");
+        assert_eq!(log.read(0, 1).unwrap()[0].0, start);
+
+        let by_bytes = Retention {
+            max_bytes: Some(1),
+            ..Retention::default()
+        };
+        log.apply_retention(&by_bytes).unwrap();
+        assert_eq!(log.segments.len(), 1);
+        assert!(segments > 1);
+        assert_eq!(log.last_offset(), Some(50));
+        fs::remove_dir_all(config.dir).unwrap();
+    }
+
+    #[test]
+    fn compaction_keeps_the_latest_record_per_sub_key() {
+        let config = config("compact");
+        let dir = config.dir.join("k");
+        let mut log = SegmentedLog::open(&dir, config.clone()).unwrap();
+        for i in 1..=40 {
+            log.append(i, format!("{}={}", i % 3, i).as_bytes())
+                .unwrap();
+        }
+        let sub_key = |p: &[u8]| p.split(|b| *b == b'=').next().map(|k| k.to_vec());
+        log.compact(sub_key).unwrap();
+
+        let active = log.segments.last().unwrap().base_offset;
+        let kept: Vec<_> = log.read(0, 100).unwrap();
+        assert!(kept.iter().all(|(o, _)| *o >= active || *o >= 38));
+        assert_eq!(kept.last().unwrap().0, 40);
+        drop(log);
+
+        let log = SegmentedLog::open(&dir, config.clone()).unwrap();
+        assert_eq!(log.read(0, 100).unwrap(), kept);
+        fs::remove_dir_all(config.dir).unwrap();
+    }
+
+    #[test]
+    fn truncation_reopens_the_segment_it_ends_in() {
+        let config = config("truncate");
+        let dir = config.dir.join("k");
+        let mut log = SegmentedLog::open(&dir, config.clone()).unwrap();
+        for i in 1..=30 {
+            log.append(i, &payload(i)).unwrap();
+        }
+        assert!(log.segments.len() > 2);
+        log.truncate_after(7).unwrap();
+        assert_eq!(log.last_offset(), Some(7));
+        log.append(8, b"new").unwrap();
+        drop(log);
+
+        let log = SegmentedLog::open(&dir, config.clone()).unwrap();
+        let records = log.read(0, 100).unwrap();
+        assert_eq!(records.len(), 8);
+        assert_eq!(records[7], (8, b"new".to_vec()));
+        fs::remove_dir_all(config.dir).unwrap();
+    }
+
+    #[test]
+    fn truncating_the_head_keeps_the_end() {
+        let config = config("head");
+        let mut storage = Storage::open(config.clone()).unwrap();
+        for i in 1..=30 {
+            storage.append("k", i, &payload(i)).unwrap();
+        }
+        storage.truncate_before("k", 12).unwrap();
+        let records = storage.read("k", 0, 100).unwrap();
+        assert_eq!(records.first().map(|r| r.0), Some(12));
+        assert_eq!(records.len(), 19);
+
+        storage.truncate_before("k", 31).unwrap();
+        assert!(storage.read("k", 0, 100).unwrap().is_empty());
+        assert_eq!(storage.last_offset("k"), Some(30));
+        drop(storage);
+
+        let mut storage = Storage::open(config.clone()).unwrap();
+        assert_eq!(storage.last_offset("k"), Some(30));
+        storage.append("k", 31, b"next").unwrap();
+        assert_eq!(
+            storage.read("k", 0, 100).unwrap(),
+            vec![(31, b"next".to_vec())]
+        );
+
+        storage.delete("k").unwrap();
+        assert_eq!(storage.keys().count(), 0);
+        assert!(Storage::open(config.clone())
+            .unwrap()
+            .read("k", 0, 1)
+            .unwrap()
+            .is_empty());
+        fs::remove_dir_all(config.dir).unwrap();
+    }
+
+    #[test]
+    fn truncates_a_torn_tail() {
+        let config = config("torn");
+        let mut log = SegmentedLog::open(config.dir.join("k"), config.clone()).unwrap();
+        log.append(1, b"complete").unwrap();
+        log.append(2, b"torn write").unwrap();
+        let path = log.segments[0].path.clone();
+        drop(log);
+
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        let mut log = SegmentedLog::open(config.dir.join("k"), config.clone()).unwrap();
+        assert_eq!(log.last_offset(), Some(1));
+        log.append(2, b"rewritten").unwrap();
+        assert_eq!(
+            log.read(1, 10).unwrap(),
+            vec![(1, b"complete".to_vec()), (2, b"rewritten".to_vec())]
+        );
+        fs::remove_dir_all(config.dir).unwrap();
+    }
+}
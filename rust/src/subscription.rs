@@ -0,0 +1,306 @@
+//! Push subscriptions: which messages a node owes each subscribed client.
+//!
+//! A client subscribes to keys from an offset and grants credits, one per
+//! message it is willing to take. The node polls for it, pushes what it
+//! finds and spends a credit per message. Pushes are not reliable, so the
+//! client confirms the last offset it consumed of each key when it grants
+//! more credits. A push left unconfirmed for the redelivery timeout is taken
+//! as lost: its key goes back to the confirmed offset and its credits are
+//! refunded, so the messages are pushed again.
+//!
+//! Nothing here does I/O: [`Subscriptions::due`] says what to poll for, and
+//! [`Subscriptions::pushed`] trims the result to what may still be pushed.
+
+use crate::log::Isolation;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_REDELIVERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A client's position in one key.
+#[derive(Debug)]
+struct Cursor {
+    /// The next offset to push.
+    next: usize,
+    /// Offsets pushed but not confirmed yet, with when they were pushed.
+    in_flight: VecDeque<(usize, Instant)>,
+}
+
+impl Cursor {
+    fn new(from: usize) -> Self {
+        Self {
+            next: from,
+            in_flight: VecDeque::new(),
+        }
+    }
+
+    /// Confirms every offset up to `consumed`.
+    fn confirm(&mut self, consumed: usize) {
+        self.in_flight.retain(|(o, _)| *o > consumed);
+        self.next = self.next.max(consumed + 1);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Subscription {
+    cursors: HashMap<String, Cursor>,
+    isolation: Isolation,
+    credits: usize,
+    /// Set from [`Subscriptions::due`] until the poll for it is done, so a
+    /// slow poll holds up this client alone and is never run twice at once.
+    busy: bool,
+}
+
+/// A poll to run for a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Due {
+    pub client: String,
+    pub offsets: HashMap<String, usize>,
+    pub isolation: Isolation,
+}
+
+#[derive(Debug)]
+pub struct Subscriptions {
+    clients: HashMap<String, Subscription>,
+    redelivery_timeout: Duration,
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Self::new(DEFAULT_REDELIVERY_TIMEOUT)
+    }
+}
+
+impl Subscriptions {
+    pub fn new(redelivery_timeout: Duration) -> Self {
+        Self {
+            clients: HashMap::new(),
+            redelivery_timeout,
+        }
+    }
+
+    /// Subscribes `client` to `offsets`, pushing from each offset on. Keys
+    /// subscribed again start over at the new offset.
+    pub fn subscribe(
+        &mut self,
+        client: &str,
+        offsets: HashMap<String, usize>,
+        credits: usize,
+        isolation: Isolation,
+    ) {
+        let sub = self.clients.entry(client.to_string()).or_default();
+        for (key, from) in offsets {
+            if let Some(old) = sub.cursors.insert(key, Cursor::new(from)) {
+                sub.credits += old.in_flight.len();
+            }
+        }
+        sub.credits += credits;
+        sub.isolation = isolation;
+    }
+
+    /// Grants `client` more credits and confirms the last offset it consumed
+    /// of each key in `consumed`. Returns the credits it has now, or `None`
+    /// if it is not subscribed.
+    pub fn credit(
+        &mut self,
+        client: &str,
+        credits: usize,
+        consumed: &HashMap<String, usize>,
+    ) -> Option<usize> {
+        let sub = self.clients.get_mut(client)?;
+        for (key, offset) in consumed {
+            if let Some(cursor) = sub.cursors.get_mut(key) {
+                cursor.confirm(*offset);
+            }
+        }
+        sub.credits += credits;
+        Some(sub.credits)
+    }
+
+    /// Drops `keys` from `client`'s subscription, or all of it without them.
+    pub fn unsubscribe(&mut self, client: &str, keys: Option<&[String]>) {
+        match keys {
+            Some(keys) => {
+                if let Some(sub) = self.clients.get_mut(client) {
+                    for key in keys {
+                        sub.cursors.remove(key);
+                    }
+                }
+            }
+            None => {
+                self.clients.remove(client);
+            }
+        }
+    }
+
+    /// The polls to run now: one per client with credits and keys that is
+    /// not waiting on a poll already. Pushes unconfirmed for too long are
+    /// rewound first.
+    pub fn due(&mut self, now: Instant) -> Vec<Due> {
+        let mut due = Vec::new();
+        for (client, sub) in &mut self.clients {
+            if sub.busy {
+                continue;
+            }
+            for cursor in sub.cursors.values_mut() {
+                let lost = cursor
+                    .in_flight
+                    .front()
+                    .is_some_and(|(_, at)| now.duration_since(*at) >= self.redelivery_timeout);
+                if lost {
+                    cursor.next = cursor.in_flight[0].0;
+                    sub.credits += cursor.in_flight.len();
+                    cursor.in_flight.clear();
+                }
+            }
+            if sub.credits == 0 || sub.cursors.is_empty() {
+                continue;
+            }
+            sub.busy = true;
+            due.push(Due {
+                client: client.clone(),
+                offsets: sub
+                    .cursors
+                    .iter()
+                    .map(|(k, c)| (k.clone(), c.next))
+                    .collect(),
+                isolation: sub.isolation,
+            });
+        }
+        due
+    }
+
+    /// The poll for `due` returned `msgs`, runs of messages per key. Trims
+    /// them to the client's credits and to the keys it is still subscribed
+    /// to at the polled offsets, moves those keys on past them, and returns
+    /// the credits left; `None` if nothing is left to push.
+    pub fn pushed<V>(
+        &mut self,
+        due: &Due,
+        msgs: &mut HashMap<String, Vec<(usize, V)>>,
+        now: Instant,
+    ) -> Option<usize> {
+        let Some(sub) = self.clients.get_mut(&due.client) else {
+            msgs.clear();
+            return None;
+        };
+        sub.busy = false;
+        msgs.retain(|key, run| {
+            let Some(cursor) = sub.cursors.get_mut(key) else {
+                return false;
+            };
+            // the client moved the key on while the poll ran
+            if Some(&cursor.next) != due.offsets.get(key) {
+                return false;
+            }
+            run.truncate(sub.credits);
+            let Some((last, _)) = run.last() else {
+                return false;
+            };
+            cursor.next = last + 1;
+            cursor.in_flight.extend(run.iter().map(|(o, _)| (*o, now)));
+            sub.credits -= run.len();
+            true
+        });
+        (!msgs.is_empty()).then_some(sub.credits)
+    }
+
+    /// The poll for `due` failed; the client is polled for again next time.
+    pub fn failed(&mut self, due: &Due) {
+        if let Some(sub) = self.clients.get_mut(&due.client) {
+            sub.busy = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: &str = "c1";
+
+    fn offsets(pairs: &[(&str, usize)]) -> HashMap<String, usize> {
+        pairs.iter().map(|(k, o)| (k.to_string(), *o)).collect()
+    }
+
+    fn run(from: usize, to: usize) -> Vec<(usize, ())> {
+        (from..=to).map(|o| (o, ())).collect()
+    }
+
+    /// Polls for the one due client, finding offsets `from..=to` of "a".
+    fn push(
+        subs: &mut Subscriptions,
+        from: usize,
+        to: usize,
+        now: Instant,
+    ) -> Option<(Vec<usize>, usize)> {
+        let due = subs.due(now).pop()?;
+        assert_eq!(due.offsets["a"], from);
+        let mut msgs = HashMap::from([("a".to_string(), run(from, to))]);
+        let credits = subs.pushed(&due, &mut msgs, now)?;
+        Some((msgs["a"].iter().map(|(o, _)| *o).collect(), credits))
+    }
+
+    #[test]
+    fn pushes_no_more_than_the_credits() {
+        let mut subs = Subscriptions::default();
+        let now = Instant::now();
+        subs.subscribe(CLIENT, offsets(&[("a", 1)]), 2, Isolation::ReadUncommitted);
+        assert_eq!(push(&mut subs, 1, 5, now), Some((vec![1, 2], 0)));
+        assert!(subs.due(now).is_empty());
+
+        assert_eq!(subs.credit(CLIENT, 3, &offsets(&[("a", 2)])), Some(3));
+        assert_eq!(push(&mut subs, 3, 4, now), Some((vec![3, 4], 1)));
+        assert_eq!(subs.credit("c2", 1, &HashMap::new()), None);
+    }
+
+    #[test]
+    fn polls_a_client_once_at_a_time() {
+        let mut subs = Subscriptions::default();
+        let now = Instant::now();
+        subs.subscribe(CLIENT, offsets(&[("a", 1)]), 5, Isolation::ReadUncommitted);
+        let due = subs.due(now).pop().unwrap();
+        assert!(subs.due(now).is_empty());
+        subs.failed(&due);
+        assert_eq!(subs.due(now), vec![due]);
+    }
+
+    #[test]
+    fn drops_what_a_poll_finds_for_unsubscribed_keys() {
+        let mut subs = Subscriptions::default();
+        let now = Instant::now();
+        let both = offsets(&[("a", 1), ("b", 1)]);
+        subs.subscribe(CLIENT, both, 5, Isolation::ReadUncommitted);
+        let due = subs.due(now).pop().unwrap();
+        subs.unsubscribe(CLIENT, Some(&["b".to_string()]));
+        let mut msgs = HashMap::from([("a".to_string(), run(1, 1)), ("b".to_string(), run(1, 1))]);
+        assert_eq!(subs.pushed(&due, &mut msgs, now), Some(4));
+        assert!(!msgs.contains_key("b"));
+
+        let due = subs.due(now).pop().unwrap();
+        subs.unsubscribe(CLIENT, None);
+        let mut msgs = HashMap::from([("a".to_string(), run(2, 2))]);
+        assert_eq!(subs.pushed(&due, &mut msgs, now), None);
+        assert!(subs.due(now).is_empty());
+    }
+
+    #[test]
+    fn redelivers_pushes_left_unconfirmed() {
+        let timeout = Duration::from_millis(100);
+        let mut subs = Subscriptions::new(timeout);
+        let mut now = Instant::now();
+        subs.subscribe(CLIENT, offsets(&[("a", 1)]), 3, Isolation::ReadUncommitted);
+        assert_eq!(push(&mut subs, 1, 3, now), Some((vec![1, 2, 3], 0)));
+
+        // 1 arrived and was consumed, the rest of the push was lost
+        subs.credit(CLIENT, 0, &offsets(&[("a", 1)]));
+        now += timeout;
+        assert_eq!(push(&mut subs, 2, 3, now), Some((vec![2, 3], 0)));
+
+        subs.credit(CLIENT, 1, &offsets(&[("a", 3)]));
+        now += timeout;
+        assert_eq!(push(&mut subs, 4, 4, now), Some((vec![4], 0)));
+    }
+}
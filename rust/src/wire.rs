@@ -0,0 +1,460 @@
+//! Encoding of the Kafka binary protocol.
+//!
+//! Requests and responses are built from big-endian integers, strings and
+//! byte arrays prefixed with their length, and arrays prefixed with their
+//! element count; a length of -1 stands for null. Only the versions of each
+//! message that predate the flexible encoding are covered, so there are no
+//! compact types or tagged fields.
+//!
+//! Messages travel in record batches (magic 2): a fixed header with a CRC-32C
+//! over the rest of the batch, followed by records whose fields are varints
+//! relative to the batch's base offset and timestamp. Compressed batches are
+//! not supported.
+
+use anyhow::{bail, ensure, Context};
+
+const MAGIC: i8 = 2;
+const COMPRESSION_MASK: i16 = 0x07;
+/// Bytes from the start of a batch to its batch length field, inclusive.
+const BATCH_OVERHEAD: usize = 12;
+/// Bytes from the start of a batch up to and including its CRC.
+const CRC_END: usize = 21;
+
+/// Reads protocol types off the front of a buffer.
+#[derive(Debug)]
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        ensure!(self.buf.len() >= n, "truncated message");
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("took N bytes"))
+    }
+
+    pub fn i8(&mut self) -> anyhow::Result<i8> {
+        Ok(i8::from_be_bytes(self.array()?))
+    }
+
+    pub fn i16(&mut self) -> anyhow::Result<i16> {
+        Ok(i16::from_be_bytes(self.array()?))
+    }
+
+    pub fn i32(&mut self) -> anyhow::Result<i32> {
+        Ok(i32::from_be_bytes(self.array()?))
+    }
+
+    pub fn i64(&mut self) -> anyhow::Result<i64> {
+        Ok(i64::from_be_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    pub fn bool(&mut self) -> anyhow::Result<bool> {
+        Ok(self.i8()? != 0)
+    }
+
+    pub fn string(&mut self) -> anyhow::Result<String> {
+        self.nullable_string()?.context("null string")
+    }
+
+    pub fn nullable_string(&mut self) -> anyhow::Result<Option<String>> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        let data = self.take(len as usize)?;
+        Ok(Some(
+            String::from_utf8(data.to_vec()).context("string is not UTF-8")?,
+        ))
+    }
+
+    pub fn nullable_bytes(&mut self) -> anyhow::Result<Option<&'a [u8]>> {
+        let len = self.i32()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.take(len as usize)?))
+    }
+
+    /// Reads an array, calling `item` once per element.
+    pub fn array_of<T>(
+        &mut self,
+        item: impl FnMut(&mut Self) -> anyhow::Result<T>,
+    ) -> anyhow::Result<Vec<T>> {
+        Ok(self.nullable_array_of(item)?.unwrap_or_default())
+    }
+
+    pub fn nullable_array_of<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> anyhow::Result<T>,
+    ) -> anyhow::Result<Option<Vec<T>>> {
+        let len = self.i32()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        // every element takes at least a byte, so a bogus count cannot
+        // reserve more than the buffer
+        let mut items = Vec::with_capacity((len as usize).min(self.buf.len()));
+        for _ in 0..len {
+            items.push(item(self)?);
+        }
+        Ok(Some(items))
+    }
+
+    /// A zigzag-encoded variable-length integer.
+    pub fn varint(&mut self) -> anyhow::Result<i32> {
+        let v = self.varlong()?;
+        i32::try_from(v).context("varint out of range")
+    }
+
+    pub fn varlong(&mut self) -> anyhow::Result<i64> {
+        let mut raw = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.array::<1>()?[0];
+            raw |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok((raw >> 1) as i64 ^ -((raw & 1) as i64));
+            }
+        }
+        bail!("varint longer than 64 bits")
+    }
+
+    /// Bytes prefixed with a varint length, -1 for null.
+    fn varint_bytes(&mut self) -> anyhow::Result<Option<&'a [u8]>> {
+        let len = self.varint()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.take(len as usize)?))
+    }
+}
+
+/// Builds a message out of protocol types.
+#[derive(Debug, Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn i8(&mut self, v: i8) -> &mut Self {
+        self.raw(&v.to_be_bytes())
+    }
+
+    pub fn i16(&mut self, v: i16) -> &mut Self {
+        self.raw(&v.to_be_bytes())
+    }
+
+    pub fn i32(&mut self, v: i32) -> &mut Self {
+        self.raw(&v.to_be_bytes())
+    }
+
+    pub fn i64(&mut self, v: i64) -> &mut Self {
+        self.raw(&v.to_be_bytes())
+    }
+
+    pub fn u32(&mut self, v: u32) -> &mut Self {
+        self.raw(&v.to_be_bytes())
+    }
+
+    pub fn bool(&mut self, v: bool) -> &mut Self {
+        self.i8(v as i8)
+    }
+
+    pub fn string(&mut self, v: &str) -> &mut Self {
+        self.nullable_string(Some(v))
+    }
+
+    pub fn nullable_string(&mut self, v: Option<&str>) -> &mut Self {
+        match v {
+            Some(s) => self.i16(s.len() as i16).raw(s.as_bytes()),
+            None => self.i16(-1),
+        }
+    }
+
+    pub fn nullable_bytes(&mut self, v: Option<&[u8]>) -> &mut Self {
+        match v {
+            Some(b) => self.i32(b.len() as i32).raw(b),
+            None => self.i32(-1),
+        }
+    }
+
+    /// Starts an array of `len` elements, which the caller writes next.
+    pub fn array(&mut self, len: usize) -> &mut Self {
+        self.i32(len as i32)
+    }
+
+    pub fn null_array(&mut self) -> &mut Self {
+        self.i32(-1)
+    }
+
+    pub fn varint(&mut self, v: i32) -> &mut Self {
+        self.varlong(v.into())
+    }
+
+    pub fn varlong(&mut self, v: i64) -> &mut Self {
+        let mut raw = ((v << 1) ^ (v >> 63)) as u64;
+        while raw >= 0x80 {
+            self.buf.push(raw as u8 | 0x80);
+            raw >>= 7;
+        }
+        self.buf.push(raw as u8);
+        self
+    }
+
+    fn varint_bytes(&mut self, v: Option<&[u8]>) -> &mut Self {
+        match v {
+            Some(b) => self.varint(b.len() as i32).raw(b),
+            None => self.varint(-1),
+        }
+    }
+
+    pub fn raw(&mut self, data: &[u8]) -> &mut Self {
+        self.buf.extend_from_slice(data);
+        self
+    }
+}
+
+/// A record with its offset and timestamp resolved against its batch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Record {
+    pub offset: i64,
+    /// Milliseconds since the epoch.
+    pub timestamp: i64,
+    pub key: Option<Vec<u8>>,
+    pub value: Option<Vec<u8>>,
+    pub headers: Vec<(String, Option<Vec<u8>>)>,
+}
+
+/// Decodes the records of a produce request, which may hold several batches.
+pub fn decode_batches(mut data: &[u8]) -> anyhow::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    while !data.is_empty() {
+        let mut header = Reader::new(data);
+        let base_offset = header.i64()?;
+        let len = header.i32()?;
+        ensure!(len >= 0, "negative batch length");
+        let batch = Reader::new(data).take(BATCH_OVERHEAD + len as usize)?;
+        data = &data[batch.len()..];
+        let mut r = Reader::new(&batch[BATCH_OVERHEAD..]);
+        let _leader_epoch = r.i32()?;
+        let magic = r.i8()?;
+        ensure!(magic == MAGIC, "unsupported record batch magic {}", magic);
+        let crc = r.u32()?;
+        ensure!(
+            crc == crc32c(&batch[CRC_END..]),
+            "record batch checksum mismatch"
+        );
+        let attributes = r.i16()?;
+        ensure!(
+            attributes & COMPRESSION_MASK == 0,
+            "compressed record batches are not supported"
+        );
+        let _last_offset_delta = r.i32()?;
+        let base_timestamp = r.i64()?;
+        let _max_timestamp = r.i64()?;
+        let _producer_id = r.i64()?;
+        let _producer_epoch = r.i16()?;
+        let _base_sequence = r.i32()?;
+        let count = r.i32()?;
+        for _ in 0..count {
+            let len = r.varint()?;
+            ensure!(len >= 0, "negative record length");
+            let mut record = Reader::new(r.take(len as usize)?);
+            let _attributes = record.i8()?;
+            let timestamp = base_timestamp
+                .checked_add(record.varlong()?)
+                .context("record timestamp out of range")?;
+            let offset = base_offset
+                .checked_add(i64::from(record.varint()?))
+                .context("record offset out of range")?;
+            let key = record.varint_bytes()?.map(<[u8]>::to_vec);
+            let value = record.varint_bytes()?.map(<[u8]>::to_vec);
+            let mut headers = Vec::new();
+            for _ in 0..record.varint()? {
+                let name = record.varint_bytes()?.context("null header key")?;
+                let name = String::from_utf8(name.to_vec()).context("header key is not UTF-8")?;
+                headers.push((name, record.varint_bytes()?.map(<[u8]>::to_vec)));
+            }
+            records.push(Record {
+                offset,
+                timestamp,
+                key,
+                value,
+                headers,
+            });
+        }
+    }
+    Ok(records)
+}
+
+/// Encodes `records` as one batch based at the first record's offset and
+/// timestamp. Offsets may have gaps but must increase.
+pub fn encode_batch(records: &[Record]) -> Vec<u8> {
+    let (Some(first), Some(last)) = (records.first(), records.last()) else {
+        return Vec::new();
+    };
+    let mut body = Writer::new();
+    body.i16(0)
+        .i32((last.offset - first.offset) as i32)
+        .i64(first.timestamp)
+        .i64(
+            records
+                .iter()
+                .map(|r| r.timestamp)
+                .max()
+                .unwrap_or_default(),
+        )
+        .i64(-1)
+        .i16(-1)
+        .i32(-1)
+        .array(records.len());
+    for record in records {
+        let mut w = Writer::new();
+        w.i8(0)
+            .varlong(record.timestamp - first.timestamp)
+            .varint((record.offset - first.offset) as i32)
+            .varint_bytes(record.key.as_deref())
+            .varint_bytes(record.value.as_deref())
+            .varint(record.headers.len() as i32);
+        for (name, value) in &record.headers {
+            w.varint_bytes(Some(name.as_bytes()))
+                .varint_bytes(value.as_deref());
+        }
+        let record = w.into_inner();
+        body.varint(record.len() as i32).raw(&record);
+    }
+    let body = body.into_inner();
+
+    let mut batch = Writer::new();
+    batch
+        .i64(first.offset)
+        .i32((body.len() + CRC_END - BATCH_OVERHEAD) as i32)
+        .i32(-1)
+        .i8(MAGIC)
+        .u32(crc32c(&body))
+        .raw(&body);
+    batch.into_inner()
+}
+
+/// CRC-32C (Castagnoli), as record batches use.
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82f6_3b78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varints_round_trip() {
+        let values = [0, 1, -1, 63, -64, 64, 300, -300, i64::MAX, i64::MIN];
+        let mut w = Writer::new();
+        for v in values {
+            w.varlong(v);
+        }
+        let buf = w.into_inner();
+        let mut r = Reader::new(&buf);
+        for v in values {
+            assert_eq!(r.varlong().unwrap(), v);
+        }
+        assert_eq!(r.remaining(), 0);
+
+        // zigzag keeps small negatives short
+        let mut w = Writer::new();
+        w.varint(-1).varint(300);
+        assert_eq!(w.into_inner(), vec![1, 0xd8, 0x04]);
+    }
+
+    #[test]
+    fn crc32c_matches_the_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn record_batches_round_trip() {
+        let records = vec![
+            Record {
+                offset: 7,
+                timestamp: 1_000,
+                key: Some(b"k".to_vec()),
+                value: Some(b"hello".to_vec()),
+                headers: vec![("h".to_string(), Some(b"v".to_vec()))],
+            },
+            Record {
+                offset: 9,
+                timestamp: 1_002,
+                key: None,
+                value: None,
+                headers: vec![("empty".to_string(), None)],
+            },
+        ];
+        let mut data = encode_batch(&records);
+        data.extend(encode_batch(&records[1..]));
+        let decoded = decode_batches(&data).unwrap();
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[..2], records[..]);
+        assert_eq!(decoded[2], records[1]);
+
+        let mut corrupt = encode_batch(&records);
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        assert!(decode_batches(&corrupt).is_err());
+    }
+
+    #[test]
+    fn out_of_range_deltas_are_rejected() {
+        let record = |offset, timestamp| Record {
+            offset,
+            timestamp,
+            key: None,
+            value: None,
+            headers: Vec::new(),
+        };
+        let data = encode_batch(&[record(0, 0), record(1, 1)]);
+
+        let mut offsets = data.clone();
+        offsets[..8].copy_from_slice(&i64::MAX.to_be_bytes());
+        let err = decode_batches(&offsets).unwrap_err();
+        assert!(format!("{err:#}").contains("offset out of range"));
+
+        // the base timestamp is covered by the checksum
+        let mut timestamps = data;
+        timestamps[27..35].copy_from_slice(&i64::MAX.to_be_bytes());
+        let crc = crc32c(&timestamps[21..]);
+        timestamps[17..21].copy_from_slice(&crc.to_be_bytes());
+        let err = decode_batches(&timestamps).unwrap_err();
+        assert!(format!("{err:#}").contains("timestamp out of range"));
+    }
+}